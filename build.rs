@@ -5,6 +5,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "chay/proto/v1/chayd_service.proto",
             "chay/proto/v1/program_event_result.proto",
             "chay/proto/v1/program_status.proto",
+            "chay/proto/v1/program_transition.proto",
         ],
         &include_paths,
     )?;
@@ -0,0 +1,104 @@
+/// Default address chayd listens on and chay connects to.
+pub const DEFAULT_CHAYD_ADDR: &str = "[::1]:50051";
+
+const UNIX_SCHEME: &str = "unix:";
+
+/// Address of the chayd gRPC API. Either a TCP socket address (e.g. `[::1]:50051`) or a Unix
+/// domain socket path prefixed with `unix:` (e.g. `unix:/run/chayd.sock`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChaydAddr {
+    Tcp(std::net::SocketAddr),
+    Unix(std::path::PathBuf),
+}
+
+impl std::str::FromStr for ChaydAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+            // Also accept the URI form, i.e. unix:///run/chayd.sock
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if path.is_empty() {
+                return Err(format!("Missing socket path in address: {addr}"));
+            }
+            return Ok(ChaydAddr::Unix(std::path::PathBuf::from(path)));
+        }
+        match addr.parse() {
+            Ok(socket_addr) => Ok(ChaydAddr::Tcp(socket_addr)),
+            Err(error) => Err(format!("Invalid address {addr}: {error}")),
+        }
+    }
+}
+
+impl std::fmt::Display for ChaydAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChaydAddr::Tcp(socket_addr) => write!(f, "{socket_addr}"),
+            ChaydAddr::Unix(path) => write!(f, "{UNIX_SCHEME}{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(addr: &str) -> String {
+        addr.parse::<ChaydAddr>().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_tcp_addresses() {
+        assert_eq!(
+            "127.0.0.1:50051".parse::<ChaydAddr>(),
+            Ok(ChaydAddr::Tcp("127.0.0.1:50051".parse().unwrap()))
+        );
+        assert_eq!(
+            DEFAULT_CHAYD_ADDR.parse::<ChaydAddr>(),
+            Ok(ChaydAddr::Tcp("[::1]:50051".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn parses_unix_paths() {
+        assert_eq!(
+            "unix:/run/chayd.sock".parse::<ChaydAddr>(),
+            Ok(ChaydAddr::Unix("/run/chayd.sock".into()))
+        );
+        assert_eq!(
+            "unix:///run/chayd.sock".parse::<ChaydAddr>(),
+            Ok(ChaydAddr::Unix("/run/chayd.sock".into()))
+        );
+        assert_eq!(
+            "unix:chayd.sock".parse::<ChaydAddr>(),
+            Ok(ChaydAddr::Unix("chayd.sock".into()))
+        );
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        assert_eq!(round_trip("127.0.0.1:50051"), "127.0.0.1:50051");
+        assert_eq!(round_trip("[::1]:50051"), "[::1]:50051");
+        assert_eq!(round_trip("unix:/run/chayd.sock"), "unix:/run/chayd.sock");
+        // The URI form is displayed in the short form, which parses to the same address.
+        assert_eq!(round_trip("unix:///run/chayd.sock"), "unix:/run/chayd.sock");
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for addr in [
+            "",
+            "localhost",
+            "127.0.0.1",
+            "::1:50051",
+            "127.0.0.1:port",
+            "unix:",
+            "unix://",
+        ] {
+            assert!(
+                addr.parse::<ChaydAddr>().is_err(),
+                "{addr:?} should be invalid"
+            );
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::chay_proto;
+use crate::ChaydClient;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Ctrl-P followed by Ctrl-Q detaches, same as docker.
+const DETACH_KEYS: [u8; 2] = [0x10, 0x11];
+const DETACH_KEYS_NAME: &str = "Ctrl-P Ctrl-Q";
+
+/// Puts the terminal in raw mode so every key, e.g. Ctrl-C, goes to the program. Restores the
+/// terminal when dropped.
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        crossterm::terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum StdinInput {
+    Data(Vec<u8>),
+    Detach,
+}
+
+/// Finds the detach keys in the input, which may be split across reads.
+#[derive(Default)]
+struct DetachKeysMatcher {
+    /// Number of detach keys at the end of the previous input, held back until it's known
+    /// whether the rest of the sequence follows.
+    num_matched: usize,
+}
+
+impl DetachKeysMatcher {
+    fn feed(&mut self, data: &[u8]) -> Vec<StdinInput> {
+        let mut inputs = vec![];
+        let mut forwarded = vec![];
+        for byte in data {
+            if *byte == DETACH_KEYS[self.num_matched] {
+                self.num_matched += 1;
+                if self.num_matched == DETACH_KEYS.len() {
+                    if !forwarded.is_empty() {
+                        inputs.push(StdinInput::Data(forwarded));
+                    }
+                    inputs.push(StdinInput::Detach);
+                    return inputs;
+                }
+                continue;
+            }
+            // Not the detach keys after all, so forward what was held back.
+            forwarded.extend_from_slice(&DETACH_KEYS[..self.num_matched]);
+            self.num_matched = 0;
+            if *byte == DETACH_KEYS[0] {
+                self.num_matched = 1;
+            } else {
+                forwarded.push(*byte);
+            }
+        }
+        if !forwarded.is_empty() {
+            inputs.push(StdinInput::Data(forwarded));
+        }
+        inputs
+    }
+}
+
+/// Reads stdin on a thread since reads block. The channel is closed at EOF.
+fn spawn_stdin_reader(stdin_tx: tokio::sync::mpsc::Sender<StdinInput>) {
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 4096];
+        let mut detach_keys_matcher = DetachKeysMatcher::default();
+        loop {
+            let num_read = match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(num_read) => num_read,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            for input in detach_keys_matcher.feed(&buf[..num_read]) {
+                if stdin_tx.blocking_send(input).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// The terminal doesn't translate "\n" to "\r\n" in raw mode, so do it here to keep the program's
+/// output readable. Output from a pty already has "\r\n", which is left as is.
+fn write_output(
+    writer: &mut impl Write,
+    data: &[u8],
+    raw_mode: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if raw_mode {
+        for line in data.split_inclusive(|byte| *byte == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(stripped_line) if !stripped_line.ends_with(b"\r") => {
+                    writer.write_all(stripped_line)?;
+                    writer.write_all(b"\r\n")?;
+                }
+                _ => writer.write_all(line)?,
+            }
+        }
+    } else {
+        writer.write_all(data)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Size of the terminal chay runs in, sent so programs in a pty can match it.
+fn terminal_window_size() -> Option<chay_proto::WindowSize> {
+    crossterm::terminal::size()
+        .ok()
+        .map(|(cols, rows)| chay_proto::WindowSize {
+            rows: rows as u32,
+            cols: cols as u32,
+        })
+}
+
+/// Forwards stdin to the program and prints its output until the detach keys are pressed or
+/// chayd ends the stream, e.g. because the program was removed.
+pub async fn run_attach(
+    client: &mut ChaydClient,
+    program_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let is_tty = nix::unistd::isatty(std::io::stdin().as_raw_fd()).unwrap_or(false);
+    let (input_tx, input_rx) = tokio::sync::mpsc::channel(16);
+    input_tx
+        .send(chay_proto::ChaydServiceAttachRequest {
+            name: program_name.to_string(),
+            input: vec![],
+            window_size: if is_tty { terminal_window_size() } else { None },
+        })
+        .await?;
+    let mut output_stream = client
+        .attach(tokio_stream::wrappers::ReceiverStream::new(input_rx))
+        .await?
+        .into_inner();
+
+    if is_tty {
+        eprintln!("Attached to {program_name}, detach with {DETACH_KEYS_NAME}");
+    }
+    let raw_mode_guard = if is_tty {
+        Some(RawModeGuard::new()?)
+    } else {
+        None
+    };
+    let mut window_changes =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?;
+    let (stdin_tx, mut stdin_rx) = tokio::sync::mpsc::channel(16);
+    spawn_stdin_reader(stdin_tx);
+    let mut stdin_open = true;
+    loop {
+        tokio::select! {
+            response = output_stream.message() => match response? {
+                Some(response) => match response.output {
+                    Some(chay_proto::chayd_service_attach_response::Output::Stdout(data)) => {
+                        write_output(&mut std::io::stdout(), &data, raw_mode_guard.is_some())?
+                    }
+                    Some(chay_proto::chayd_service_attach_response::Output::Stderr(data)) => {
+                        write_output(&mut std::io::stderr(), &data, raw_mode_guard.is_some())?
+                    }
+                    None => {}
+                },
+                None => {
+                    drop(raw_mode_guard);
+                    return Err(format!("chayd ended the attach to {program_name}").into());
+                }
+            },
+            stdin_input = stdin_rx.recv(), if stdin_open => match stdin_input {
+                Some(StdinInput::Data(input)) => {
+                    let request = chay_proto::ChaydServiceAttachRequest {
+                        input,
+                        ..Default::default()
+                    };
+                    if input_tx.send(request).await.is_err() {
+                        break;
+                    }
+                }
+                Some(StdinInput::Detach) => break,
+                // Keep printing the output after EOF, e.g. when the input was piped in, until
+                // interrupted.
+                None => stdin_open = false,
+            },
+            Some(_) = window_changes.recv(), if is_tty => {
+                let request = chay_proto::ChaydServiceAttachRequest {
+                    window_size: terminal_window_size(),
+                    ..Default::default()
+                };
+                if input_tx.send(request).await.is_err() {
+                    break;
+                }
+            },
+            _ = tokio::signal::ctrl_c(), if raw_mode_guard.is_none() => break,
+        }
+    }
+    drop(raw_mode_guard);
+    if is_tty {
+        eprintln!("\nDetached from {program_name}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CTRL_P: u8 = 0x10;
+    const CTRL_Q: u8 = 0x11;
+
+    #[test]
+    fn detaches_on_keys_split_across_reads() {
+        let mut matcher = DetachKeysMatcher::default();
+        assert_eq!(
+            matcher.feed(&[b'a', CTRL_P]),
+            vec![StdinInput::Data(vec![b'a'])]
+        );
+        assert_eq!(matcher.feed(&[CTRL_Q, b'b']), vec![StdinInput::Detach]);
+    }
+
+    #[test]
+    fn forwards_ctrl_p_when_not_followed_by_ctrl_q() {
+        let mut matcher = DetachKeysMatcher::default();
+        assert_eq!(
+            matcher.feed(&[CTRL_P, b'x']),
+            vec![StdinInput::Data(vec![CTRL_P, b'x'])]
+        );
+        // Held back until the next read shows it isn't the start of the detach keys.
+        assert_eq!(matcher.feed(&[CTRL_P]), vec![]);
+        assert_eq!(
+            matcher.feed(b"x"),
+            vec![StdinInput::Data(vec![CTRL_P, b'x'])]
+        );
+    }
+
+    #[test]
+    fn detaches_after_a_repeated_ctrl_p() {
+        let mut matcher = DetachKeysMatcher::default();
+        assert_eq!(
+            matcher.feed(&[CTRL_P, CTRL_P, CTRL_Q]),
+            vec![StdinInput::Data(vec![CTRL_P]), StdinInput::Detach]
+        );
+    }
+}
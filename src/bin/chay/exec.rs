@@ -0,0 +1,55 @@
+use crate::chay_proto;
+use crate::ChaydClient;
+use chay_proto::chayd_service_exec_response::Output;
+use std::io::Write;
+
+/// Returned when the command exited unsuccessfully, so chay exits with the same code. A command
+/// killed by a signal gets 128 plus the signal number, like in shells.
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub exit_code: u8,
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with code {}", self.exit_code)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Runs the command in the program's context and prints its output as it arrives.
+pub async fn run_exec(
+    client: &mut ChaydClient,
+    program_name: &str,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(chay_proto::ChaydServiceExecRequest {
+        name: program_name.to_string(),
+        args: args.to_vec(),
+    });
+    let mut stream = client.exec(request).await?.into_inner();
+    while let Some(response) = stream.message().await? {
+        match response.output {
+            Some(Output::Stdout(data)) => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            Some(Output::Stderr(data)) => std::io::stderr().write_all(&data)?,
+            Some(Output::Exit(program_exit)) => {
+                let exit_code = match program_exit.status {
+                    Some(chay_proto::program_exit::Status::Code(0)) => return Ok(()),
+                    Some(chay_proto::program_exit::Status::Code(code)) => code,
+                    Some(chay_proto::program_exit::Status::Signal(signal)) => 128 + signal,
+                    None => 1,
+                };
+                return Err(Box::new(CommandFailed {
+                    exit_code: exit_code.clamp(1, 255) as u8,
+                }));
+            }
+            None => {}
+        }
+    }
+    Err("chayd closed the stream before the command exited".into())
+}
@@ -0,0 +1,583 @@
+use chay::addr::ChaydAddr;
+use chay_proto::chayd_service_client::ChaydServiceClient;
+use chay_proto::{
+    ChaydServiceAddProgramRequest, ChaydServiceGetHealthRequest, ChaydServiceGetJobRequest,
+    ChaydServiceGetStatusRequest, ChaydServiceReloadConfigRequest, ChaydServiceReloadRequest,
+    ChaydServiceRemoveProgramRequest, ChaydServiceRestartRequest, ChaydServiceRunJobRequest,
+    ChaydServiceSignalRequest, ChaydServiceStartRequest, ChaydServiceStopRequest,
+    ChaydServiceTailRequest, ChaydServiceUpdateProgramRequest, ChaydServiceWatchEventsRequest,
+};
+use clap::Parser;
+use output::{Output, OutputFormat, ProgramResultsFailed};
+use std::io::Write;
+
+pub mod chay_proto {
+    tonic::include_proto!("chay.proto.v1");
+}
+mod attach;
+mod exec;
+mod output;
+mod shell;
+mod top;
+
+#[derive(clap::Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Output format. Exits non-zero if the action failed for any program
+    #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(clap::Args)]
+struct ConnectionArgs {
+    /// Address of chayd. Either a TCP socket address or a Unix domain socket path prefixed with
+    /// "unix:", e.g. "unix:/run/chayd.sock"
+    #[arg(long, env = "CHAY_ADDR", default_value = chay::addr::DEFAULT_CHAYD_ADDR)]
+    addr: ChaydAddr,
+    /// Path to the PEM encoded CA certificate used to verify chayd's certificate. Enables TLS
+    #[arg(long, env = "CHAY_TLS_CA")]
+    tls_ca: Option<std::path::PathBuf>,
+    /// Path to the PEM encoded client certificate, for chayd configured with mutual TLS
+    #[arg(long, env = "CHAY_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<std::path::PathBuf>,
+    /// Path to the PEM encoded private key of --tls-cert
+    #[arg(long, env = "CHAY_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+    /// Domain name to verify chayd's certificate against. Defaults to the host of --addr
+    #[arg(long, env = "CHAY_TLS_DOMAIN")]
+    tls_domain: Option<String>,
+    /// Bearer token to authenticate with, if chayd is configured with auth tokens
+    #[arg(long, env = "CHAY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+type ChaydClient = ChaydServiceClient<
+    tonic::codegen::InterceptedService<tonic::transport::Channel, TokenInterceptor>,
+>;
+
+/// Adds the bearer token, if any, to the metadata of every request.
+#[derive(Clone)]
+struct TokenInterceptor {
+    authorization: Option<tonic::metadata::MetadataValue<tonic::metadata::Ascii>>,
+}
+
+impl tonic::service::Interceptor for TokenInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+#[derive(clap::Args)]
+struct WaitArgs {
+    /// Wait until the programs are running (or stopped), or failed to
+    #[arg(long)]
+    wait: bool,
+    /// Seconds to wait before giving up. Waits forever if unset
+    #[arg(long, requires = "wait")]
+    timeout: Option<u32>,
+}
+
+#[derive(clap::Subcommand)]
+enum Action {
+    Health,
+    /// Print the status of the matching programs, or of every program
+    Status {
+        program_expr: Option<String>,
+        /// Keep printing the statuses every time they are updated
+        #[arg(long)]
+        watch: bool,
+    },
+    Start {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    Stop {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    Restart {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Reload the matching programs with their reload_command or reload_signal
+    Reload {
+        program_expr: String,
+    },
+    /// Send a signal to the matching programs, e.g. `chay signal HUP 'web-*'`
+    Signal {
+        /// Signal name with or without the "SIG" prefix, or a signal number
+        signal: String,
+        program_expr: String,
+        /// Which of the program's processes to send the signal to
+        #[arg(long, value_enum, default_value_t = SignalTarget::Program)]
+        target: SignalTarget,
+    },
+    /// Stream every state transition of the matching programs
+    Watch {
+        #[arg(default_value = "all")]
+        program_expr: String,
+    },
+    /// Add a program that is not in chayd's config file, until chayd restarts
+    Add {
+        name: String,
+        /// Path to a TOML file with the program definition, in the same format as a
+        /// [programs.<name>] table of chayd's config file
+        program_config_path: std::path::PathBuf,
+    },
+    /// Stop a program and replace it with a new definition
+    Update {
+        name: String,
+        /// Path to a TOML file with the program definition, in the same format as a
+        /// [programs.<name>] table of chayd's config file
+        program_config_path: std::path::PathBuf,
+    },
+    /// Stop a program and remove it
+    Remove {
+        name: String,
+    },
+    /// Make chayd re-read its config file and apply the changed programs
+    ReloadConfig,
+    /// Start an interactive shell that runs commands over a single connection
+    Shell,
+    /// Show a full-screen dashboard of the matching programs, or of every program
+    Top {
+        program_expr: Option<String>,
+    },
+    /// Forward stdin to a program with attachable = true and print its output, until detached
+    /// with Ctrl-P Ctrl-Q
+    Attach {
+        program_name: String,
+    },
+    /// Run a one-off command with the same context as a program, e.g.
+    /// `chay exec web -- ls '{{example.log_dir}}'`. Exits with the command's exit code
+    Exec {
+        program_name: String,
+        /// The command and its arguments, rendered with the vars of chayd's config
+        #[arg(last = true, required = true)]
+        args: Vec<String>,
+    },
+    /// Run a command as a job in the background, e.g. `chay run -- apt-get upgrade -y`, and print
+    /// its ID. It shows up in the status until it finishes
+    Run {
+        /// Name of a logger from chayd's config to also send the job's output to
+        #[arg(long)]
+        logger: Option<String>,
+        /// Seconds after which the job is stopped if it's still running
+        #[arg(long)]
+        timeout: Option<u32>,
+        /// The command and its arguments, rendered with the vars of chayd's config
+        #[arg(last = true, required = true)]
+        args: Vec<String>,
+    },
+    /// Print a job's state, exit and captured output, also after it finished
+    Job {
+        id: String,
+    },
+    /// Print the end of the output of a program or running job
+    Tail {
+        program_name: String,
+        #[command(flatten)]
+        tail: TailArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct TailArgs {
+    /// Number of lines to print from the end of the output. Prints everything chayd kept if 0
+    #[arg(long, short = 'n', default_value_t = 10)]
+    lines: u32,
+    /// Keep printing the output as it comes, until interrupted
+    #[arg(long, short)]
+    follow: bool,
+}
+
+fn client_tls_config(
+    connection: &ConnectionArgs,
+) -> Result<Option<tonic::transport::ClientTlsConfig>, Box<dyn std::error::Error>> {
+    if connection.tls_ca.is_none() && connection.tls_cert.is_none() {
+        return Ok(None);
+    }
+    let mut tls_config = tonic::transport::ClientTlsConfig::new();
+    if let Some(ca_path) = &connection.tls_ca {
+        tls_config = tls_config.ca_certificate(tonic::transport::Certificate::from_pem(
+            std::fs::read(ca_path)?,
+        ));
+    }
+    if let (Some(cert_path), Some(key_path)) = (&connection.tls_cert, &connection.tls_key) {
+        tls_config = tls_config.identity(tonic::transport::Identity::from_pem(
+            std::fs::read(cert_path)?,
+            std::fs::read(key_path)?,
+        ));
+    }
+    if let Some(domain) = &connection.tls_domain {
+        tls_config = tls_config.domain_name(domain.clone());
+    }
+    Ok(Some(tls_config))
+}
+
+#[derive(Clone, clap::ValueEnum)]
+enum SignalTarget {
+    Program,
+    Logger,
+    PreCommand,
+    /// The program and all of its sidecar programs that are running
+    Group,
+}
+
+impl From<&SignalTarget> for chay_proto::SignalTarget {
+    fn from(signal_target: &SignalTarget) -> Self {
+        match signal_target {
+            SignalTarget::Program => chay_proto::SignalTarget::Program,
+            SignalTarget::Logger => chay_proto::SignalTarget::Logger,
+            SignalTarget::PreCommand => chay_proto::SignalTarget::PreCommand,
+            SignalTarget::Group => chay_proto::SignalTarget::Group,
+        }
+    }
+}
+
+async fn connect(connection: &ConnectionArgs) -> Result<ChaydClient, Box<dyn std::error::Error>> {
+    let tls_config = client_tls_config(connection)?;
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let mut endpoint = match &connection.addr {
+        ChaydAddr::Tcp(socket_addr) => {
+            tonic::transport::Endpoint::try_from(format!("{scheme}://{socket_addr}"))?
+        }
+        // The URI is ignored by the Unix socket connector, but the endpoint requires a valid one.
+        ChaydAddr::Unix(_) => {
+            tonic::transport::Endpoint::try_from(format!("{scheme}://[::]:50051"))?
+        }
+    };
+    if let Some(tls_config) = tls_config {
+        endpoint = endpoint.tls_config(tls_config)?;
+    }
+    let channel = match &connection.addr {
+        ChaydAddr::Tcp(_) => endpoint.connect().await?,
+        ChaydAddr::Unix(socket_path) => {
+            let socket_path = socket_path.clone();
+            endpoint
+                .connect_with_connector(tower::service_fn(move |_: tonic::transport::Uri| {
+                    tokio::net::UnixStream::connect(socket_path.clone())
+                }))
+                .await?
+        }
+    };
+    let authorization = match &connection.token {
+        Some(token) => Some(format!("Bearer {token}").parse()?),
+        None => None,
+    };
+    Ok(ChaydServiceClient::with_interceptor(
+        channel,
+        TokenInterceptor { authorization },
+    ))
+}
+
+async fn handle_health_action(
+    client: &mut ChaydClient,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceGetHealthRequest {});
+    client.get_health(request).await?;
+    output.print_health()
+}
+
+async fn handle_status_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &Option<String>,
+    watch: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceGetStatusRequest {
+        program_expr: program_expr.clone().unwrap_or_default(),
+        watch,
+    });
+    let mut stream = client.get_status(request).await?.into_inner();
+    while let Some(response) = stream.message().await? {
+        output.print_program_statuses(&response.program_statuses)?;
+    }
+    Ok(())
+}
+
+async fn handle_start_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &str,
+    wait: &WaitArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceStartRequest {
+        program_expr: program_expr.to_string(),
+        wait: wait.wait,
+        timeout_secs: wait.timeout,
+    });
+    let response = client.start(request).await?;
+    output.print_program_event_results(&response.get_ref().program_event_results)
+}
+
+async fn handle_stop_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &str,
+    wait: &WaitArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceStopRequest {
+        program_expr: program_expr.to_string(),
+        wait: wait.wait,
+        timeout_secs: wait.timeout,
+    });
+    let response = client.stop(request).await?;
+    output.print_program_event_results(&response.get_ref().program_event_results)
+}
+
+async fn handle_restart_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &str,
+    wait: &WaitArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceRestartRequest {
+        program_expr: program_expr.to_string(),
+        wait: wait.wait,
+        timeout_secs: wait.timeout,
+    });
+    let response = client.restart(request).await?;
+    output.print_program_event_results(&response.get_ref().program_event_results)
+}
+
+async fn handle_reload_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceReloadRequest {
+        program_expr: program_expr.to_string(),
+    });
+    let response = client.reload(request).await?;
+    output.print_program_event_results(&response.get_ref().program_event_results)
+}
+
+async fn handle_signal_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    signal: &str,
+    program_expr: &str,
+    target: &SignalTarget,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut signal_request = ChaydServiceSignalRequest {
+        program_expr: program_expr.to_string(),
+        signal: signal.to_string(),
+        ..Default::default()
+    };
+    signal_request.set_target(target.into());
+    let response = client.signal(tonic::Request::new(signal_request)).await?;
+    output.print_program_event_results(&response.get_ref().program_event_results)
+}
+
+async fn handle_watch_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    program_expr: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceWatchEventsRequest {
+        program_expr: program_expr.to_string(),
+    });
+    let mut stream = client.watch_events(request).await?.into_inner();
+    while let Some(response) = stream.message().await? {
+        if let Some(program_transition) = &response.program_transition {
+            output.print_program_transition(program_transition)?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_tail_action(
+    client: &mut ChaydClient,
+    program_name: &str,
+    tail: &TailArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceTailRequest {
+        name: program_name.to_string(),
+        num_lines: tail.lines,
+        follow: tail.follow,
+    });
+    let mut stream = client.tail(request).await?.into_inner();
+    let mut stdout = std::io::stdout();
+    while let Some(response) = stream.message().await? {
+        stdout.write_all(&response.output)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+async fn handle_add_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    name: &str,
+    program_config_path: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program_config = std::fs::read_to_string(program_config_path)?;
+    let request = tonic::Request::new(ChaydServiceAddProgramRequest {
+        name: name.to_string(),
+        program_config,
+    });
+    client.add_program(request).await?;
+    output.print_program_change(name, "added")
+}
+
+async fn handle_update_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    name: &str,
+    program_config_path: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program_config = std::fs::read_to_string(program_config_path)?;
+    let request = tonic::Request::new(ChaydServiceUpdateProgramRequest {
+        name: name.to_string(),
+        program_config,
+    });
+    client.update_program(request).await?;
+    output.print_program_change(name, "updated")
+}
+
+async fn handle_remove_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceRemoveProgramRequest {
+        name: name.to_string(),
+    });
+    client.remove_program(request).await?;
+    output.print_program_change(name, "removed")
+}
+
+async fn handle_reload_config_action(
+    client: &mut ChaydClient,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceReloadConfigRequest {});
+    let response = client.reload_config(request).await?;
+    output.print_config_changes(response.get_ref())
+}
+
+async fn handle_run_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    logger: &Option<String>,
+    timeout: &Option<u32>,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceRunJobRequest {
+        args: args.to_vec(),
+        logger: logger.clone(),
+        timeout_secs: *timeout,
+    });
+    let response = client.run_job(request).await?;
+    output.print_job_id(&response.get_ref().id)
+}
+
+async fn handle_job_action(
+    client: &mut ChaydClient,
+    output: &Output,
+    id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(ChaydServiceGetJobRequest { id: id.to_string() });
+    let response = client.get_job(request).await?;
+    match &response.get_ref().job {
+        Some(job) => output.print_job(job),
+        None => Err("chayd returned no job".into()),
+    }
+}
+
+async fn handle_action(args: &Args, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let client = &mut connect(&args.connection).await?;
+    match &args.action {
+        Action::Health => handle_health_action(client, output).await,
+        Action::Status {
+            program_expr,
+            watch,
+        } => handle_status_action(client, output, program_expr, *watch).await,
+        Action::Start { program_expr, wait } => {
+            handle_start_action(client, output, program_expr, wait).await
+        }
+        Action::Stop { program_expr, wait } => {
+            handle_stop_action(client, output, program_expr, wait).await
+        }
+        Action::Restart { program_expr, wait } => {
+            handle_restart_action(client, output, program_expr, wait).await
+        }
+        Action::Reload { program_expr } => handle_reload_action(client, output, program_expr).await,
+        Action::Signal {
+            signal,
+            program_expr,
+            target,
+        } => handle_signal_action(client, output, signal, program_expr, target).await,
+        Action::Watch { program_expr } => handle_watch_action(client, output, program_expr).await,
+        Action::Add {
+            name,
+            program_config_path,
+        } => handle_add_action(client, output, name, program_config_path).await,
+        Action::Update {
+            name,
+            program_config_path,
+        } => handle_update_action(client, output, name, program_config_path).await,
+        Action::Remove { name } => handle_remove_action(client, output, name).await,
+        Action::ReloadConfig => handle_reload_config_action(client, output).await,
+        Action::Shell => shell::run_shell(client, output).await,
+        Action::Top { program_expr } => top::run_top(client, program_expr).await,
+        Action::Attach { program_name } => attach::run_attach(client, program_name).await,
+        Action::Exec { program_name, args } => exec::run_exec(client, program_name, args).await,
+        Action::Run {
+            logger,
+            timeout,
+            args,
+        } => handle_run_action(client, output, logger, timeout, args).await,
+        Action::Job { id } => handle_job_action(client, output, id).await,
+        Action::Tail { program_name, tail } => handle_tail_action(client, program_name, tail).await,
+    }
+}
+
+/// Prints an error returned by a handler, unless it only reports failures whose output has
+/// already been printed.
+fn print_action_error(error: &(dyn std::error::Error + 'static)) {
+    // The command's own output explains why it failed.
+    if error.is::<ProgramResultsFailed>() || error.is::<exec::CommandFailed>() {
+        return;
+    }
+    match error.downcast_ref::<tonic::Status>() {
+        Some(status) => eprintln!("Error: {}", status.message()),
+        None => eprintln!("Error: {}", error),
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    let args = Args::parse();
+    let output = Output::new(args.output);
+    match handle_action(&args, &output).await {
+        Ok(_) => std::process::ExitCode::SUCCESS,
+        Err(error) => {
+            print_action_error(error.as_ref());
+            match error.downcast_ref::<exec::CommandFailed>() {
+                Some(command_failed) => std::process::ExitCode::from(command_failed.exit_code),
+                None => std::process::ExitCode::FAILURE,
+            }
+        }
+    }
+}
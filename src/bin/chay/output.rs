@@ -0,0 +1,858 @@
+use crate::chay_proto;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable, colorized when printing to a terminal
+    Text,
+    /// One JSON document per line
+    Json,
+    /// YAML documents separated by "---"
+    Yaml,
+}
+
+/// Returned when some programs' results were errors. The results have already been printed, so
+/// the CLI only needs to exit non-zero.
+#[derive(Debug)]
+pub struct ProgramResultsFailed {
+    num_failed: usize,
+}
+
+impl std::fmt::Display for ProgramResultsFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} program(s) failed", self.num_failed)
+    }
+}
+
+impl std::error::Error for ProgramResultsFailed {}
+
+#[derive(serde::Serialize)]
+struct ProgramExitOutput {
+    code: Option<i32>,
+    signal: Option<i32>,
+    time: Option<String>,
+    user_cpu_secs: Option<f64>,
+    system_cpu_secs: Option<f64>,
+    max_rss_bytes: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct ResourceUsageOutput {
+    cpu_percent: f64,
+    rss_bytes: u64,
+    num_threads: u32,
+    num_fds: u32,
+    read_bytes: u64,
+    write_bytes: u64,
+    num_processes: u32,
+}
+
+#[derive(serde::Serialize)]
+struct ProgramStatusOutput {
+    name: String,
+    state: String,
+    description: String,
+    pid: Option<u32>,
+    logger_pid: Option<u32>,
+    start_time: Option<String>,
+    uptime_secs: Option<u64>,
+    restarts: u32,
+    last_exit: Option<ProgramExitOutput>,
+    last_error: Option<String>,
+    next_start_time: Option<String>,
+    labels: BTreeMap<String, String>,
+    groups: Vec<String>,
+    resource_usage: Option<ResourceUsageOutput>,
+}
+
+#[derive(serde::Serialize)]
+struct ProgramResultOutput {
+    ok: bool,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+struct ProgramTransitionOutput {
+    time: Option<String>,
+    name: String,
+    from_state: String,
+    to_state: String,
+    reason: String,
+    message: String,
+    source: String,
+    exit: Option<ProgramExitOutput>,
+}
+
+#[derive(serde::Serialize)]
+struct ProgramChangeOutput<'a> {
+    name: &'a str,
+    change: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct HealthOutput {
+    healthy: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ConfigChangesOutput<'a> {
+    added_programs: &'a Vec<String>,
+    removed_programs: &'a Vec<String>,
+    changed_programs: &'a Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct JobIdOutput<'a> {
+    id: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct JobOutput {
+    id: String,
+    args: Vec<String>,
+    state: String,
+    finished: bool,
+    start_time: Option<String>,
+    end_time: Option<String>,
+    exit: Option<ProgramExitOutput>,
+    error: Option<String>,
+    timed_out: bool,
+    /// Invalid UTF-8 is replaced, since the output is serialized as a string.
+    output: String,
+}
+
+/// Lower case name of a proto enum value without its prefix, e.g. "running" for
+/// PROGRAM_STATE_RUNNING.
+fn enum_value_name(str_name: &str, prefix: &str) -> String {
+    str_name
+        .strip_prefix(prefix)
+        .unwrap_or(str_name)
+        .to_lowercase()
+}
+
+pub fn state_name(program_state: chay_proto::ProgramState) -> String {
+    enum_value_name(program_state.as_str_name(), "PROGRAM_STATE_")
+}
+
+fn program_exit_output(program_exit: &chay_proto::ProgramExit) -> ProgramExitOutput {
+    let (code, signal) = match &program_exit.status {
+        Some(chay_proto::program_exit::Status::Code(code)) => (Some(*code), None),
+        Some(chay_proto::program_exit::Status::Signal(signal)) => (None, Some(*signal)),
+        None => (None, None),
+    };
+    let secs = |duration: &Option<prost_types::Duration>| {
+        duration
+            .as_ref()
+            .and_then(|duration| std::time::Duration::try_from(duration.clone()).ok())
+            .map(|duration| duration.as_secs_f64())
+    };
+    ProgramExitOutput {
+        code,
+        signal,
+        time: program_exit.time.as_ref().map(|time| time.to_string()),
+        user_cpu_secs: secs(&program_exit.user_cpu_time),
+        system_cpu_secs: secs(&program_exit.system_cpu_time),
+        max_rss_bytes: program_exit.max_rss_bytes,
+    }
+}
+
+fn program_status_output(program_status: &chay_proto::ProgramStatus) -> ProgramStatusOutput {
+    ProgramStatusOutput {
+        name: program_status.name.clone(),
+        state: state_name(program_status.state()),
+        description: program_status.description.clone(),
+        pid: program_status.pid,
+        logger_pid: program_status.logger_pid,
+        start_time: program_status
+            .start_time
+            .as_ref()
+            .map(|start_time| start_time.to_string()),
+        uptime_secs: program_status
+            .uptime
+            .as_ref()
+            .and_then(|uptime| std::time::Duration::try_from(uptime.clone()).ok())
+            .map(|uptime| uptime.as_secs()),
+        restarts: program_status.num_restarts,
+        last_exit: program_status.last_exit.as_ref().map(program_exit_output),
+        last_error: if program_status.last_error.is_empty() {
+            None
+        } else {
+            Some(program_status.last_error.clone())
+        },
+        next_start_time: program_status
+            .next_backoff_time
+            .as_ref()
+            .map(|next_backoff_time| next_backoff_time.to_string()),
+        labels: program_status.labels.clone().into_iter().collect(),
+        groups: program_status.groups.clone(),
+        resource_usage: program_status
+            .resource_usage
+            .as_ref()
+            .map(|resource_usage| ResourceUsageOutput {
+                cpu_percent: resource_usage.cpu_percent,
+                rss_bytes: resource_usage.rss_bytes,
+                num_threads: resource_usage.num_threads,
+                num_fds: resource_usage.num_fds,
+                read_bytes: resource_usage.read_bytes,
+                write_bytes: resource_usage.write_bytes,
+                num_processes: resource_usage.num_processes,
+            }),
+    }
+}
+
+fn program_result_output(
+    program_event_result: &chay_proto::ProgramEventResult,
+) -> ProgramResultOutput {
+    match &program_event_result.result {
+        Some(chay_proto::program_event_result::Result::Ok(ok)) => ProgramResultOutput {
+            ok: true,
+            message: ok.message.clone(),
+        },
+        Some(chay_proto::program_event_result::Result::Err(err)) => ProgramResultOutput {
+            ok: false,
+            message: err.message.clone(),
+        },
+        None => ProgramResultOutput {
+            ok: false,
+            message: "Missing result".to_string(),
+        },
+    }
+}
+
+fn program_transition_output(
+    program_transition: &chay_proto::ProgramTransition,
+) -> ProgramTransitionOutput {
+    ProgramTransitionOutput {
+        time: program_transition
+            .time
+            .as_ref()
+            .map(|time| time.to_string()),
+        name: program_transition.name.clone(),
+        from_state: state_name(program_transition.from_state()),
+        to_state: state_name(program_transition.to_state()),
+        reason: enum_value_name(program_transition.reason().as_str_name(), "REASON_"),
+        message: program_transition.message.clone(),
+        source: program_transition.source.clone(),
+        exit: program_transition.exit.as_ref().map(program_exit_output),
+    }
+}
+
+fn job_output(job: &chay_proto::Job) -> JobOutput {
+    JobOutput {
+        id: job.id.clone(),
+        args: job.args.clone(),
+        state: state_name(job.state()),
+        finished: job.finished,
+        start_time: job
+            .start_time
+            .as_ref()
+            .map(|start_time| start_time.to_string()),
+        end_time: job.end_time.as_ref().map(|end_time| end_time.to_string()),
+        exit: job.exit.as_ref().map(program_exit_output),
+        error: if job.error.is_empty() {
+            None
+        } else {
+            Some(job.error.clone())
+        },
+        timed_out: job.timed_out,
+        output: String::from_utf8_lossy(&job.output).to_string(),
+    }
+}
+
+pub fn format_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {mins}m {secs}s")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m {secs}s")
+    } else if mins > 0 {
+        format!("{mins}m {secs}s")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// Bytes in binary units, e.g. "1.5G".
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit_index = 0;
+    while value >= 1024.0 && unit_index + 1 < units.len() {
+        value /= 1024.0;
+        unit_index += 1;
+    }
+    if unit_index == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.1}{}", units[unit_index])
+    }
+}
+
+/// The CPU % and RSS columns of a program, "-" if it isn't running.
+pub fn format_resource_usage(program_status: &chay_proto::ProgramStatus) -> (String, String) {
+    match &program_status.resource_usage {
+        Some(resource_usage) => (
+            format!("{:.1}%", resource_usage.cpu_percent),
+            format_bytes(resource_usage.rss_bytes),
+        ),
+        None => ("-".to_string(), "-".to_string()),
+    }
+}
+
+fn format_exit_status(program_exit: &chay_proto::ProgramExit) -> String {
+    match &program_exit.status {
+        Some(chay_proto::program_exit::Status::Code(code)) => format!("code {code}"),
+        Some(chay_proto::program_exit::Status::Signal(signal)) => format!("signal {signal}"),
+        None => "unknown".to_string(),
+    }
+}
+
+fn format_program_exit(program_exit: &chay_proto::ProgramExit) -> String {
+    let status = format_exit_status(program_exit);
+    match &program_exit.time {
+        Some(time) => format!("{status} at {time}"),
+        None => status,
+    }
+}
+
+/// Shorter than format_program_exit to fit in the status table, e.g. "code 1, 5m ago".
+pub fn format_program_exit_ago(program_exit: &chay_proto::ProgramExit) -> String {
+    let status = format_exit_status(program_exit);
+    let ago = program_exit
+        .time
+        .as_ref()
+        .and_then(|time| std::time::SystemTime::try_from(time.clone()).ok())
+        .and_then(|time| time.elapsed().ok());
+    match ago {
+        Some(ago) => format!("{status}, {} ago", format_duration(ago)),
+        None => status,
+    }
+}
+
+pub fn format_program_transition(program_transition: &chay_proto::ProgramTransition) -> String {
+    let mut line = format!(
+        "{} {} {} -> {} ({}",
+        program_transition
+            .time
+            .as_ref()
+            .map(|time| time.to_string())
+            .unwrap_or_default(),
+        program_transition.name,
+        state_name(program_transition.from_state()),
+        state_name(program_transition.to_state()),
+        enum_value_name(program_transition.reason().as_str_name(), "REASON_"),
+    );
+    if !program_transition.message.is_empty() {
+        line.push_str(&format!(": {}", program_transition.message));
+    }
+    if let Some(exit) = &program_transition.exit {
+        line.push_str(&format!(" [{}]", format_program_exit(exit)));
+    }
+    if !program_transition.source.is_empty() {
+        line.push_str(&format!(" from {}", program_transition.source));
+    }
+    line.push(')');
+    line
+}
+
+pub struct Output {
+    format: OutputFormat,
+    color: bool,
+    printed_table: std::cell::Cell<bool>,
+}
+
+impl Output {
+    /// Colors are only used for text output to a terminal, unless disabled by NO_COLOR.
+    pub fn new(format: OutputFormat) -> Self {
+        let color = matches!(format, OutputFormat::Text)
+            && std::env::var_os("NO_COLOR").is_none()
+            && nix::unistd::isatty(std::io::stdout().as_raw_fd()).unwrap_or(false);
+        Self {
+            format,
+            color,
+            printed_table: std::cell::Cell::new(false),
+        }
+    }
+
+    fn paint(&self, text: &str, color: &str) -> String {
+        if self.color && !color.is_empty() {
+            format!("{color}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn state_color(state: chay_proto::ProgramState) -> &'static str {
+        match state {
+            chay_proto::ProgramState::Running => GREEN,
+            chay_proto::ProgramState::Starting
+            | chay_proto::ProgramState::Stopping
+            | chay_proto::ProgramState::Exiting => YELLOW,
+            chay_proto::ProgramState::Backoff | chay_proto::ProgramState::Exited => RED,
+            chay_proto::ProgramState::Stopped | chay_proto::ProgramState::Unspecified => "",
+        }
+    }
+
+    /// Only called for the json and yaml formats.
+    fn print_serialized<T: serde::Serialize>(
+        &self,
+        value: &T,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            OutputFormat::Json => println!("{}", serde_json::to_string(value)?),
+            // serde_yaml starts every document with "---", so streamed documents are separated.
+            OutputFormat::Yaml => println!("{}", serde_yaml::to_string(value)?),
+            OutputFormat::Text => unreachable!("Text output is not serialized"),
+        }
+        Ok(())
+    }
+
+    /// Prints rows with every column but the last padded to the same width. Colors are applied
+    /// after padding so the escape codes don't affect the alignment.
+    fn print_table(&self, rows: &Vec<Vec<(String, &str)>>) {
+        let mut widths: Vec<usize> = vec![];
+        for row in rows {
+            for (index, (cell, _)) in row.iter().enumerate() {
+                if index >= widths.len() {
+                    widths.push(0);
+                }
+                widths[index] = widths[index].max(cell.chars().count());
+            }
+        }
+        for row in rows {
+            let cells: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(index, (cell, color))| {
+                    let cell = if index + 1 < row.len() {
+                        format!("{:width$}", cell, width = widths[index])
+                    } else {
+                        cell.clone()
+                    };
+                    self.paint(&cell, color)
+                })
+                .collect();
+            println!("{}", cells.join("  ").trim_end());
+        }
+    }
+
+    pub fn print_health(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            OutputFormat::Text => println!("{}", self.paint("healthy", GREEN)),
+            _ => self.print_serialized(&HealthOutput { healthy: true })?,
+        }
+        Ok(())
+    }
+
+    /// Prints a table per call when watching, separated by a blank line.
+    pub fn print_program_statuses(
+        &self,
+        program_statuses: &Vec<chay_proto::ProgramStatus>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.format, OutputFormat::Text) {
+            let program_statuses: Vec<ProgramStatusOutput> =
+                program_statuses.iter().map(program_status_output).collect();
+            return self.print_serialized(&program_statuses);
+        }
+        if self.printed_table.get() {
+            println!();
+        }
+        let mut rows = vec![[
+            "NAME",
+            "STATE",
+            "PID",
+            "CPU",
+            "MEM",
+            "UPTIME",
+            "RESTARTS",
+            "LAST EXIT",
+        ]
+        .iter()
+        .map(|header| (header.to_string(), BOLD))
+        .collect()];
+        for program_status in program_statuses {
+            let uptime = program_status
+                .uptime
+                .as_ref()
+                .and_then(|uptime| std::time::Duration::try_from(uptime.clone()).ok());
+            let (cpu, mem) = format_resource_usage(program_status);
+            rows.push(vec![
+                (program_status.name.clone(), ""),
+                (
+                    state_name(program_status.state()),
+                    Self::state_color(program_status.state()),
+                ),
+                (
+                    program_status
+                        .pid
+                        .map_or("-".to_string(), |pid| pid.to_string()),
+                    "",
+                ),
+                (cpu, ""),
+                (mem, ""),
+                (uptime.map_or("-".to_string(), format_duration), ""),
+                (program_status.num_restarts.to_string(), ""),
+                (
+                    program_status
+                        .last_exit
+                        .as_ref()
+                        .map_or("-".to_string(), format_program_exit_ago),
+                    "",
+                ),
+            ]);
+        }
+        self.print_table(&rows);
+        self.printed_table.set(true);
+        Ok(())
+    }
+
+    /// Prints the result of an action for each program. Returns ProgramResultsFailed if any of
+    /// the results is an error.
+    pub fn print_program_event_results(
+        &self,
+        program_event_results: &HashMap<String, chay_proto::ProgramEventResult>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Sort by name since the results are a map and would otherwise be printed in random order.
+        let program_results: BTreeMap<&String, ProgramResultOutput> = program_event_results
+            .iter()
+            .map(|(program_name, program_event_result)| {
+                (program_name, program_result_output(program_event_result))
+            })
+            .collect();
+        if matches!(self.format, OutputFormat::Text) {
+            let rows: Vec<Vec<(String, &str)>> = program_results
+                .iter()
+                .map(|(program_name, program_result)| {
+                    let (result, color) = if program_result.ok {
+                        ("ok", GREEN)
+                    } else {
+                        ("error", RED)
+                    };
+                    vec![
+                        (program_name.to_string(), ""),
+                        (result.to_string(), color),
+                        (program_result.message.clone(), ""),
+                    ]
+                })
+                .collect();
+            self.print_table(&rows);
+        } else {
+            self.print_serialized(&program_results)?;
+        }
+        let num_failed = program_results
+            .values()
+            .filter(|program_result| !program_result.ok)
+            .count();
+        if num_failed > 0 {
+            return Err(Box::new(ProgramResultsFailed { num_failed }));
+        }
+        Ok(())
+    }
+
+    pub fn print_program_transition(
+        &self,
+        program_transition: &chay_proto::ProgramTransition,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            OutputFormat::Text => println!("{}", format_program_transition(program_transition)),
+            _ => self.print_serialized(&program_transition_output(program_transition))?,
+        }
+        Ok(())
+    }
+
+    /// For the add, update and remove actions.
+    pub fn print_program_change(
+        &self,
+        program_name: &str,
+        change: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            OutputFormat::Text => println!("{program_name}: {change}"),
+            _ => self.print_serialized(&ProgramChangeOutput {
+                name: program_name,
+                change,
+            })?,
+        }
+        Ok(())
+    }
+
+    pub fn print_config_changes(
+        &self,
+        response: &chay_proto::ChaydServiceReloadConfigResponse,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.format, OutputFormat::Text) {
+            return self.print_serialized(&ConfigChangesOutput {
+                added_programs: &response.added_programs,
+                removed_programs: &response.removed_programs,
+                changed_programs: &response.changed_programs,
+            });
+        }
+        let rows: Vec<Vec<(String, &str)>> = [
+            ("added", &response.added_programs),
+            ("removed", &response.removed_programs),
+            ("changed", &response.changed_programs),
+        ]
+        .iter()
+        .flat_map(|(change, program_names)| {
+            program_names
+                .iter()
+                .map(move |program_name| vec![(program_name.clone(), ""), (change.to_string(), "")])
+        })
+        .collect();
+        if rows.is_empty() {
+            println!("No programs changed");
+        } else {
+            self.print_table(&rows);
+        }
+        Ok(())
+    }
+
+    pub fn print_job_id(&self, job_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            OutputFormat::Text => println!("{job_id}"),
+            _ => self.print_serialized(&JobIdOutput { id: job_id })?,
+        }
+        Ok(())
+    }
+
+    /// Prints the job's details followed by its captured output as is.
+    pub fn print_job(&self, job: &chay_proto::Job) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.format, OutputFormat::Text) {
+            return self.print_serialized(&job_output(job));
+        }
+        let optional = |value: Option<String>| value.unwrap_or("-".to_string());
+        let mut rows = vec![
+            vec![("ID".to_string(), BOLD), (job.id.clone(), "")],
+            vec![("COMMAND".to_string(), BOLD), (job.args.join(" "), "")],
+            vec![
+                ("STATE".to_string(), BOLD),
+                (state_name(job.state()), Self::state_color(job.state())),
+            ],
+            vec![
+                ("STARTED".to_string(), BOLD),
+                (
+                    optional(job.start_time.as_ref().map(|time| time.to_string())),
+                    "",
+                ),
+            ],
+            vec![
+                ("ENDED".to_string(), BOLD),
+                (
+                    optional(job.end_time.as_ref().map(|time| time.to_string())),
+                    "",
+                ),
+            ],
+            vec![
+                ("EXIT".to_string(), BOLD),
+                (optional(job.exit.as_ref().map(format_program_exit)), ""),
+            ],
+        ];
+        if !job.error.is_empty() {
+            rows.push(vec![("ERROR".to_string(), BOLD), (job.error.clone(), RED)]);
+        }
+        if job.timed_out {
+            rows.push(vec![
+                ("TIMED OUT".to_string(), BOLD),
+                ("yes".to_string(), RED),
+            ]);
+        }
+        self.print_table(&rows);
+        if !job.output.is_empty() {
+            println!();
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&job.output)?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(seconds: i64) -> Option<prost_types::Timestamp> {
+        Some(prost_types::Timestamp { seconds, nanos: 0 })
+    }
+
+    fn program_exit() -> chay_proto::ProgramExit {
+        chay_proto::ProgramExit {
+            status: Some(chay_proto::program_exit::Status::Code(3)),
+            time: timestamp(1_700_000_000),
+            user_cpu_time: Some(prost_types::Duration {
+                seconds: 1,
+                nanos: 500_000_000,
+            }),
+            system_cpu_time: None,
+            max_rss_bytes: Some(1024),
+        }
+    }
+
+    fn ok_result(message: &str) -> chay_proto::ProgramEventResult {
+        chay_proto::ProgramEventResult {
+            result: Some(chay_proto::program_event_result::Result::Ok(
+                chay_proto::program_event_result::Ok {
+                    message: message.to_string(),
+                },
+            )),
+        }
+    }
+
+    fn err_result(message: &str) -> chay_proto::ProgramEventResult {
+        chay_proto::ProgramEventResult {
+            result: Some(chay_proto::program_event_result::Result::Err(
+                chay_proto::program_event_result::Err {
+                    message: message.to_string(),
+                },
+            )),
+        }
+    }
+
+    #[test]
+    fn serializes_program_statuses() {
+        let program_status = chay_proto::ProgramStatus {
+            name: "web".to_string(),
+            state: chay_proto::ProgramState::Running.into(),
+            start_time: timestamp(1_700_000_100),
+            uptime: Some(prost_types::Duration {
+                seconds: 90,
+                nanos: 0,
+            }),
+            pid: Some(42),
+            logger_pid: None,
+            num_restarts: 1,
+            last_exit: Some(program_exit()),
+            last_error: String::new(),
+            next_backoff_time: None,
+            description: "Web server".to_string(),
+            labels: HashMap::from([("tier".to_string(), "web".to_string())]),
+            groups: vec!["frontend".to_string()],
+            resource_usage: Some(chay_proto::ResourceUsage {
+                cpu_percent: 12.5,
+                rss_bytes: 2048,
+                num_threads: 4,
+                num_fds: 8,
+                read_bytes: 16,
+                write_bytes: 32,
+                num_processes: 2,
+            }),
+        };
+        assert_eq!(
+            serde_json::to_value(program_status_output(&program_status)).unwrap(),
+            serde_json::json!({
+                "name": "web",
+                "state": "running",
+                "description": "Web server",
+                "pid": 42,
+                "logger_pid": null,
+                "start_time": "2023-11-14T22:15:00Z",
+                "uptime_secs": 90,
+                "restarts": 1,
+                "last_exit": {
+                    "code": 3,
+                    "signal": null,
+                    "time": "2023-11-14T22:13:20Z",
+                    "user_cpu_secs": 1.5,
+                    "system_cpu_secs": null,
+                    "max_rss_bytes": 1024,
+                },
+                "last_error": null,
+                "next_start_time": null,
+                "labels": {"tier": "web"},
+                "groups": ["frontend"],
+                "resource_usage": {
+                    "cpu_percent": 12.5,
+                    "rss_bytes": 2048,
+                    "num_threads": 4,
+                    "num_fds": 8,
+                    "read_bytes": 16,
+                    "write_bytes": 32,
+                    "num_processes": 2,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_program_results() {
+        assert_eq!(
+            serde_json::to_value(program_result_output(&ok_result("Started"))).unwrap(),
+            serde_json::json!({"ok": true, "message": "Started"})
+        );
+        assert_eq!(
+            serde_json::to_value(program_result_output(&err_result("Not running"))).unwrap(),
+            serde_json::json!({"ok": false, "message": "Not running"})
+        );
+        let missing_result = chay_proto::ProgramEventResult { result: None };
+        assert_eq!(
+            serde_json::to_value(program_result_output(&missing_result)).unwrap(),
+            serde_json::json!({"ok": false, "message": "Missing result"})
+        );
+    }
+
+    #[test]
+    fn serializes_program_transitions() {
+        let program_transition = chay_proto::ProgramTransition {
+            name: "web".to_string(),
+            from_state: chay_proto::ProgramState::Running.into(),
+            to_state: chay_proto::ProgramState::Backoff.into(),
+            time: timestamp(1_700_000_000),
+            reason: chay_proto::program_transition::Reason::ProgramExited.into(),
+            message: "web exited with code 3".to_string(),
+            source: String::new(),
+            exit: Some(program_exit()),
+        };
+        assert_eq!(
+            serde_json::to_value(program_transition_output(&program_transition)).unwrap(),
+            serde_json::json!({
+                "time": "2023-11-14T22:13:20Z",
+                "name": "web",
+                "from_state": "running",
+                "to_state": "backoff",
+                "reason": "program_exited",
+                "message": "web exited with code 3",
+                "source": "",
+                "exit": {
+                    "code": 3,
+                    "signal": null,
+                    "time": "2023-11-14T22:13:20Z",
+                    "user_cpu_secs": 1.5,
+                    "system_cpu_secs": null,
+                    "max_rss_bytes": 1024,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn fails_if_any_program_result_is_an_error() {
+        let output = Output::new(OutputFormat::Json);
+        let program_event_results = HashMap::from([
+            ("db".to_string(), ok_result("Started")),
+            ("web".to_string(), ok_result("Started")),
+        ]);
+        assert!(output
+            .print_program_event_results(&program_event_results)
+            .is_ok());
+
+        let program_event_results = HashMap::from([
+            ("db".to_string(), ok_result("Started")),
+            ("web".to_string(), err_result("Not running")),
+        ]);
+        let error = output
+            .print_program_event_results(&program_event_results)
+            .unwrap_err();
+        // main exits non-zero without printing the error again, since the results explain it.
+        assert!(error.is::<ProgramResultsFailed>());
+        assert_eq!(error.to_string(), "1 program(s) failed");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::output::Output;
+use crate::{ChaydClient, SignalTarget, TailArgs, WaitArgs};
+use clap::Parser;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+
+const PROMPT: &str = "chay> ";
+const HISTORY_FILE_NAME: &str = ".chay_history";
+
+/// A line entered in the shell, parsed like a command line whose first word is the subcommand.
+#[derive(clap::Parser)]
+#[command(multicall = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: ShellCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum ShellCommand {
+    Start {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    Stop {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    Restart {
+        program_expr: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Print the status of the matching programs, or of every program
+    Status { program_expr: Option<String> },
+    /// Send a signal to the matching programs, e.g. `signal HUP 'web-*'`
+    Signal {
+        /// Signal name with or without the "SIG" prefix, or a signal number
+        signal: String,
+        program_expr: String,
+        /// Which of the program's processes to send the signal to
+        #[arg(long, value_enum, default_value_t = SignalTarget::Program)]
+        target: SignalTarget,
+    },
+    /// Print the end of a program's output, and follow it with -f until interrupted with Ctrl-C
+    Tail {
+        program_name: String,
+        #[command(flatten)]
+        tail: TailArgs,
+    },
+    /// Follow the state transitions of the matching programs until interrupted with Ctrl-C
+    Events {
+        #[arg(default_value = "all")]
+        program_expr: String,
+    },
+    /// Leave the shell
+    #[command(alias = "quit")]
+    Exit,
+}
+
+const COMMAND_NAMES: [&str; 10] = [
+    "start", "stop", "restart", "status", "signal", "tail", "events", "exit", "quit", "help",
+];
+
+/// Completes command names for the first word and program names for the others.
+struct ShellHelper {
+    program_names: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let word_start = line
+            .rfind(|c: char| c.is_whitespace() || c == ',' || c == '!')
+            .map_or(0, |index| index + 1);
+        let word = &line[word_start..];
+        let is_first_word = line[..word_start].trim().is_empty();
+        let candidates = if is_first_word {
+            COMMAND_NAMES
+                .iter()
+                .filter(|command_name| command_name.starts_with(word))
+                .map(|command_name| command_name.to_string())
+                .collect()
+        } else {
+            self.program_names
+                .iter()
+                .filter(|program_name| program_name.starts_with(word))
+                .cloned()
+                .collect()
+        };
+        Ok((word_start, candidates))
+    }
+}
+
+impl rustyline::hint::Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl rustyline::highlight::Highlighter for ShellHelper {}
+
+impl rustyline::validate::Validator for ShellHelper {}
+
+impl rustyline::Helper for ShellHelper {}
+
+/// Fetches the program names for tab-completion. Completion just won't offer any names if this
+/// fails, the error is reported by the next command instead.
+async fn fetch_program_names(client: &mut ChaydClient) -> Vec<String> {
+    let request = tonic::Request::new(crate::chay_proto::ChaydServiceGetStatusRequest::default());
+    let mut stream = match client.get_status(request).await {
+        Ok(response) => response.into_inner(),
+        Err(_) => return vec![],
+    };
+    match stream.message().await {
+        Ok(Some(response)) => response
+            .program_statuses
+            .into_iter()
+            .map(|program_status| program_status.name)
+            .collect(),
+        Ok(None) | Err(_) => vec![],
+    }
+}
+
+async fn handle_shell_command(
+    client: &mut ChaydClient,
+    output: &Output,
+    command: &ShellCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        ShellCommand::Start { program_expr, wait } => {
+            crate::handle_start_action(client, output, program_expr, wait).await
+        }
+        ShellCommand::Stop { program_expr, wait } => {
+            crate::handle_stop_action(client, output, program_expr, wait).await
+        }
+        ShellCommand::Restart { program_expr, wait } => {
+            crate::handle_restart_action(client, output, program_expr, wait).await
+        }
+        ShellCommand::Status { program_expr } => {
+            crate::handle_status_action(client, output, program_expr, false).await
+        }
+        ShellCommand::Signal {
+            signal,
+            program_expr,
+            target,
+        } => crate::handle_signal_action(client, output, signal, program_expr, target).await,
+        ShellCommand::Tail { program_name, tail } => {
+            tokio::select! {
+                result = crate::handle_tail_action(client, program_name, tail) => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            }
+        }
+        ShellCommand::Events { program_expr } => {
+            tokio::select! {
+                result = crate::handle_watch_action(client, output, program_expr) => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            }
+        }
+        // Handled by the caller.
+        ShellCommand::Exit => Ok(()),
+    }
+}
+
+/// Runs commands read from stdin over a single connection to chayd until exit or EOF.
+pub async fn run_shell(
+    client: &mut ChaydClient,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut editor = rustyline::Editor::<ShellHelper>::new()?;
+    editor.set_helper(Some(ShellHelper {
+        program_names: vec![],
+    }));
+    let history_path =
+        std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(HISTORY_FILE_NAME));
+    if let Some(history_path) = &history_path {
+        // The history file doesn't exist the first time the shell is run.
+        let _ = editor.load_history(history_path);
+    }
+    loop {
+        let program_names = fetch_program_names(client).await;
+        if let Some(helper) = editor.helper_mut() {
+            helper.program_names = program_names;
+        }
+        // Reading a line blocks, so don't hold up the runtime's other tasks meanwhile.
+        let line = match tokio::task::block_in_place(|| editor.readline(PROMPT)) {
+            Ok(line) => line,
+            // Ctrl-C discards the current line like in other shells.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error.into()),
+        };
+        // Split like a POSIX shell does, so program expressions can be quoted.
+        let words = match shell_words::split(&line) {
+            Ok(words) => words,
+            Err(error) => {
+                eprintln!("Could not parse command: {error}");
+                continue;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str());
+        let shell_line = match ShellLine::try_parse_from(&words) {
+            Ok(shell_line) => shell_line,
+            Err(error) => {
+                // Also covers `help`, which clap reports as an error.
+                let _ = error.print();
+                continue;
+            }
+        };
+        if let ShellCommand::Exit = shell_line.command {
+            break;
+        }
+        if let Err(error) = handle_shell_command(client, output, &shell_line.command).await {
+            crate::print_action_error(error.as_ref());
+        }
+    }
+    if let Some(history_path) = &history_path {
+        if let Err(error) = editor.save_history(history_path) {
+            eprintln!(
+                "Could not save history to {}: {}",
+                history_path.display(),
+                error
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> ShellCommand {
+        let words = shell_words::split(line).unwrap();
+        ShellLine::try_parse_from(words).unwrap().command
+    }
+
+    #[test]
+    fn parses_quoted_program_expressions() {
+        match parse_line("signal HUP 'web-*'") {
+            ShellCommand::Signal {
+                signal,
+                program_expr,
+                ..
+            } => {
+                assert_eq!(signal, "HUP");
+                assert_eq!(program_expr, "web-*");
+            }
+            _ => panic!("Expected a signal command"),
+        }
+        match parse_line(r#"status "label:tier=web,group:batch""#) {
+            ShellCommand::Status { program_expr } => {
+                assert_eq!(program_expr.as_deref(), Some("label:tier=web,group:batch"));
+            }
+            _ => panic!("Expected a status command"),
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use crate::chay_proto;
+use crate::output::{
+    format_duration, format_program_exit_ago, format_program_transition, format_resource_usage,
+    state_name,
+};
+use crate::ChaydClient;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use std::collections::{HashMap, VecDeque};
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Modifier, Style};
+use tui::widgets::{Block, Borders, Cell, List, ListItem, Paragraph, Row, Table, TableState};
+
+/// Number of transitions kept for the transitions pane.
+const MAX_PROGRAM_TRANSITIONS: usize = 200;
+const PROGRAM_TRANSITIONS_HEIGHT: u16 = 8;
+/// Only the end of the selected program's output is kept for the output pane.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+const OUTPUT_HEIGHT: u16 = 12;
+const COLUMN_WIDTHS: [Constraint; 9] = [
+    Constraint::Percentage(20),
+    Constraint::Length(9),
+    Constraint::Length(8),
+    Constraint::Length(7),
+    Constraint::Length(8),
+    Constraint::Length(14),
+    Constraint::Length(9),
+    Constraint::Length(22),
+    Constraint::Percentage(40),
+];
+const HELP: &str =
+    "q: quit  up/down: select  s: start  t: stop  r: restart  f: follow output of selected program";
+
+type Terminal = tui::Terminal<CrosstermBackend<std::io::Stdout>>;
+
+/// Puts the terminal in raw mode on the alternate screen, and restores it when dropped so the
+/// shell is usable again even if chay exits with an error.
+struct TerminalGuard {
+    terminal: Terminal,
+}
+
+impl TerminalGuard {
+    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        crossterm::terminal::enable_raw_mode()?;
+        let mut stdout = std::io::stdout();
+        crossterm::execute!(stdout, crossterm::terminal::EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        Ok(Self { terminal })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+        let _ = crossterm::execute!(
+            self.terminal.backend_mut(),
+            crossterm::terminal::LeaveAlternateScreen
+        );
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Reads terminal events on a thread since crossterm's reads block. The thread stops once the
+/// receiver is dropped.
+fn spawn_terminal_events_reader(terminal_events_tx: tokio::sync::mpsc::Sender<Event>) {
+    std::thread::spawn(move || {
+        while !terminal_events_tx.is_closed() {
+            match crossterm::event::poll(std::time::Duration::from_millis(200)) {
+                Ok(true) => match crossterm::event::read() {
+                    Ok(event) => {
+                        if terminal_events_tx.blocking_send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                },
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+#[derive(Default)]
+struct Top {
+    program_statuses: Vec<chay_proto::ProgramStatus>,
+    program_transitions: VecDeque<chay_proto::ProgramTransition>,
+    /// Kept by name rather than index so the selection doesn't move when programs are added or
+    /// removed.
+    selected_program: Option<String>,
+    /// End of the selected program's output.
+    output: Vec<u8>,
+    /// Keep adding the selected program's new output to the output pane.
+    follow: bool,
+    /// Result of the last action, shown above the help line.
+    message: String,
+}
+
+impl Top {
+    fn selected_index(&self) -> Option<usize> {
+        let selected_program = self.selected_program.as_ref()?;
+        self.program_statuses
+            .iter()
+            .position(|program_status| &program_status.name == selected_program)
+    }
+
+    fn set_program_statuses(&mut self, program_statuses: Vec<chay_proto::ProgramStatus>) {
+        self.program_statuses = program_statuses;
+        if self.selected_index().is_none() {
+            self.selected_program = self
+                .program_statuses
+                .first()
+                .map(|program_status| program_status.name.clone());
+        }
+    }
+
+    fn add_program_transition(&mut self, program_transition: chay_proto::ProgramTransition) {
+        if self.program_transitions.len() == MAX_PROGRAM_TRANSITIONS {
+            self.program_transitions.pop_front();
+        }
+        self.program_transitions.push_back(program_transition);
+    }
+
+    fn add_output(&mut self, output: &[u8]) {
+        self.output.extend_from_slice(output);
+        if self.output.len() > MAX_OUTPUT_BYTES {
+            let num_dropped = self.output.len() - MAX_OUTPUT_BYTES;
+            self.output.drain(..num_dropped);
+        }
+    }
+
+    /// Moves the selection up (negative offset) or down, stopping at the first and last programs.
+    fn move_selection(&mut self, offset: isize) {
+        if self.program_statuses.is_empty() {
+            return;
+        }
+        let index = self.selected_index().unwrap_or(0) as isize + offset;
+        let index = index.clamp(0, self.program_statuses.len() as isize - 1) as usize;
+        self.selected_program = Some(self.program_statuses[index].name.clone());
+    }
+
+    fn draw(&self, terminal: &mut Terminal) -> Result<(), Box<dyn std::error::Error>> {
+        let header = Row::new(
+            [
+                "NAME",
+                "STATE",
+                "PID",
+                "CPU",
+                "MEM",
+                "UPTIME",
+                "RESTARTS",
+                "LAST EXIT",
+                "DESCRIPTION",
+            ]
+            .iter()
+            .map(|header| Cell::from(*header)),
+        )
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.program_statuses.iter().map(|program_status| {
+            let uptime = program_status
+                .uptime
+                .as_ref()
+                .and_then(|uptime| std::time::Duration::try_from(uptime.clone()).ok());
+            let (cpu, mem) = format_resource_usage(program_status);
+            Row::new(vec![
+                Cell::from(program_status.name.clone()),
+                Cell::from(state_name(program_status.state()))
+                    .style(Style::default().fg(state_color(program_status.state()))),
+                Cell::from(
+                    program_status
+                        .pid
+                        .map_or("-".to_string(), |pid| pid.to_string()),
+                ),
+                Cell::from(cpu),
+                Cell::from(mem),
+                Cell::from(uptime.map_or("-".to_string(), format_duration)),
+                Cell::from(program_status.num_restarts.to_string()),
+                Cell::from(
+                    program_status
+                        .last_exit
+                        .as_ref()
+                        .map_or("-".to_string(), format_program_exit_ago),
+                ),
+                Cell::from(program_status.description.clone()),
+            ])
+        });
+        let table = Table::new(rows)
+            .header(header)
+            .block(Block::default().borders(Borders::ALL).title("Programs"))
+            .widths(&COLUMN_WIDTHS)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut table_state = TableState::default();
+        table_state.select(self.selected_index());
+
+        let output_title = match (&self.selected_program, self.follow) {
+            (Some(selected_program), true) => format!("Output of {selected_program} (following)"),
+            (Some(selected_program), false) => format!("Output of {selected_program}"),
+            (None, _) => "Output".to_string(),
+        };
+        // Only the last lines that fit in the pane. Control characters, e.g. the escape sequences
+        // of programs in a pty, would garble the pane.
+        let output = String::from_utf8_lossy(&self.output);
+        let output_lines: Vec<&str> = output.lines().collect();
+        let num_visible = OUTPUT_HEIGHT.saturating_sub(2) as usize;
+        let output_lines: Vec<ListItem> = output_lines
+            [output_lines.len().saturating_sub(num_visible)..]
+            .iter()
+            .map(|line| {
+                ListItem::new(
+                    line.replace('\t', " ")
+                        .replace(|c: char| c.is_control(), ""),
+                )
+            })
+            .collect();
+        let output_lines = List::new(output_lines)
+            .block(Block::default().borders(Borders::ALL).title(output_title));
+
+        // Only the most recent transitions that fit in the pane, with the newest at the bottom.
+        let num_visible = PROGRAM_TRANSITIONS_HEIGHT.saturating_sub(2) as usize;
+        let program_transitions: Vec<ListItem> = self
+            .program_transitions
+            .iter()
+            .skip(self.program_transitions.len().saturating_sub(num_visible))
+            .map(|program_transition| {
+                ListItem::new(format_program_transition(program_transition))
+                    .style(Style::default().fg(state_color(program_transition.to_state())))
+            })
+            .collect();
+        let program_transitions = List::new(program_transitions)
+            .block(Block::default().borders(Borders::ALL).title("Transitions"));
+
+        let footer = Paragraph::new(if self.message.is_empty() {
+            HELP.to_string()
+        } else {
+            format!("{}\n{}", self.message, HELP)
+        });
+
+        terminal.draw(|frame| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Min(3),
+                        Constraint::Length(OUTPUT_HEIGHT),
+                        Constraint::Length(PROGRAM_TRANSITIONS_HEIGHT),
+                        Constraint::Length(2),
+                    ]
+                    .as_ref(),
+                )
+                .split(frame.size());
+            frame.render_stateful_widget(table, chunks[0], &mut table_state);
+            frame.render_widget(output_lines, chunks[1]);
+            frame.render_widget(program_transitions, chunks[2]);
+            frame.render_widget(footer, chunks[3]);
+        })?;
+        Ok(())
+    }
+}
+
+fn state_color(state: chay_proto::ProgramState) -> Color {
+    match state {
+        chay_proto::ProgramState::Running => Color::Green,
+        chay_proto::ProgramState::Starting
+        | chay_proto::ProgramState::Stopping
+        | chay_proto::ProgramState::Exiting => Color::Yellow,
+        chay_proto::ProgramState::Backoff | chay_proto::ProgramState::Exited => Color::Red,
+        chay_proto::ProgramState::Stopped | chay_proto::ProgramState::Unspecified => Color::Reset,
+    }
+}
+
+/// Summarizes the results of an action on a single program for the message line.
+fn format_program_event_results(
+    program_name: &str,
+    action: &str,
+    program_event_results: &HashMap<String, chay_proto::ProgramEventResult>,
+) -> String {
+    match program_event_results
+        .get(program_name)
+        .and_then(|program_event_result| program_event_result.result.as_ref())
+    {
+        Some(chay_proto::program_event_result::Result::Ok(ok)) if ok.message.is_empty() => {
+            format!("{action} {program_name}: ok")
+        }
+        Some(chay_proto::program_event_result::Result::Ok(ok)) => {
+            format!("{action} {program_name}: {}", ok.message)
+        }
+        Some(chay_proto::program_event_result::Result::Err(err)) => {
+            format!("{action} {program_name} failed: {}", err.message)
+        }
+        None => format!("{action} {program_name}: missing result"),
+    }
+}
+
+/// Sends the action to the program, which is matched by its exact name. Errors are shown on the
+/// message line rather than leaving top.
+async fn send_program_action(client: &mut ChaydClient, action: &str, program_name: &str) -> String {
+    let program_expr = program_name.to_string();
+    let response = match action {
+        "start" => client
+            .start(tonic::Request::new(chay_proto::ChaydServiceStartRequest {
+                program_expr,
+                ..Default::default()
+            }))
+            .await
+            .map(|response| response.into_inner().program_event_results),
+        "stop" => client
+            .stop(tonic::Request::new(chay_proto::ChaydServiceStopRequest {
+                program_expr,
+                ..Default::default()
+            }))
+            .await
+            .map(|response| response.into_inner().program_event_results),
+        "restart" => client
+            .restart(tonic::Request::new(
+                chay_proto::ChaydServiceRestartRequest {
+                    program_expr,
+                    ..Default::default()
+                },
+            ))
+            .await
+            .map(|response| response.into_inner().program_event_results),
+        _ => unreachable!("Unknown action {action}"),
+    };
+    match response {
+        Ok(program_event_results) => {
+            format_program_event_results(program_name, action, &program_event_results)
+        }
+        Err(status) => format!("{action} {program_name} failed: {}", status.message()),
+    }
+}
+
+/// Replaces the output pane with the last lines of the selected program's output. The returned
+/// stream then sends the new output if following, or ends otherwise. Errors are shown on the
+/// message line, e.g. if the client isn't allowed to tail the program.
+async fn tail_selected_program(
+    client: &mut ChaydClient,
+    top: &mut Top,
+) -> Option<tonic::Streaming<chay_proto::ChaydServiceTailResponse>> {
+    top.output.clear();
+    let selected_program = top.selected_program.clone()?;
+    let response = client
+        .tail(tonic::Request::new(chay_proto::ChaydServiceTailRequest {
+            name: selected_program.clone(),
+            num_lines: OUTPUT_HEIGHT.saturating_sub(2) as u32,
+            follow: top.follow,
+        }))
+        .await;
+    match response {
+        Ok(response) => Some(response.into_inner()),
+        Err(status) => {
+            top.message = format!("tail {selected_program} failed: {}", status.message());
+            None
+        }
+    }
+}
+
+/// Shows the statuses of the matching programs, the output of the selected program and the recent
+/// transitions in a full-screen dashboard until the user quits.
+pub async fn run_top(
+    client: &mut ChaydClient,
+    program_expr: &Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program_expr = program_expr.clone().unwrap_or_default();
+    let mut program_statuses_stream = client
+        .get_status(tonic::Request::new(
+            chay_proto::ChaydServiceGetStatusRequest {
+                program_expr: program_expr.clone(),
+                watch: true,
+            },
+        ))
+        .await?
+        .into_inner();
+    let mut program_transitions_stream = client
+        .watch_events(tonic::Request::new(
+            chay_proto::ChaydServiceWatchEventsRequest {
+                program_expr: if program_expr.is_empty() {
+                    "all".to_string()
+                } else {
+                    program_expr
+                },
+            },
+        ))
+        .await?
+        .into_inner();
+
+    let mut terminal_guard = TerminalGuard::new()?;
+    let (terminal_events_tx, mut terminal_events_rx) = tokio::sync::mpsc::channel(16);
+    spawn_terminal_events_reader(terminal_events_tx);
+    let mut top = Top::default();
+    // The program and whether its output is followed, for the current tail stream.
+    let mut tailed_program: Option<(String, bool)> = None;
+    let mut tail_stream: Option<tonic::Streaming<chay_proto::ChaydServiceTailResponse>> = None;
+    top.draw(&mut terminal_guard.terminal)?;
+    loop {
+        tokio::select! {
+            response = program_statuses_stream.message() => match response? {
+                Some(response) => top.set_program_statuses(response.program_statuses),
+                None => return Err("chayd closed the status stream".into()),
+            },
+            response = program_transitions_stream.message() => match response? {
+                Some(response) => {
+                    if let Some(program_transition) = response.program_transition {
+                        top.add_program_transition(program_transition);
+                    }
+                }
+                None => return Err("chayd closed the events stream".into()),
+            },
+            response = async { tail_stream.as_mut().unwrap().message().await },
+                if tail_stream.is_some() => match response {
+                Ok(Some(response)) => top.add_output(&response.output),
+                // Sent all of the output when not following, or the program was removed.
+                Ok(None) => tail_stream = None,
+                Err(status) => {
+                    top.message = format!("tail failed: {}", status.message());
+                    tail_stream = None;
+                }
+            },
+            terminal_event = terminal_events_rx.recv() => match terminal_event {
+                Some(Event::Key(KeyEvent { code, modifiers, .. })) => match code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Up | KeyCode::Char('k') => top.move_selection(-1),
+                    KeyCode::Down | KeyCode::Char('j') => top.move_selection(1),
+                    KeyCode::Char('f') => top.follow = !top.follow,
+                    KeyCode::Char(key @ ('s' | 't' | 'r')) => {
+                        if let Some(selected_program) = top.selected_program.clone() {
+                            let action = match key {
+                                's' => "start",
+                                't' => "stop",
+                                _ => "restart",
+                            };
+                            top.message = send_program_action(client, action, &selected_program).await;
+                        }
+                    }
+                    _ => {}
+                },
+                // Redraw on resizes.
+                Some(_) => {}
+                None => return Err("Could not read terminal events".into()),
+            },
+        }
+        let selected_program = top
+            .selected_program
+            .clone()
+            .map(|selected_program| (selected_program, top.follow));
+        if selected_program != tailed_program {
+            tailed_program = selected_program;
+            tail_stream = tail_selected_program(client, &mut top).await;
+        }
+        top.draw(&mut terminal_guard.terminal)?;
+    }
+    Ok(())
+}
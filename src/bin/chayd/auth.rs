@@ -0,0 +1,460 @@
+use crate::config::AuthConfig;
+use crate::program_selector::{ProgramSelector, SelectorTarget};
+use crate::request_error::RequestError;
+use sha2::{Digest, Sha256};
+use tonic::transport::server::{TlsConnectInfo, UdsConnectInfo};
+
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+const UNIX_USER_PREFIX: &str = "user:";
+
+pub const STATUS_ACTION: &str = "status";
+pub const ADD_PROGRAM_ACTION: &str = "add";
+pub const UPDATE_PROGRAM_ACTION: &str = "update";
+pub const REMOVE_PROGRAM_ACTION: &str = "remove";
+pub const RELOAD_CONFIG_ACTION: &str = "reload_config";
+pub const ATTACH_ACTION: &str = "attach";
+pub const EXEC_ACTION: &str = "exec";
+pub const RUN_JOB_ACTION: &str = "run_job";
+pub const TAIL_ACTION: &str = "tail";
+const ACTIONS: [&str; 14] = [
+    "start",
+    "stop",
+    "restart",
+    "reload",
+    "signal",
+    STATUS_ACTION,
+    ADD_PROGRAM_ACTION,
+    UPDATE_PROGRAM_ACTION,
+    REMOVE_PROGRAM_ACTION,
+    RELOAD_CONFIG_ACTION,
+    ATTACH_ACTION,
+    EXEC_ACTION,
+    RUN_JOB_ACTION,
+    TAIL_ACTION,
+];
+/// Actions that run commands chosen by the client, which are denied without authentication unless
+/// chayd.allow_unauthenticated_commands is set.
+const COMMAND_ACTIONS: [&str; 4] = [
+    ADD_PROGRAM_ACTION,
+    UPDATE_PROGRAM_ACTION,
+    EXEC_ACTION,
+    RUN_JOB_ACTION,
+];
+
+/// An auth rule from the config with its program expression parsed.
+#[derive(Clone, Debug)]
+pub struct AuthRule {
+    identity: String,
+    actions: Vec<String>,
+    programs: ProgramSelector,
+}
+
+impl AuthRule {
+    fn allows_action(&self, action: &str) -> bool {
+        self.actions
+            .iter()
+            .any(|rule_action| rule_action == "*" || rule_action == action)
+    }
+}
+
+/// The actions an authenticated client is allowed to perform.
+#[derive(Clone, Debug)]
+pub enum Permissions {
+    /// Authentication is disabled, so every client may do anything except run commands of its
+    /// choosing, which is only allowed with allow_commands.
+    AllowAll { allow_commands: bool },
+    Rules {
+        identity: String,
+        rules: Vec<AuthRule>,
+    },
+}
+
+impl Permissions {
+    pub fn is_allowed(&self, action: &str, target: &SelectorTarget) -> bool {
+        match self {
+            Permissions::AllowAll { allow_commands } => {
+                *allow_commands || !COMMAND_ACTIONS.contains(&action)
+            }
+            Permissions::Rules { rules, .. } => rules
+                .iter()
+                .any(|rule| rule.allows_action(action) && rule.programs.matches(target)),
+        }
+    }
+
+    /// For actions that affect every program, which are only allowed by rules for all programs.
+    pub fn is_allowed_for_all_programs(&self, action: &str) -> bool {
+        match self {
+            Permissions::AllowAll { allow_commands } => {
+                *allow_commands || !COMMAND_ACTIONS.contains(&action)
+            }
+            Permissions::Rules { rules, .. } => rules
+                .iter()
+                .any(|rule| rule.allows_action(action) && rule.programs.is_all()),
+        }
+    }
+
+    /// Name of the authenticated identity, if authentication is enabled.
+    pub fn identity(&self) -> Option<&str> {
+        match self {
+            Permissions::AllowAll { .. } => None,
+            Permissions::Rules { identity, .. } => Some(identity),
+        }
+    }
+
+    pub fn denied_message(&self, action: &str) -> String {
+        match self {
+            Permissions::AllowAll { .. } => format!(
+                "Permission denied: {action} requires an [auth] config or \
+                chayd.allow_unauthenticated_commands"
+            ),
+            Permissions::Rules { identity, .. } => {
+                format!("Permission denied: {identity} may not {action} this program")
+            }
+        }
+    }
+}
+
+pub struct Authenticator {
+    config: Option<AuthConfig>,
+    rules: Vec<AuthRule>,
+    allow_unauthenticated_commands: bool,
+}
+
+impl Authenticator {
+    pub fn new(
+        config: Option<AuthConfig>,
+        allow_unauthenticated_commands: bool,
+    ) -> Result<Self, String> {
+        let mut rules = vec![];
+        if let Some(config) = &config {
+            for rule in &config.rules {
+                for action in &rule.actions {
+                    if action != "*" && !ACTIONS.contains(&action.as_str()) {
+                        return Err(format!("Unknown action in auth rule: {action}"));
+                    }
+                }
+                rules.push(AuthRule {
+                    identity: rule.identity.clone(),
+                    actions: rule.actions.clone(),
+                    programs: rule.programs.parse()?,
+                });
+            }
+            for (identity, token) in &config.tokens {
+                if token.is_empty() {
+                    return Err(format!("Empty token for identity: {identity}"));
+                }
+            }
+        }
+        Ok(Self {
+            config,
+            rules,
+            allow_unauthenticated_commands,
+        })
+    }
+
+    /// Returns the permissions of the client that sent the request, or an unauthenticated status
+    /// if the client could not be identified.
+    pub fn authenticate<T>(
+        &self,
+        request: &tonic::Request<T>,
+    ) -> Result<Permissions, RequestError> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => {
+                return Ok(Permissions::AllowAll {
+                    allow_commands: self.allow_unauthenticated_commands,
+                })
+            }
+        };
+        let identity = match Self::token_identity(config, request)? {
+            Some(identity) => identity,
+            None => match Self::unix_peer_identity(config, request) {
+                Some(identity) => identity,
+                None => {
+                    return Err(RequestError::new(
+                        tonic::Code::Unauthenticated,
+                        "Missing credentials",
+                    ))
+                }
+            },
+        };
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.identity == "*" || rule.identity == identity)
+            .cloned()
+            .collect();
+        Ok(Permissions::Rules { identity, rules })
+    }
+
+    fn token_identity<T>(
+        config: &AuthConfig,
+        request: &tonic::Request<T>,
+    ) -> Result<Option<String>, RequestError> {
+        let header = match request.metadata().get(AUTHORIZATION_HEADER) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|header| header.strip_prefix(BEARER_PREFIX))
+            .ok_or_else(|| {
+                RequestError::new(
+                    tonic::Code::Unauthenticated,
+                    "Malformed authorization header",
+                )
+            })?;
+        // Compares against every token so the time taken doesn't tell which one nearly matched.
+        let mut identity = None;
+        for (token_identity, configured_token) in &config.tokens {
+            if tokens_equal(configured_token, token) && identity.is_none() {
+                identity = Some(token_identity.clone());
+            }
+        }
+        match identity {
+            Some(identity) => Ok(Some(identity)),
+            None => Err(RequestError::new(
+                tonic::Code::Unauthenticated,
+                "Invalid token",
+            )),
+        }
+    }
+
+    fn unix_peer_identity<T>(config: &AuthConfig, request: &tonic::Request<T>) -> Option<String> {
+        if !config.unix_peer_credentials {
+            return None;
+        }
+        let connect_info = match request.extensions().get::<UdsConnectInfo>() {
+            Some(connect_info) => connect_info,
+            None => request
+                .extensions()
+                .get::<TlsConnectInfo<UdsConnectInfo>>()?
+                .get_ref(),
+        };
+        let uid = nix::unistd::Uid::from_raw(connect_info.peer_cred?.uid());
+        match nix::unistd::User::from_uid(uid) {
+            Ok(Some(user)) => Some(format!("{UNIX_USER_PREFIX}{}", user.name)),
+            Ok(None) | Err(_) => {
+                log::warn!("Could not look up user name of uid {}", uid);
+                None
+            }
+        }
+    }
+}
+
+/// Compares the SHA-256 digests of the tokens in constant time, so neither the position of the
+/// first differing byte nor the token length leak through timing.
+fn tokens_equal(a: &str, b: &str) -> bool {
+    let a_digest = Sha256::digest(a.as_bytes());
+    let b_digest = Sha256::digest(b.as_bytes());
+    a_digest
+        .iter()
+        .zip(b_digest.iter())
+        .fold(0, |difference, (a_byte, b_byte)| {
+            difference | (a_byte ^ b_byte)
+        })
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthRuleConfig;
+    use std::collections::BTreeMap;
+
+    fn rule(identity: &str, actions: &[&str], programs: &str) -> AuthRuleConfig {
+        AuthRuleConfig {
+            identity: identity.to_string(),
+            actions: actions.iter().map(|action| action.to_string()).collect(),
+            programs: programs.to_string(),
+        }
+    }
+
+    fn authenticator(rules: Vec<AuthRuleConfig>) -> Authenticator {
+        let config = AuthConfig {
+            tokens: BTreeMap::from([
+                ("deploy".to_string(), "deploy-token".to_string()),
+                ("ops".to_string(), "ops-token".to_string()),
+            ]),
+            unix_peer_credentials: true,
+            rules,
+        };
+        Authenticator::new(Some(config), false).unwrap()
+    }
+
+    fn request_with_token(token: &str) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        request.metadata_mut().insert(
+            AUTHORIZATION_HEADER,
+            format!("{BEARER_PREFIX}{token}").parse().unwrap(),
+        );
+        request
+    }
+
+    fn target(name: &str) -> SelectorTarget {
+        SelectorTarget::from_name(name)
+    }
+
+    #[test]
+    fn allow_all_denies_commands_unless_allowed() {
+        let permissions = Authenticator::new(None, false)
+            .unwrap()
+            .authenticate(&tonic::Request::new(()))
+            .unwrap();
+        assert!(permissions.identity().is_none());
+        assert!(permissions.is_allowed("start", &target("web")));
+        assert!(permissions.is_allowed_for_all_programs(RELOAD_CONFIG_ACTION));
+        for action in COMMAND_ACTIONS {
+            assert!(!permissions.is_allowed(action, &target("web")));
+            assert!(!permissions.is_allowed_for_all_programs(action));
+        }
+        assert!(permissions
+            .denied_message(EXEC_ACTION)
+            .contains("allow_unauthenticated_commands"));
+
+        let permissions = Authenticator::new(None, true)
+            .unwrap()
+            .authenticate(&tonic::Request::new(()))
+            .unwrap();
+        for action in COMMAND_ACTIONS {
+            assert!(permissions.is_allowed(action, &target("web")));
+            assert!(permissions.is_allowed_for_all_programs(action));
+        }
+    }
+
+    #[test]
+    fn looks_up_token_identities() {
+        let authenticator = authenticator(vec![]);
+        let permissions = authenticator
+            .authenticate(&request_with_token("ops-token"))
+            .unwrap();
+        assert_eq!(permissions.identity(), Some("ops"));
+        let permissions = authenticator
+            .authenticate(&request_with_token("deploy-token"))
+            .unwrap();
+        assert_eq!(permissions.identity(), Some("deploy"));
+
+        for token in ["ops-token2", "ops-toke", ""] {
+            let status = authenticator
+                .authenticate(&request_with_token(token))
+                .map_err(tonic::Status::from)
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+            assert_eq!(status.message(), "Invalid token");
+        }
+
+        let mut request = tonic::Request::new(());
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_HEADER, "Basic ops-token".parse().unwrap());
+        let status = authenticator
+            .authenticate(&request)
+            .map_err(tonic::Status::from)
+            .unwrap_err();
+        assert_eq!(status.message(), "Malformed authorization header");
+
+        let status = authenticator
+            .authenticate(&tonic::Request::new(()))
+            .map_err(tonic::Status::from)
+            .unwrap_err();
+        assert_eq!(status.message(), "Missing credentials");
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(tokens_equal("ops-token", "ops-token"));
+        assert!(!tokens_equal("ops-token", "ops-tokeN"));
+        assert!(!tokens_equal("ops-token", "ops-token-"));
+        assert!(!tokens_equal("ops-token", ""));
+    }
+
+    #[tokio::test]
+    async fn identifies_unix_peers_by_uid() {
+        let (stream, _peer_stream) = tokio::net::UnixStream::pair().unwrap();
+        let mut request = tonic::Request::new(());
+        request.extensions_mut().insert(UdsConnectInfo {
+            peer_addr: None,
+            peer_cred: Some(stream.peer_cred().unwrap()),
+        });
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let permissions = authenticator(vec![]).authenticate(&request).unwrap();
+        assert_eq!(
+            permissions.identity(),
+            Some(format!("{UNIX_USER_PREFIX}{}", user.name).as_str())
+        );
+
+        let config = AuthConfig {
+            tokens: BTreeMap::new(),
+            unix_peer_credentials: false,
+            rules: vec![],
+        };
+        let status = Authenticator::new(Some(config), false)
+            .unwrap()
+            .authenticate(&request)
+            .map_err(tonic::Status::from)
+            .unwrap_err();
+        assert_eq!(status.message(), "Missing credentials");
+    }
+
+    #[test]
+    fn matches_rules_of_the_identity() {
+        let authenticator = authenticator(vec![
+            rule("ops", &["start", "stop"], "web-*"),
+            rule("ops", &[EXEC_ACTION], "label:exec=yes"),
+            rule("deploy", &["*"], "all"),
+            rule("*", &[STATUS_ACTION], "all"),
+        ]);
+        let ops = authenticator
+            .authenticate(&request_with_token("ops-token"))
+            .unwrap();
+        assert!(ops.is_allowed("start", &target("web-1")));
+        assert!(ops.is_allowed("stop", &target("web-1")));
+        assert!(!ops.is_allowed("restart", &target("web-1")));
+        assert!(!ops.is_allowed("start", &target("db")));
+        assert!(!ops.is_allowed(EXEC_ACTION, &target("web-1")));
+        let mut exec_target = target("web-1");
+        exec_target
+            .labels
+            .insert("exec".to_string(), "yes".to_string());
+        assert!(ops.is_allowed(EXEC_ACTION, &exec_target));
+        assert!(ops.is_allowed(STATUS_ACTION, &target("db")));
+        assert!(!ops.is_allowed_for_all_programs("start"));
+        assert!(ops.is_allowed_for_all_programs(STATUS_ACTION));
+        assert_eq!(
+            ops.denied_message("restart"),
+            "Permission denied: ops may not restart this program"
+        );
+
+        let deploy = authenticator
+            .authenticate(&request_with_token("deploy-token"))
+            .unwrap();
+        assert!(deploy.is_allowed(RUN_JOB_ACTION, &target("db")));
+        assert!(deploy.is_allowed_for_all_programs(RELOAD_CONFIG_ACTION));
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let config = AuthConfig {
+            tokens: BTreeMap::new(),
+            unix_peer_credentials: true,
+            rules: vec![rule("ops", &["launch"], "all")],
+        };
+        assert_eq!(
+            Authenticator::new(Some(config), false).err().unwrap(),
+            "Unknown action in auth rule: launch"
+        );
+        let config = AuthConfig {
+            tokens: BTreeMap::from([("ops".to_string(), String::new())]),
+            unix_peer_credentials: true,
+            rules: vec![],
+        };
+        assert_eq!(
+            Authenticator::new(Some(config), false).err().unwrap(),
+            "Empty token for identity: ops"
+        );
+    }
+}
@@ -1,17 +1,39 @@
+use crate::auth::{Authenticator, Permissions};
 use crate::bug_panic;
 use crate::chay_proto;
-use crate::program_fsm::{ProgramEvent, ProgramFsm, ProgramState};
+use crate::jobs::{GetJobRequest, RunJobRequest};
+use crate::program_changes::{
+    ConfigReloadRequest, ProgramChange, ProgramChangeRequest, ProgramChangeResult,
+};
+use crate::program_console::{AttachRequest, ConsoleOutput, ConsoleStream, TailRequest};
+use crate::program_context::SignalTarget;
+use crate::program_exec::ExecRequest;
+use crate::program_fsm::{ProgramEvent, ProgramFsm, ProgramTransition};
+use crate::program_selector::{ProgramSelector, SelectorTarget};
 use crate::proto_converters::{
-    proto_from_program_state, proto_restart_response_from_program_events_results,
+    proto_from_program_transition, proto_program_status_from_program_fsm,
+    proto_reload_config_response_from_config_changes,
+    proto_reload_response_from_program_events_results,
+    proto_restart_response_from_program_events_results,
+    proto_signal_response_from_program_events_results,
     proto_start_response_from_program_events_results,
-    proto_stop_response_from_program_events_results,
+    proto_stop_response_from_program_events_results, signal_target_from_proto,
 };
+use crate::request_error::RequestError;
 use chay_proto::chayd_service_server::ChaydService;
 use chay_proto::{
-    ChaydServiceGetHealthRequest, ChaydServiceGetHealthResponse, ChaydServiceGetStatusRequest,
-    ChaydServiceGetStatusResponse, ChaydServiceRestartRequest, ChaydServiceRestartResponse,
-    ChaydServiceStartRequest, ChaydServiceStartResponse, ChaydServiceStopRequest,
-    ChaydServiceStopResponse,
+    ChaydServiceAddProgramRequest, ChaydServiceAddProgramResponse, ChaydServiceAttachRequest,
+    ChaydServiceAttachResponse, ChaydServiceExecRequest, ChaydServiceExecResponse,
+    ChaydServiceGetHealthRequest, ChaydServiceGetHealthResponse, ChaydServiceGetJobRequest,
+    ChaydServiceGetJobResponse, ChaydServiceGetStatusRequest, ChaydServiceGetStatusResponse,
+    ChaydServiceReloadConfigRequest, ChaydServiceReloadConfigResponse, ChaydServiceReloadRequest,
+    ChaydServiceReloadResponse, ChaydServiceRemoveProgramRequest,
+    ChaydServiceRemoveProgramResponse, ChaydServiceRestartRequest, ChaydServiceRestartResponse,
+    ChaydServiceRunJobRequest, ChaydServiceRunJobResponse, ChaydServiceSignalRequest,
+    ChaydServiceSignalResponse, ChaydServiceStartRequest, ChaydServiceStartResponse,
+    ChaydServiceStopRequest, ChaydServiceStopResponse, ChaydServiceTailRequest,
+    ChaydServiceTailResponse, ChaydServiceUpdateProgramRequest, ChaydServiceUpdateProgramResponse,
+    ChaydServiceWatchEventsRequest, ChaydServiceWatchEventsResponse,
 };
 use futures_core;
 use std::collections::HashMap;
@@ -19,65 +41,286 @@ use std::pin::Pin;
 use tokio_stream;
 use tonic;
 
+/// A program's status along with what program expressions are matched against, so each client
+/// only gets the statuses it asked for and is allowed to see.
+#[derive(Clone, Debug)]
+pub struct SelectableProgramStatus {
+    pub selector_target: SelectorTarget,
+    pub program_status: chay_proto::ProgramStatus,
+}
+
 #[derive(Default)]
-pub struct ProgramStatesChannels {
-    pub senders:
-        HashMap<std::net::SocketAddr, tokio::sync::mpsc::Sender<HashMap<String, ProgramState>>>,
+pub struct ProgramStatusesChannels {
+    /// Keyed by a client ID rather than the remote address since clients connected over a Unix
+    /// domain socket don't have one.
+    pub senders: HashMap<u64, tokio::sync::mpsc::Sender<Vec<SelectableProgramStatus>>>,
+    next_client_id: u64,
+    /// The most recently broadcast statuses, for clients that only want a snapshot.
+    pub latest_program_statuses: Vec<SelectableProgramStatus>,
 }
 
-impl ProgramStatesChannels {
-    pub async fn broadcast(&self, program_states: &HashMap<String, ProgramState>) {
-        for (socket, tx) in &self.senders {
-            match tx.send(program_states.clone()).await {
-                Ok(_) => {}
-                Err(_) => {
-                    log::error!("[Broadcast] SendError: to {}", socket)
+impl ProgramStatusesChannels {
+    /// Registers a new status stream and returns its client ID.
+    pub fn add_sender(
+        &mut self,
+        sender: tokio::sync::mpsc::Sender<Vec<SelectableProgramStatus>>,
+    ) -> u64 {
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        self.senders.insert(client_id, sender);
+        client_id
+    }
+
+    /// Never waits for a client, since the lock is held meanwhile. Every update holds all the
+    /// statuses, so a client that hasn't taken the previous one yet just skips this one.
+    pub fn broadcast(&mut self, program_statuses: Vec<SelectableProgramStatus>) {
+        self.senders.retain(
+            |client_id, tx| match tx.try_send(program_statuses.clone()) {
+                Ok(_) => true,
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                    log::debug!(
+                        "Status client {} lagged behind, skipped an update",
+                        client_id
+                    );
+                    true
                 }
-            }
-        }
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+            },
+        );
+        self.latest_program_statuses = program_statuses;
     }
 }
 
-pub async fn broadcast_program_states(
-    program_fsms: &Vec<ProgramFsm>,
-    program_states_channels: &std::sync::Arc<tokio::sync::RwLock<ProgramStatesChannels>>,
+/// Broadcasts the statuses of the programs and of the running jobs.
+pub async fn broadcast_program_statuses<'a>(
+    program_fsms: impl Iterator<Item = &'a ProgramFsm>,
+    program_statuses_channels: &std::sync::Arc<tokio::sync::RwLock<ProgramStatusesChannels>>,
 ) {
-    let program_states: HashMap<String, ProgramState> = program_fsms
-        .iter()
-        .map(|machine| (machine.app_context().name(), machine.current_state_key()))
+    let program_statuses: Vec<SelectableProgramStatus> = program_fsms
+        .map(|program_fsm| SelectableProgramStatus {
+            selector_target: SelectorTarget::from_program_fsm(program_fsm),
+            program_status: proto_program_status_from_program_fsm(program_fsm),
+        })
         .collect();
-    program_states_channels
-        .read()
+    program_statuses_channels
+        .write()
         .await
-        .broadcast(&program_states)
-        .await;
+        .broadcast(program_statuses);
 }
 
 pub type ProgramEventsResult = Result<HashMap<String, chay::fsm::MachineResult>, tonic::Status>;
 
+/// Something a client asked to do to a program.
+pub enum ProgramAction {
+    /// Handled by the program's state machine.
+    Event(ProgramEvent),
+    /// Sent to the program's processes directly, without affecting its state.
+    Signal {
+        signal: nix::sys::signal::Signal,
+        target: SignalTarget,
+    },
+}
+
+impl ProgramAction {
+    /// Name of the action as used in auth rules.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgramAction::Event(ProgramEvent::Start) => "start",
+            ProgramAction::Event(ProgramEvent::Stop) => "stop",
+            ProgramAction::Event(ProgramEvent::Restart) => "restart",
+            ProgramAction::Event(ProgramEvent::Reload) => "reload",
+            ProgramAction::Signal { .. } => "signal",
+        }
+    }
+}
+
+/// Wait for the programs to reach the state their event leads to before sending the results.
+#[derive(Clone, Copy, Debug)]
+pub struct WaitOptions {
+    /// Wait forever if None.
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl WaitOptions {
+    fn from_request(wait: bool, timeout_secs: Option<u32>) -> Option<Self> {
+        if !wait {
+            return None;
+        }
+        Some(Self {
+            timeout: timeout_secs
+                .map(|timeout_secs| std::time::Duration::from_secs(timeout_secs as u64)),
+        })
+    }
+}
+
+/// A program action to perform on every program matching the expression that the client has the
+/// permissions for, along with the channel to send the results back on.
+pub struct ProgramEventsRequest {
+    pub program_action: ProgramAction,
+    pub program_selector: ProgramSelector,
+    pub permissions: Permissions,
+    /// Describes the client that sent the request, e.g. "deploy (127.0.0.1:41234)".
+    pub source: String,
+    pub wait: Option<WaitOptions>,
+    pub results_sender: tokio::sync::mpsc::Sender<ProgramEventsResult>,
+}
+
+/// Keeps the statuses of the programs that match the selector and the client may see.
+fn filter_program_statuses(
+    program_statuses: Vec<SelectableProgramStatus>,
+    program_selector: &ProgramSelector,
+    permissions: &Permissions,
+) -> Vec<chay_proto::ProgramStatus> {
+    program_statuses
+        .into_iter()
+        .filter(|program_status| {
+            program_selector.matches(&program_status.selector_target)
+                && permissions
+                    .is_allowed(crate::auth::STATUS_ACTION, &program_status.selector_target)
+        })
+        .map(|program_status| program_status.program_status)
+        .collect()
+}
+
+fn request_source<T>(request: &tonic::Request<T>, permissions: &Permissions) -> String {
+    let remote_addr = match request.remote_addr() {
+        Some(remote_addr) => remote_addr.to_string(),
+        None => "unix socket".to_string(),
+    };
+    match permissions.identity() {
+        Some(identity) => format!("{identity} ({remote_addr})"),
+        None => remote_addr,
+    }
+}
+
+/// The channels the service sends its requests to the main loop through.
+pub struct ChaydServiceSenders {
+    pub program_events: tokio::sync::mpsc::Sender<ProgramEventsRequest>,
+    pub program_transitions: tokio::sync::broadcast::Sender<ProgramTransition>,
+    pub program_changes: tokio::sync::mpsc::Sender<ProgramChangeRequest>,
+    pub config_reloads: tokio::sync::mpsc::Sender<ConfigReloadRequest>,
+    pub attach_requests: tokio::sync::mpsc::Sender<AttachRequest>,
+    pub exec_requests: tokio::sync::mpsc::Sender<ExecRequest>,
+    pub run_job_requests: tokio::sync::mpsc::Sender<RunJobRequest>,
+    pub get_job_requests: tokio::sync::mpsc::Sender<GetJobRequest>,
+    pub tail_requests: tokio::sync::mpsc::Sender<TailRequest>,
+}
+
 pub struct ChaydServiceImpl {
-    program_states_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatesChannels>>,
-    program_events_sender: tokio::sync::mpsc::Sender<(
-        ProgramEvent,
-        String,
-        tokio::sync::mpsc::Sender<ProgramEventsResult>,
-    )>,
+    program_statuses_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatusesChannels>>,
+    senders: ChaydServiceSenders,
+    /// Replaced by the main loop when the config is reloaded.
+    authenticator: std::sync::Arc<std::sync::RwLock<Authenticator>>,
 }
 
 impl ChaydServiceImpl {
     pub fn new(
-        program_states_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatesChannels>>,
-        program_events_sender: tokio::sync::mpsc::Sender<(
-            ProgramEvent,
-            String,
-            tokio::sync::mpsc::Sender<ProgramEventsResult>,
-        )>,
+        program_statuses_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatusesChannels>>,
+        senders: ChaydServiceSenders,
+        authenticator: std::sync::Arc<std::sync::RwLock<Authenticator>>,
     ) -> Self {
         Self {
-            program_states_channels,
-            program_events_sender,
+            program_statuses_channels,
+            senders,
+            authenticator,
         }
     }
+
+    fn authenticate<T>(&self, request: &tonic::Request<T>) -> Result<Permissions, RequestError> {
+        self.authenticator.read().unwrap().authenticate(request)
+    }
+
+    async fn send_program_action<T>(
+        &self,
+        program_action: ProgramAction,
+        program_expr: &str,
+        wait: Option<WaitOptions>,
+        request: &tonic::Request<T>,
+    ) -> ProgramEventsResult {
+        let permissions = self.authenticate(request)?;
+        let program_selector = program_expr
+            .parse()
+            .map_err(tonic::Status::invalid_argument)?;
+        let source = request_source(request, &permissions);
+        let (program_events_results_tx, mut program_events_results_rx) =
+            tokio::sync::mpsc::channel(1);
+        match self
+            .senders
+            .program_events
+            .send(ProgramEventsRequest {
+                program_action,
+                program_selector,
+                permissions,
+                source,
+                wait,
+                results_sender: program_events_results_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to program events channel");
+            }
+        }
+        match program_events_results_rx.recv().await {
+            Some(result) => result,
+            None => {
+                bug_panic("Received None from program events channel rx");
+                // Unreachable
+                Err(tonic::Status::unknown(
+                    "Received None from program events channel rx",
+                ))
+            }
+        }
+    }
+
+    async fn send_program_change(
+        &self,
+        program_name: &str,
+        change: ProgramChange,
+        permissions: Permissions,
+    ) -> ProgramChangeResult {
+        let (program_change_results_tx, mut program_change_results_rx) =
+            tokio::sync::mpsc::channel(1);
+        match self
+            .senders
+            .program_changes
+            .send(ProgramChangeRequest {
+                program_name: program_name.to_string(),
+                change,
+                permissions,
+                results_sender: program_change_results_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to program changes channel");
+            }
+        }
+        match program_change_results_rx.recv().await {
+            Some(result) => result,
+            None => {
+                bug_panic("Received None from program changes channel rx");
+                // Unreachable
+                Err(RequestError::new(
+                    tonic::Code::Unknown,
+                    "Received None from program changes channel rx",
+                ))
+            }
+        }
+    }
+}
+
+fn parse_program_config(
+    program_config: &str,
+) -> Result<crate::config::ProgramConfig, RequestError> {
+    toml::from_str(program_config).map_err(|error| {
+        RequestError::new(
+            tonic::Code::InvalidArgument,
+            format!("Invalid program config: {error}"),
+        )
+    })
 }
 
 #[tonic::async_trait]
@@ -89,10 +332,37 @@ impl ChaydService for ChaydServiceImpl {
         >,
     >;
 
+    type WatchEventsStream = Pin<
+        Box<
+            dyn futures_core::Stream<Item = Result<ChaydServiceWatchEventsResponse, tonic::Status>>
+                + Send,
+        >,
+    >;
+
+    type AttachStream = Pin<
+        Box<
+            dyn futures_core::Stream<Item = Result<ChaydServiceAttachResponse, tonic::Status>>
+                + Send,
+        >,
+    >;
+
+    type ExecStream = Pin<
+        Box<
+            dyn futures_core::Stream<Item = Result<ChaydServiceExecResponse, tonic::Status>> + Send,
+        >,
+    >;
+
+    type TailStream = Pin<
+        Box<
+            dyn futures_core::Stream<Item = Result<ChaydServiceTailResponse, tonic::Status>> + Send,
+        >,
+    >;
+
     async fn get_health(
         &self,
         request: tonic::Request<ChaydServiceGetHealthRequest>,
     ) -> Result<tonic::Response<ChaydServiceGetHealthResponse>, tonic::Status> {
+        self.authenticate(&request)?;
         log::info!("Received GetHealth request: {:?}", request.get_ref());
         let response = ChaydServiceGetHealthResponse {};
         Ok(tonic::Response::new(response))
@@ -102,29 +372,55 @@ impl ChaydService for ChaydServiceImpl {
         &self,
         request: tonic::Request<ChaydServiceGetStatusRequest>,
     ) -> tonic::Result<tonic::Response<Self::GetStatusStream>, tonic::Status> {
-        let remote_addr = request.remote_addr().unwrap();
+        let permissions = self.authenticate(&request)?;
+        let remote_addr = request.remote_addr();
+        let ChaydServiceGetStatusRequest {
+            program_expr,
+            watch,
+        } = request.into_inner();
+        // An empty expression selects every program.
+        let program_selector: ProgramSelector = if program_expr.is_empty() {
+            "all"
+        } else {
+            program_expr.as_str()
+        }
+        .parse()
+        .map_err(tonic::Status::invalid_argument)?;
+        if !watch {
+            let program_statuses = filter_program_statuses(
+                self.program_statuses_channels
+                    .read()
+                    .await
+                    .latest_program_statuses
+                    .clone(),
+                &program_selector,
+                &permissions,
+            );
+            if program_statuses.is_empty() && !program_expr.is_empty() {
+                return Err(tonic::Status::not_found(format!(
+                    "No programs found matching expression: {}",
+                    program_expr
+                )));
+            }
+            let response = ChaydServiceGetStatusResponse { program_statuses };
+            return Ok(tonic::Response::new(
+                Box::pin(tokio_stream::once(tonic::Result::Ok(response))) as Self::GetStatusStream,
+            ));
+        }
         log::info!("GetStatus client connected from {:?}", &remote_addr);
         let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(1);
-        let (program_states_tx, mut program_states_rx) = tokio::sync::mpsc::channel(1);
-        {
-            self.program_states_channels
+        let (program_statuses_tx, mut program_statuses_rx) = tokio::sync::mpsc::channel(1);
+        let client_id = {
+            self.program_statuses_channels
                 .write()
                 .await
-                .senders
-                .insert(remote_addr.clone(), program_states_tx);
-        }
-        let program_states_channels_clone = self.program_states_channels.clone();
+                .add_sender(program_statuses_tx)
+        };
+        let program_statuses_channels_clone = self.program_statuses_channels.clone();
         tokio::spawn(async move {
-            while let Some(program_states) = program_states_rx.recv().await {
-                let program_statuses_proto = program_states
-                    .iter()
-                    .map(|(program_name, program_state)| {
-                        let mut program_status = chay_proto::ProgramStatus::default();
-                        program_status.name = program_name.clone();
-                        program_status.set_state(proto_from_program_state(program_state.clone()));
-                        program_status
-                    })
-                    .collect();
+            while let Some(program_statuses) = program_statuses_rx.recv().await {
+                let program_statuses_proto =
+                    filter_program_statuses(program_statuses, &program_selector, &permissions);
                 let response = ChaydServiceGetStatusResponse {
                     program_statuses: program_statuses_proto,
                 };
@@ -138,11 +434,11 @@ impl ChaydService for ChaydServiceImpl {
                 }
             }
             {
-                program_states_channels_clone
+                program_statuses_channels_clone
                     .write()
                     .await
                     .senders
-                    .remove(&remote_addr);
+                    .remove(&client_id);
             }
             log::info!("GetStatus client disconnected from {:?}", &remote_addr);
         });
@@ -158,110 +454,564 @@ impl ChaydService for ChaydServiceImpl {
         request: tonic::Request<ChaydServiceStartRequest>,
     ) -> tonic::Result<tonic::Response<ChaydServiceStartResponse>, tonic::Status> {
         log::info!("Received Start request {:?}", request.get_ref());
-        let (program_events_results_tx, mut program_events_results_rx) =
+        let program_events_results = self
+            .send_program_action(
+                ProgramAction::Event(ProgramEvent::Start),
+                &request.get_ref().program_expr,
+                WaitOptions::from_request(request.get_ref().wait, request.get_ref().timeout_secs),
+                &request,
+            )
+            .await?;
+        Ok(tonic::Response::new(
+            proto_start_response_from_program_events_results(&program_events_results),
+        ))
+    }
+
+    async fn stop(
+        &self,
+        request: tonic::Request<ChaydServiceStopRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceStopResponse>, tonic::Status> {
+        log::info!("Received Stop request: {:?}", request.get_ref());
+        let program_events_results = self
+            .send_program_action(
+                ProgramAction::Event(ProgramEvent::Stop),
+                &request.get_ref().program_expr,
+                WaitOptions::from_request(request.get_ref().wait, request.get_ref().timeout_secs),
+                &request,
+            )
+            .await?;
+        Ok(tonic::Response::new(
+            proto_stop_response_from_program_events_results(&program_events_results),
+        ))
+    }
+
+    async fn restart(
+        &self,
+        request: tonic::Request<ChaydServiceRestartRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceRestartResponse>, tonic::Status> {
+        log::info!("Received Restart request: {:?}", request.get_ref());
+        let program_events_results = self
+            .send_program_action(
+                ProgramAction::Event(ProgramEvent::Restart),
+                &request.get_ref().program_expr,
+                WaitOptions::from_request(request.get_ref().wait, request.get_ref().timeout_secs),
+                &request,
+            )
+            .await?;
+        Ok(tonic::Response::new(
+            proto_restart_response_from_program_events_results(&program_events_results),
+        ))
+    }
+
+    async fn reload(
+        &self,
+        request: tonic::Request<ChaydServiceReloadRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceReloadResponse>, tonic::Status> {
+        log::info!("Received Reload request: {:?}", request.get_ref());
+        let program_events_results = self
+            .send_program_action(
+                ProgramAction::Event(ProgramEvent::Reload),
+                &request.get_ref().program_expr,
+                None,
+                &request,
+            )
+            .await?;
+        Ok(tonic::Response::new(
+            proto_reload_response_from_program_events_results(&program_events_results),
+        ))
+    }
+
+    async fn signal(
+        &self,
+        request: tonic::Request<ChaydServiceSignalRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceSignalResponse>, tonic::Status> {
+        log::info!("Received Signal request: {:?}", request.get_ref());
+        let signal = crate::program::parse_signal(&request.get_ref().signal)
+            .map_err(tonic::Status::invalid_argument)?;
+        let target = signal_target_from_proto(request.get_ref().target());
+        let program_events_results = self
+            .send_program_action(
+                ProgramAction::Signal { signal, target },
+                &request.get_ref().program_expr,
+                None,
+                &request,
+            )
+            .await?;
+        Ok(tonic::Response::new(
+            proto_signal_response_from_program_events_results(&program_events_results),
+        ))
+    }
+
+    async fn watch_events(
+        &self,
+        request: tonic::Request<ChaydServiceWatchEventsRequest>,
+    ) -> tonic::Result<tonic::Response<Self::WatchEventsStream>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        let remote_addr = request.remote_addr();
+        log::info!("WatchEvents client connected from {:?}", &remote_addr);
+        let program_selector: ProgramSelector = request
+            .into_inner()
+            .program_expr
+            .parse()
+            .map_err(tonic::Status::invalid_argument)?;
+        let mut program_transitions_rx = self.senders.program_transitions.subscribe();
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(20);
+        tokio::spawn(async move {
+            loop {
+                let program_transition = match program_transitions_rx.recv().await {
+                    Ok(program_transition) => program_transition,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(num_skipped)) => {
+                        log::warn!(
+                            "WatchEvents client {:?} lagged behind, skipped {} events",
+                            &remote_addr,
+                            num_skipped
+                        );
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let target = SelectorTarget::from_program_transition(&program_transition);
+                if !program_selector.matches(&target)
+                    || !permissions.is_allowed(crate::auth::STATUS_ACTION, &target)
+                {
+                    continue;
+                }
+                let response = ChaydServiceWatchEventsResponse {
+                    program_transition: Some(proto_from_program_transition(&program_transition)),
+                };
+                match stream_tx.send(tonic::Result::Ok(response)).await {
+                    // response was successfully queued to be send to client.
+                    Ok(_) => {}
+                    // output_stream was build from rx and both are dropped
+                    Err(_) => {
+                        break;
+                    }
+                }
+            }
+            log::info!("WatchEvents client disconnected from {:?}", &remote_addr);
+        });
+
+        let response_stream = tokio_stream::wrappers::ReceiverStream::new(stream_rx);
+        Ok(tonic::Response::new(
+            Box::pin(response_stream) as Self::WatchEventsStream
+        ))
+    }
+
+    async fn add_program(
+        &self,
+        request: tonic::Request<ChaydServiceAddProgramRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceAddProgramResponse>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        let program_config = parse_program_config(&request.get_ref().program_config)?;
+        let change = ProgramChange::Add(program_config);
+        log::info!("Received AddProgram request: {:?}", request.get_ref());
+        self.send_program_change(&request.get_ref().name, change, permissions)
+            .await?;
+        Ok(tonic::Response::new(ChaydServiceAddProgramResponse {}))
+    }
+
+    async fn update_program(
+        &self,
+        request: tonic::Request<ChaydServiceUpdateProgramRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceUpdateProgramResponse>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        let program_config = parse_program_config(&request.get_ref().program_config)?;
+        let change = ProgramChange::Update(program_config);
+        log::info!("Received UpdateProgram request: {:?}", request.get_ref());
+        self.send_program_change(&request.get_ref().name, change, permissions)
+            .await?;
+        Ok(tonic::Response::new(ChaydServiceUpdateProgramResponse {}))
+    }
+
+    async fn remove_program(
+        &self,
+        request: tonic::Request<ChaydServiceRemoveProgramRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceRemoveProgramResponse>, tonic::Status> {
+        log::info!("Received RemoveProgram request: {:?}", request.get_ref());
+        let permissions = self.authenticate(&request)?;
+        self.send_program_change(&request.get_ref().name, ProgramChange::Remove, permissions)
+            .await?;
+        Ok(tonic::Response::new(ChaydServiceRemoveProgramResponse {}))
+    }
+
+    async fn reload_config(
+        &self,
+        request: tonic::Request<ChaydServiceReloadConfigRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceReloadConfigResponse>, tonic::Status> {
+        log::info!("Received ReloadConfig request: {:?}", request.get_ref());
+        let permissions = self.authenticate(&request)?;
+        if !permissions.is_allowed_for_all_programs(crate::auth::RELOAD_CONFIG_ACTION) {
+            return Err(tonic::Status::permission_denied(
+                permissions.denied_message(crate::auth::RELOAD_CONFIG_ACTION),
+            ));
+        }
+        let (config_reload_results_tx, mut config_reload_results_rx) =
             tokio::sync::mpsc::channel(1);
         match self
-            .program_events_sender
-            .send((
-                ProgramEvent::Start,
-                request.get_ref().program_expr.clone(),
-                program_events_results_tx,
-            ))
+            .senders
+            .config_reloads
+            .send(ConfigReloadRequest {
+                results_sender: config_reload_results_tx,
+            })
             .await
         {
             Ok(_) => {}
             Err(_) => {
-                bug_panic("Could not send to start channel");
+                bug_panic("Could not send to config reloads channel");
             }
         }
-        match program_events_results_rx.recv().await {
-            Some(result) => match result {
-                Ok(program_events_results) => Ok(tonic::Response::new(
-                    proto_start_response_from_program_events_results(&program_events_results),
-                )),
-                Err(err) => Err(err),
-            },
+        let config_changes = match config_reload_results_rx.recv().await {
+            Some(result) => result?,
             None => {
-                bug_panic("Received None from start channel rx");
+                bug_panic("Received None from config reloads channel rx");
                 // Unreachable
-                Err(tonic::Status::unknown(
-                    "Received None from start channel rx",
-                ))
+                return Err(tonic::Status::unknown(
+                    "Received None from config reloads channel rx",
+                ));
+            }
+        };
+        Ok(tonic::Response::new(
+            proto_reload_config_response_from_config_changes(&config_changes),
+        ))
+    }
+
+    async fn attach(
+        &self,
+        request: tonic::Request<tonic::Streaming<ChaydServiceAttachRequest>>,
+    ) -> tonic::Result<tonic::Response<Self::AttachStream>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        let remote_addr = request.remote_addr();
+        let mut input_stream = request.into_inner();
+        let first_request = input_stream.message().await?.ok_or_else(|| {
+            tonic::Status::invalid_argument("Expected the program name in the first message")
+        })?;
+        let program_name = first_request.name.clone();
+        log::info!(
+            "Attach client connected from {:?} to {}",
+            &remote_addr,
+            program_name
+        );
+        let (attach_results_tx, mut attach_results_rx) = tokio::sync::mpsc::channel(1);
+        match self
+            .senders
+            .attach_requests
+            .send(AttachRequest {
+                program_name: program_name.clone(),
+                permissions,
+                results_sender: attach_results_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to attach requests channel");
+            }
+        }
+        let console = match attach_results_rx.recv().await {
+            Some(result) => result?,
+            None => {
+                bug_panic("Received None from attach requests channel rx");
+                // Unreachable
+                return Err(tonic::Status::unknown(
+                    "Received None from attach requests channel rx",
+                ));
+            }
+        };
+
+        let mut output_rx = console.subscribe();
+        let input_console = console.clone();
+        let input_program_name = program_name.clone();
+        tokio::spawn(async move {
+            let mut request = first_request;
+            loop {
+                if let Some(window_size) = &request.window_size {
+                    // Fails for programs without a pty, which ignore window sizes.
+                    if let Err(error) = input_console.resize(
+                        window_size.rows.try_into().unwrap_or(u16::MAX),
+                        window_size.cols.try_into().unwrap_or(u16::MAX),
+                    ) {
+                        log::debug!(
+                            "Could not resize the pty of {}: {}",
+                            input_program_name,
+                            error
+                        );
+                    }
+                }
+                let input = request.input;
+                if !input.is_empty() {
+                    let console = input_console.clone();
+                    // Writing blocks until the program reads its stdin.
+                    let result =
+                        tokio::task::spawn_blocking(move || console.write_stdin(&input)).await;
+                    if let Ok(Err(error)) = result {
+                        log::warn!(
+                            "Could not write to stdin of {}: {}",
+                            input_program_name,
+                            error
+                        );
+                    }
+                }
+                request = match input_stream.message().await {
+                    Ok(Some(request)) => request,
+                    // The client closed the stream or the connection.
+                    Ok(None) | Err(_) => break,
+                };
+            }
+        });
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(20);
+        tokio::spawn(async move {
+            loop {
+                let ConsoleOutput { stream, data } = match output_rx.recv().await {
+                    Ok(console_output) => console_output,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(num_skipped)) => {
+                        log::warn!(
+                            "Attach client {:?} lagged behind, skipped {} output chunks",
+                            &remote_addr,
+                            num_skipped
+                        );
+                        continue;
+                    }
+                    // The program was removed or replaced.
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let output = match stream {
+                    ConsoleStream::Stdout => {
+                        chay_proto::chayd_service_attach_response::Output::Stdout(data)
+                    }
+                    ConsoleStream::Stderr => {
+                        chay_proto::chayd_service_attach_response::Output::Stderr(data)
+                    }
+                };
+                let response = ChaydServiceAttachResponse {
+                    output: Some(output),
+                };
+                match stream_tx.send(tonic::Result::Ok(response)).await {
+                    // response was successfully queued to be send to client.
+                    Ok(_) => {}
+                    // output_stream was build from rx and both are dropped
+                    Err(_) => {
+                        break;
+                    }
+                }
+            }
+            log::info!(
+                "Attach client disconnected from {:?} from {}",
+                &remote_addr,
+                program_name
+            );
+        });
+
+        let response_stream = tokio_stream::wrappers::ReceiverStream::new(stream_rx);
+        Ok(tonic::Response::new(
+            Box::pin(response_stream) as Self::AttachStream
+        ))
+    }
+
+    async fn exec(
+        &self,
+        request: tonic::Request<ChaydServiceExecRequest>,
+    ) -> tonic::Result<tonic::Response<Self::ExecStream>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        log::info!("Received Exec request: {:?}", request.get_ref());
+        let ChaydServiceExecRequest { name, args } = request.into_inner();
+        let (exec_results_tx, mut exec_results_rx) = tokio::sync::mpsc::channel(1);
+        match self
+            .senders
+            .exec_requests
+            .send(ExecRequest {
+                program_name: name.clone(),
+                args,
+                permissions,
+                results_sender: exec_results_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to exec requests channel");
             }
         }
+        let exec_command = match exec_results_rx.recv().await {
+            Some(result) => result?,
+            None => {
+                bug_panic("Received None from exec requests channel rx");
+                // Unreachable
+                return Err(tonic::Status::unknown(
+                    "Received None from exec requests channel rx",
+                ));
+            }
+        };
+        let stream_rx = crate::program_exec::spawn_exec(&name, exec_command)?;
+        let response_stream = tokio_stream::wrappers::ReceiverStream::new(stream_rx);
+        Ok(tonic::Response::new(
+            Box::pin(response_stream) as Self::ExecStream
+        ))
     }
 
-    async fn stop(
+    async fn run_job(
         &self,
-        request: tonic::Request<ChaydServiceStopRequest>,
-    ) -> tonic::Result<tonic::Response<ChaydServiceStopResponse>, tonic::Status> {
-        log::info!("Received Stop request: {:?}", request.get_ref());
-        let (program_events_results_tx, mut program_events_results_rx) =
-            tokio::sync::mpsc::channel(1);
+        request: tonic::Request<ChaydServiceRunJobRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceRunJobResponse>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        log::info!("Received RunJob request: {:?}", request.get_ref());
+        let source = request_source(&request, &permissions);
+        let ChaydServiceRunJobRequest {
+            args,
+            logger,
+            timeout_secs,
+        } = request.into_inner();
+        let (run_job_results_tx, mut run_job_results_rx) = tokio::sync::mpsc::channel(1);
         match self
-            .program_events_sender
-            .send((
-                ProgramEvent::Stop,
-                request.get_ref().program_expr.clone(),
-                program_events_results_tx,
-            ))
+            .senders
+            .run_job_requests
+            .send(RunJobRequest {
+                args,
+                logger,
+                timeout: timeout_secs
+                    .map(|timeout_secs| std::time::Duration::from_secs(timeout_secs as u64)),
+                permissions,
+                source,
+                results_sender: run_job_results_tx,
+            })
             .await
         {
             Ok(_) => {}
             Err(_) => {
-                bug_panic("Could not send to stop channel");
+                bug_panic("Could not send to run job requests channel");
             }
         }
-        match program_events_results_rx.recv().await {
-            Some(result) => match result {
-                Ok(program_events_results) => Ok(tonic::Response::new(
-                    proto_stop_response_from_program_events_results(&program_events_results),
-                )),
-                Err(err) => Err(err),
-            },
+        match run_job_results_rx.recv().await {
+            Some(result) => Ok(tonic::Response::new(ChaydServiceRunJobResponse {
+                id: result?,
+            })),
             None => {
-                bug_panic("Received None from stop channel rx");
+                bug_panic("Received None from run job requests channel rx");
                 // Unreachable
-                Err(tonic::Status::unknown("Received None from stop channel rx"))
+                Err(tonic::Status::unknown(
+                    "Received None from run job requests channel rx",
+                ))
             }
         }
     }
 
-    async fn restart(
+    async fn get_job(
         &self,
-        request: tonic::Request<ChaydServiceRestartRequest>,
-    ) -> tonic::Result<tonic::Response<ChaydServiceRestartResponse>, tonic::Status> {
-        log::info!("Received Restart request: {:?}", request.get_ref());
-        let (program_events_results_tx, mut program_events_results_rx) =
-            tokio::sync::mpsc::channel(1);
+        request: tonic::Request<ChaydServiceGetJobRequest>,
+    ) -> tonic::Result<tonic::Response<ChaydServiceGetJobResponse>, tonic::Status> {
+        let permissions = self.authenticate(&request)?;
+        let (get_job_results_tx, mut get_job_results_rx) = tokio::sync::mpsc::channel(1);
         match self
-            .program_events_sender
-            .send((
-                ProgramEvent::Restart,
-                request.get_ref().program_expr.clone(),
-                program_events_results_tx,
-            ))
+            .senders
+            .get_job_requests
+            .send(GetJobRequest {
+                job_id: request.into_inner().id,
+                permissions,
+                results_sender: get_job_results_tx,
+            })
             .await
         {
             Ok(_) => {}
             Err(_) => {
-                bug_panic("Could not send to restart channel");
+                bug_panic("Could not send to get job requests channel");
             }
         }
-        match program_events_results_rx.recv().await {
-            Some(result) => match result {
-                Ok(program_events_results) => Ok(tonic::Response::new(
-                    proto_restart_response_from_program_events_results(&program_events_results),
-                )),
-                Err(err) => Err(err),
-            },
+        match get_job_results_rx.recv().await {
+            Some(result) => Ok(tonic::Response::new(ChaydServiceGetJobResponse {
+                job: Some(result?),
+            })),
             None => {
-                bug_panic("Received None from restart channel rx");
+                bug_panic("Received None from get job requests channel rx");
                 // Unreachable
                 Err(tonic::Status::unknown(
-                    "Received None from restart channel rx",
+                    "Received None from get job requests channel rx",
                 ))
             }
         }
     }
+
+    async fn tail(
+        &self,
+        request: tonic::Request<ChaydServiceTailRequest>,
+    ) -> tonic::Result<tonic::Response<Self::TailStream>, tonic::Status> {
+        log::info!("Received Tail request: {:?}", request.get_ref());
+        let permissions = self.authenticate(&request)?;
+        let remote_addr = request.remote_addr();
+        let ChaydServiceTailRequest {
+            name,
+            num_lines,
+            follow,
+        } = request.into_inner();
+        let (tail_results_tx, mut tail_results_rx) = tokio::sync::mpsc::channel(1);
+        match self
+            .senders
+            .tail_requests
+            .send(TailRequest {
+                program_name: name.clone(),
+                permissions,
+                results_sender: tail_results_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to tail requests channel");
+            }
+        }
+        let console = match tail_results_rx.recv().await {
+            Some(result) => result?,
+            None => {
+                bug_panic("Received None from tail requests channel rx");
+                // Unreachable
+                return Err(tonic::Status::unknown(
+                    "Received None from tail requests channel rx",
+                ));
+            }
+        };
+
+        let (output, mut output_rx) = console.tail(num_lines as usize);
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(20);
+        tokio::spawn(async move {
+            if !output.is_empty()
+                && stream_tx
+                    .send(tonic::Result::Ok(ChaydServiceTailResponse { output }))
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+            if !follow {
+                return;
+            }
+            loop {
+                let ConsoleOutput { data, .. } = match output_rx.recv().await {
+                    Ok(console_output) => console_output,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(num_skipped)) => {
+                        log::warn!(
+                            "Tail client {:?} lagged behind, skipped {} output chunks",
+                            &remote_addr,
+                            num_skipped
+                        );
+                        continue;
+                    }
+                    // The program was removed or replaced.
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let response = ChaydServiceTailResponse { output: data };
+                // Fails once the client closed the stream.
+                if stream_tx.send(tonic::Result::Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+            log::info!(
+                "Tail client disconnected from {:?} from {}",
+                &remote_addr,
+                name
+            );
+        });
+
+        let response_stream = tokio_stream::wrappers::ReceiverStream::new(stream_rx);
+        Ok(tonic::Response::new(
+            Box::pin(response_stream) as Self::TailStream
+        ))
+    }
 }
@@ -10,6 +10,29 @@ pub fn read_from_file(
     Ok(config)
 }
 
+/// Describes an error from rendering the config. Rendering errors from tera only have a helpful
+/// message in their source, e.g. the name of an undefined variable.
+pub fn render_error_message(error: &dyn std::error::Error) -> String {
+    match error.source() {
+        Some(source) => source.to_string(),
+        None => error.to_string(),
+    }
+}
+
+/// Reads and renders the config file when reloading the config. The [chayd] section is only read
+/// when chayd starts, so the running one is kept, e.g. the cgroup_parent that was prepared. The
+/// [auth] section is read again, and the caller replaces chayd's authenticator with it, so that
+/// e.g. a revoked token is denied right away.
+pub fn read_and_render(
+    config_path: &std::path::PathBuf,
+    chayd_config: &ChaydConfig,
+) -> Result<(Config, BTreeMap<String, RenderedProgramConfig>), Box<dyn std::error::Error>> {
+    let mut config = read_from_file(config_path)?;
+    config.chayd = chayd_config.clone();
+    let rendered_config = render(&config)?;
+    Ok((config, rendered_config))
+}
+
 pub fn render(
     config: &Config,
 ) -> Result<BTreeMap<String, RenderedProgramConfig>, Box<dyn std::error::Error>> {
@@ -23,9 +46,48 @@ pub fn render(
     Ok(rendered_config)
 }
 
+/// Renders the args of a one-off command run in a program's context, e.g. by `chay exec`, with
+/// the same vars as the config plus `chayd.ctx.program`.
+pub fn render_exec_args(
+    config: &Config,
+    program_name: &str,
+    args: &[String],
+) -> Result<Vec<String>, tera::Error> {
+    let mut vars_renderer = VarsRenderer::new(&config.vars)?;
+    vars_renderer.add_ctx_vars(&HashMap::from([(
+        "program".to_string(),
+        tera::to_value(program_name).unwrap(),
+    )]));
+    args.iter()
+        .map(|arg| vars_renderer.render_str(arg))
+        .collect()
+}
+
 pub type VarsConfig = HashMap<String, HashMap<String, String>>;
 
-#[derive(Clone, Debug, Default, serde::Deserialize)]
+/// Resource limits of a process, keyed by the name of the limit, e.g. `nofile = 65536`.
+pub type RlimitsConfig = BTreeMap<String, RlimitConfig>;
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RlimitValue {
+    Value(u64),
+    /// Only "unlimited" (or "infinity") is valid, which is checked when the config is rendered.
+    Keyword(String),
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RlimitConfig {
+    /// Sets both the soft and the hard limit.
+    Limit(RlimitValue),
+    SoftHard {
+        soft: RlimitValue,
+        hard: RlimitValue,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PreCommandConfig {
     pub command: String,
@@ -33,9 +95,11 @@ pub struct PreCommandConfig {
 
     #[serde(default = "default_pre_command_timeout_secs")]
     pub timeout_secs: u32,
+    #[serde(default)]
+    pub rlimits: RlimitsConfig,
 }
 
-#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct LoggerConfig {
     pub command: String,
@@ -43,9 +107,54 @@ pub struct LoggerConfig {
     pub pre_command: Option<PreCommandConfig>,
     #[serde(default = "default_start_wait_secs")]
     pub start_wait_secs: u32,
+    #[serde(default)]
+    pub rlimits: RlimitsConfig,
+}
+
+/// What the watchdog does when a limit is exceeded.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Restart the program, e.g. to recycle a worker that leaks memory.
+    #[default]
+    Restart,
+    /// Only send an event to WatchEvents clients, without affecting the program.
+    Event,
+}
+
+/// Limits on the resource usage of a program's process and its descendants, checked on every
+/// resource usage sample while the program is running.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    pub max_rss_bytes: Option<u64>,
+    /// Percent of one CPU, e.g. 200 for two busy CPUs.
+    pub max_cpu_percent: Option<f64>,
+    pub max_open_fds: Option<u32>,
+    /// Number of consecutive samples a limit must be exceeded for. Samples are taken on every FSM
+    /// update, i.e. every 500 ms, so e.g. 10 only triggers on CPU usage sustained for 5 seconds.
+    #[serde(default = "default_watchdog_num_samples")]
+    pub num_samples: u32,
+    #[serde(default)]
+    pub action: WatchdogAction,
+}
+
+/// Limits of a program's cgroup, written verbatim to the cgroup's interface files. Only used if
+/// chayd.cgroup_parent is set.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupConfig {
+    /// Written to memory.max, e.g. "512M" or "max".
+    pub memory_max: Option<String>,
+    /// Written to cpu.max as "$MAX $PERIOD" in microseconds, e.g. "50000 100000" for half a CPU.
+    pub cpu_max: Option<String>,
+    /// Written to pids.max, e.g. "100" or "max".
+    pub pids_max: Option<String>,
+    /// Written to io.weight, from 1 to 10000.
+    pub io_weight: Option<u16>,
 }
 
-#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ProgramConfig {
     pub command: String,
@@ -69,21 +178,160 @@ pub struct ProgramConfig {
     /// Seconds to wait after a program exits unexpectedly before attempted to restart the program.
     #[serde(default = "default_sigkill_delay_secs")]
     pub sigkill_delay_secs: u32,
+
+    /// Signal to send to the program on reload, e.g. "HUP". Mutually exclusive with
+    /// reload_command. The program is restarted on reload if neither is set.
+    pub reload_signal: Option<String>,
+    /// Command to run on reload, e.g. `nginx -s reload`. Mutually exclusive with reload_signal.
+    pub reload_command: Option<PreCommandConfig>,
+
+    /// Human readable description of the program, shown in its status.
+    pub description: Option<String>,
+    /// Free-form labels to select programs by, e.g. `label:tier=web` in program expressions.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Groups the program belongs to, selected with `group:<name>` in program expressions.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Let clients attach to the program with `chay attach`. chayd then owns the program's stdin,
+    /// stdout and stderr, and forwards the output to the logger.
+    #[serde(default)]
+    pub attachable: bool,
+    /// Let clients follow the program's output with `chay tail`, which attachable programs
+    /// always allow. chayd then owns the program's stdout and stderr, and forwards them to the
+    /// logger.
+    #[serde(default)]
+    pub tailable: bool,
+    /// Run the program in a pseudo-terminal, for programs that buffer their output or behave
+    /// differently without one. Its stdout and stderr are merged into the logger's input.
+    #[serde(default)]
+    pub pty: bool,
+    #[serde(default = "default_pty_rows")]
+    pub pty_rows: u16,
+    #[serde(default = "default_pty_cols")]
+    pub pty_cols: u16,
+    /// Restarts the program, or sends an event, when it uses too many resources.
+    pub watchdog: Option<WatchdogConfig>,
+    /// Resource limits set in the program's process, e.g. `{ nofile = 65536 }`. Either a number or
+    /// "unlimited" for both the soft and hard limit, or `{ soft = 1024, hard = 65536 }`.
+    #[serde(default)]
+    pub rlimits: RlimitsConfig,
+    /// Limits of the program's cgroup. Its logger's cgroup has no limits.
+    pub cgroup: Option<CgroupConfig>,
+    /// Environment variables set for the program on top of chayd's own. The values are rendered
+    /// with the vars.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Working directory of the program, rendered with the vars. chayd's own if unset.
+    pub directory: Option<String>,
+    /// Name of the user to run the program as, with the user's groups. Requires chayd to run as
+    /// root.
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChaydConfig {
+    /// Address to serve the gRPC API on. Either a TCP socket address or a Unix domain socket path
+    /// prefixed with "unix:", e.g. "unix:/run/chayd.sock".
+    #[serde(default = "default_chayd_addr")]
+    pub addr: String,
+    /// User name to own the Unix domain socket file. Ignored for TCP addresses.
+    pub socket_owner: Option<String>,
+    /// Group name to own the Unix domain socket file. Ignored for TCP addresses.
+    pub socket_group: Option<String>,
+    /// Octal permissions of the Unix domain socket file, e.g. "0660". Ignored for TCP addresses.
+    pub socket_mode: Option<String>,
+
+    /// Path to the PEM encoded server certificate. Enables TLS when set with tls_key.
+    pub tls_cert: Option<std::path::PathBuf>,
+    /// Path to the PEM encoded private key of tls_cert.
+    pub tls_key: Option<std::path::PathBuf>,
+    /// Path to the PEM encoded CA certificate used to verify client certificates. Enables mutual
+    /// TLS, i.e. clients without a certificate signed by this CA are rejected.
+    pub tls_client_ca: Option<std::path::PathBuf>,
+    /// Allow adding and updating programs, exec and running jobs without an [auth] config. These
+    /// run commands chosen by the client, so are denied unless authenticated by default.
+    #[serde(default)]
+    pub allow_unauthenticated_commands: bool,
+
+    /// Socket address to serve Prometheus metrics on at /metrics over plain HTTP, e.g.
+    /// "127.0.0.1:9100". Not served if unset.
+    pub metrics_addr: Option<String>,
+
+    /// Path of a cgroup v2 directory to put each program and logger into its own cgroup under,
+    /// e.g. "/sys/fs/cgroup/chayd". It must be writable by chayd and contain no processes, since
+    /// controllers are only enabled for the children of cgroups without processes. Programs
+    /// aren't put into cgroups if unset.
+    pub cgroup_parent: Option<std::path::PathBuf>,
+}
+
+impl Default for ChaydConfig {
+    fn default() -> Self {
+        Self {
+            addr: default_chayd_addr(),
+            socket_owner: None,
+            socket_group: None,
+            socket_mode: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            allow_unauthenticated_commands: false,
+            metrics_addr: None,
+            cgroup_parent: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRuleConfig {
+    /// Identity the rule applies to. Either the name of a token, "user:<name>" for clients
+    /// connected over a Unix domain socket as the given user, or "*" for any identity.
+    pub identity: String,
+    /// Actions the identity is allowed to perform, e.g. ["start", "restart"], or ["*"] for all.
+    pub actions: Vec<String>,
+    /// Program expression the actions are allowed on. State terms only match programs that
+    /// exist, so they never allow adding a program.
+    #[serde(default = "default_auth_rule_programs")]
+    pub programs: String,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Bearer tokens accepted by chayd. The key is the identity name used in the rules.
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+    /// Authenticate clients connected over a Unix domain socket by their peer credentials.
+    #[serde(default = "default_auth_unix_peer_credentials")]
+    pub unix_peer_credentials: bool,
+    /// Everything that isn't explicitly allowed by a rule is denied.
+    #[serde(default)]
+    pub rules: Vec<AuthRuleConfig>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
+    pub chayd: ChaydConfig,
+    /// Authentication and authorization of RPCs. Every client is allowed to do anything except
+    /// run commands of its choosing if unset, see chayd.allow_unauthenticated_commands.
+    pub auth: Option<AuthConfig>,
     pub vars: VarsConfig,
     /// List of programs from the config file, sorted by key in alphabetical order.
     pub programs: BTreeMap<String, ProgramConfig>,
     pub loggers: BTreeMap<String, LoggerConfig>,
 }
 
-#[derive(Clone, Debug, Default)]
+/// Compared with the running programs' configs to find what a config reload changed.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct RenderedProgramConfig {
     pub program: ProgramConfig,
     pub logger: Option<LoggerConfig>,
+    /// From chayd.cgroup_parent.
+    pub cgroup_parent: Option<std::path::PathBuf>,
 }
 
 impl RenderedProgramConfig {
@@ -106,6 +354,14 @@ impl RenderedProgramConfig {
     pub fn sigkill_delay_secs(&self) -> u32 {
         self.program.sigkill_delay_secs
     }
+
+    /// Panics if the reload_signal is invalid, which is checked when the config is rendered.
+    pub fn reload_signal(&self) -> Option<nix::sys::signal::Signal> {
+        self.program
+            .reload_signal
+            .as_ref()
+            .map(|reload_signal| crate::program::parse_signal(reload_signal).unwrap())
+    }
 }
 
 impl RenderedProgramConfig {
@@ -114,7 +370,44 @@ impl RenderedProgramConfig {
         program_name: &str,
         program_config: &ProgramConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut rendered_config = Self::default();
+        if let Some(reload_signal) = &program_config.reload_signal {
+            if program_config.reload_command.is_some() {
+                return Err(format!(
+                    "{program_name}: reload_signal and reload_command are mutually exclusive"
+                )
+                .into());
+            }
+            crate::program::parse_signal(reload_signal)
+                .map_err(|error| format!("{program_name}: {error}"))?;
+        }
+        if config.chayd.cgroup_parent.is_some() {
+            // The program name is the name of its cgroup's directory.
+            if program_name.contains('/') || program_name == "." || program_name == ".." {
+                return Err(format!("{program_name}: Invalid program name for a cgroup").into());
+            }
+        } else if program_config.cgroup.is_some() {
+            return Err(format!("{program_name}: cgroup requires chayd.cgroup_parent").into());
+        }
+        if let Some(io_weight) = program_config
+            .cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.io_weight)
+        {
+            if !(1..=10000).contains(&io_weight) {
+                return Err(format!("{program_name}: io_weight must be from 1 to 10000").into());
+            }
+        }
+        if let Some(watchdog) = &program_config.watchdog {
+            if watchdog.num_samples == 0 {
+                return Err(
+                    format!("{program_name}: watchdog num_samples must be at least 1").into(),
+                );
+            }
+        }
+        let mut rendered_config = Self {
+            cgroup_parent: config.chayd.cgroup_parent.clone(),
+            ..Self::default()
+        };
         let mut vars_renderer = VarsRenderer::new(&config.vars)?;
         rendered_config.program = Self::render_program(program_config, &mut vars_renderer)?;
         if let Some(logger_name) = &program_config.logger {
@@ -128,9 +421,41 @@ impl RenderedProgramConfig {
                 return Err(format!("Logger not found: {logger_name}").into());
             }
         }
+        rendered_config
+            .validate_rlimits()
+            .map_err(|error| format!("{program_name}: {error}"))?;
+        if let Some(user) = &rendered_config.program.user {
+            if nix::unistd::User::from_name(user)?.is_none() {
+                return Err(format!("{program_name}: User not found: {user}").into());
+            }
+        }
         Ok(rendered_config)
     }
 
+    /// Checks the rlimits of the program and all of its sidecar programs.
+    fn validate_rlimits(&self) -> Result<(), String> {
+        let mut rlimits_configs = vec![&self.program.rlimits];
+        rlimits_configs.extend(
+            [&self.program.pre_command, &self.program.reload_command]
+                .into_iter()
+                .flatten()
+                .map(|pre_command| &pre_command.rlimits),
+        );
+        if let Some(logger) = &self.logger {
+            rlimits_configs.push(&logger.rlimits);
+            rlimits_configs.extend(
+                logger
+                    .pre_command
+                    .iter()
+                    .map(|pre_command| &pre_command.rlimits),
+            );
+        }
+        for rlimits_config in rlimits_configs {
+            crate::program_rlimits::parse_rlimits(rlimits_config)?;
+        }
+        Ok(())
+    }
+
     fn render_program(
         program_config: &ProgramConfig,
         vars_renderer: &mut VarsRenderer,
@@ -142,6 +467,15 @@ impl RenderedProgramConfig {
                 *arg = vars_renderer.render_str(&arg)?;
             }
         }
+        for value in rendered_program_config.env.values_mut() {
+            *value = vars_renderer.render_str(value)?;
+        }
+        if let Some(directory) = &mut rendered_program_config.directory {
+            *directory = vars_renderer.render_str(directory)?;
+        }
+        if let Some(user) = &mut rendered_program_config.user {
+            *user = vars_renderer.render_str(user)?;
+        }
         if let Some(pre_command) = &mut rendered_program_config.pre_command {
             pre_command.command = vars_renderer.render_str(&pre_command.command)?;
             if let Some(args) = &mut pre_command.args {
@@ -150,6 +484,14 @@ impl RenderedProgramConfig {
                 }
             }
         }
+        if let Some(reload_command) = &mut rendered_program_config.reload_command {
+            reload_command.command = vars_renderer.render_str(&reload_command.command)?;
+            if let Some(args) = &mut reload_command.args {
+                for arg in args {
+                    *arg = vars_renderer.render_str(arg)?;
+                }
+            }
+        }
         Ok(rendered_program_config)
     }
 
@@ -248,6 +590,18 @@ impl VarsRenderer {
     }
 }
 
+fn default_chayd_addr() -> String {
+    chay::addr::DEFAULT_CHAYD_ADDR.to_string()
+}
+
+fn default_auth_rule_programs() -> String {
+    "all".to_string()
+}
+
+fn default_auth_unix_peer_credentials() -> bool {
+    true
+}
+
 fn default_pre_command_timeout_secs() -> u32 {
     1u32
 }
@@ -272,7 +626,19 @@ fn default_num_restart_attempts() -> u32 {
     4u32
 }
 
-fn default_sigkill_delay_secs() -> u32 {
+pub fn default_sigkill_delay_secs() -> u32 {
+    10u32
+}
+
+fn default_pty_rows() -> u16 {
+    24u16
+}
+
+fn default_pty_cols() -> u16 {
+    80u16
+}
+
+fn default_watchdog_num_samples() -> u32 {
     10u32
 }
 
@@ -294,6 +660,24 @@ impl AsRef<ProgramConfig> for ProgramConfig {
     }
 }
 
+impl AsRef<ChaydConfig> for ChaydConfig {
+    fn as_ref(&self) -> &ChaydConfig {
+        self
+    }
+}
+
+impl AsRef<AuthRuleConfig> for AuthRuleConfig {
+    fn as_ref(&self) -> &AuthRuleConfig {
+        self
+    }
+}
+
+impl AsRef<AuthConfig> for AuthConfig {
+    fn as_ref(&self) -> &AuthConfig {
+        self
+    }
+}
+
 impl AsRef<Config> for Config {
     fn as_ref(&self) -> &Config {
         &self
@@ -0,0 +1,379 @@
+use crate::auth::Permissions;
+use crate::chay_proto;
+use crate::config::{ProgramConfig, RenderedProgramConfig};
+use crate::program_console::ProgramConsole;
+use crate::program_fsm::{
+    new_program_fsm, ProgramEvent, ProgramFsm, ProgramState, ProgramTransition,
+};
+use crate::program_selector::SelectorTarget;
+use crate::request_error::RequestError;
+use std::collections::VecDeque;
+
+/// Group every job belongs to, so jobs can be selected with `group:jobs`.
+pub const JOBS_GROUP: &str = "jobs";
+const JOB_ID_PREFIX: &str = "job-";
+/// Only the end of a job's output is kept, which is usually where the errors are.
+const MAX_CAPTURED_OUTPUT_BYTES: usize = 1024 * 1024;
+/// The oldest finished jobs are forgotten beyond this many.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// The ID of the new job.
+pub type RunJobResult = Result<String, RequestError>;
+
+pub struct RunJobRequest {
+    pub args: Vec<String>,
+    pub logger: Option<String>,
+    pub timeout: Option<std::time::Duration>,
+    pub permissions: Permissions,
+    /// Describes the client that sent the request, e.g. "deploy (127.0.0.1:41234)".
+    pub source: String,
+    pub results_sender: tokio::sync::mpsc::Sender<RunJobResult>,
+}
+
+pub type GetJobResult = Result<chay_proto::Job, RequestError>;
+
+pub struct GetJobRequest {
+    pub job_id: String,
+    pub permissions: Permissions,
+    pub results_sender: tokio::sync::mpsc::Sender<GetJobResult>,
+}
+
+struct RunningJob {
+    id: String,
+    program_fsm: ProgramFsm,
+    console: ProgramConsole,
+    deadline: Option<std::time::Instant>,
+    timed_out: bool,
+}
+
+/// What's left of a job once its process exited.
+struct FinishedJob {
+    id: String,
+    args: Vec<String>,
+    state: ProgramState,
+    /// Kept rather than its output, since the output may still be read for a moment after the
+    /// process exited.
+    console: ProgramConsole,
+    start_time: Option<std::time::SystemTime>,
+    end_time: std::time::SystemTime,
+    exit: Option<crate::program::ProgramExit>,
+    error: Option<String>,
+    timed_out: bool,
+}
+
+impl FinishedJob {
+    fn from_running_job(running_job: RunningJob) -> Self {
+        let program_ctx = running_job.program_fsm.app_context();
+        let program = &program_ctx.program.program;
+        Self {
+            id: running_job.id.clone(),
+            args: job_args(&program_ctx.config.program),
+            state: running_job.program_fsm.current_state_key(),
+            console: running_job.console.clone(),
+            start_time: program.start_time,
+            end_time: std::time::SystemTime::now(),
+            exit: program.last_exit.clone(),
+            error: program_ctx.last_error.clone(),
+            timed_out: running_job.timed_out,
+        }
+    }
+}
+
+fn job_args(program_config: &ProgramConfig) -> Vec<String> {
+    let mut args = vec![program_config.command.clone()];
+    args.extend(program_config.args.clone().unwrap_or_default());
+    args
+}
+
+/// A program that runs once, without restarts.
+fn job_program_config(request: &RunJobRequest) -> ProgramConfig {
+    ProgramConfig {
+        command: request.args[0].clone(),
+        args: Some(request.args[1..].to_vec()),
+        logger: request.logger.clone(),
+        autostart: true,
+        autorestart: false,
+        // Counts as started once spawned, so exiting right away finishes the job rather than
+        // failing its start.
+        start_wait_secs: 0,
+        sigkill_delay_secs: crate::config::default_sigkill_delay_secs(),
+        description: Some(format!("Job run by {}", request.source)),
+        groups: vec![JOBS_GROUP.to_string()],
+        ..Default::default()
+    }
+}
+
+/// Transient programs run with RunJob. They are kept apart from the programs of the config, so
+/// config reloads and program changes don't affect them.
+pub struct Jobs {
+    program_transitions_tx: tokio::sync::broadcast::Sender<ProgramTransition>,
+    next_job_number: u64,
+    running_jobs: Vec<RunningJob>,
+    finished_jobs: VecDeque<FinishedJob>,
+}
+
+impl Jobs {
+    pub fn new(program_transitions_tx: tokio::sync::broadcast::Sender<ProgramTransition>) -> Self {
+        Self {
+            program_transitions_tx,
+            next_job_number: 1,
+            running_jobs: vec![],
+            finished_jobs: VecDeque::new(),
+        }
+    }
+
+    pub fn program_fsms(&self) -> impl Iterator<Item = &ProgramFsm> {
+        self.running_jobs
+            .iter()
+            .map(|running_job| &running_job.program_fsm)
+    }
+
+    pub fn program_fsms_mut(&mut self) -> impl Iterator<Item = &mut ProgramFsm> {
+        self.running_jobs
+            .iter_mut()
+            .map(|running_job| &mut running_job.program_fsm)
+    }
+
+    /// Job IDs look like program names, so skip the ones a program already has.
+    fn new_job_id(&mut self, program_fsms: &[ProgramFsm]) -> String {
+        loop {
+            let job_id = format!("{JOB_ID_PREFIX}{}", self.next_job_number);
+            self.next_job_number += 1;
+            if !program_fsms
+                .iter()
+                .any(|program_fsm| program_fsm.app_context().name == job_id)
+            {
+                return job_id;
+            }
+        }
+    }
+
+    /// Creates the job, which is started by the next update.
+    pub fn run(
+        &mut self,
+        config: &crate::config::Config,
+        program_fsms: &[ProgramFsm],
+        run_job_request: &RunJobRequest,
+    ) -> RunJobResult {
+        let permissions = &run_job_request.permissions;
+        // A job can run anything as chayd's user, so it's only allowed by rules for all programs.
+        if !permissions.is_allowed_for_all_programs(crate::auth::RUN_JOB_ACTION) {
+            return Err(RequestError::new(
+                tonic::Code::PermissionDenied,
+                permissions.denied_message(crate::auth::RUN_JOB_ACTION),
+            ));
+        }
+        if run_job_request.args.is_empty() {
+            return Err(RequestError::new(
+                tonic::Code::InvalidArgument,
+                "Missing command",
+            ));
+        }
+        let job_id = self.new_job_id(program_fsms);
+        let rendered_config =
+            RenderedProgramConfig::new(config, &job_id, &job_program_config(run_job_request))
+                .map_err(|error| {
+                    RequestError::new(
+                        tonic::Code::InvalidArgument,
+                        format!(
+                            "Invalid job: {}",
+                            crate::config::render_error_message(error.as_ref())
+                        ),
+                    )
+                })?;
+        let mut program_fsm = new_program_fsm(
+            job_id.clone(),
+            &rendered_config,
+            self.program_transitions_tx.clone(),
+        );
+        let console = ProgramConsole::capturing(MAX_CAPTURED_OUTPUT_BYTES);
+        program_fsm.app_context_mut().program.program.console = Some(console.clone());
+        log::info!(
+            "Running job {} for {}: {:?}",
+            job_id,
+            run_job_request.source,
+            job_args(&rendered_config.program)
+        );
+        self.running_jobs.push(RunningJob {
+            id: job_id.clone(),
+            program_fsm,
+            console,
+            deadline: run_job_request
+                .timeout
+                .map(|timeout| std::time::Instant::now() + timeout),
+            timed_out: false,
+        });
+        Ok(job_id)
+    }
+
+    /// Updates the running jobs, stops the ones past their timeout, and keeps the ones that
+    /// finished for later retrieval.
+    pub fn update(&mut self) {
+        let now = std::time::Instant::now();
+        for running_job in &mut self.running_jobs {
+            running_job.program_fsm.update();
+            let past_deadline = running_job.deadline.is_some_and(|deadline| now >= deadline);
+            if past_deadline && !running_job.timed_out {
+                log::warn!("Job {} timed out, stopping it", running_job.id);
+                running_job.timed_out = true;
+                // Fails if the job is already stopping or exited, which is fine.
+                let _ = running_job.program_fsm.react(&ProgramEvent::Stop);
+            }
+        }
+        let mut running_jobs = vec![];
+        for running_job in self.running_jobs.drain(..) {
+            match running_job.program_fsm.current_state_key() {
+                ProgramState::Exited | ProgramState::Stopped => {
+                    log::info!("Job {} finished", running_job.id);
+                    self.finished_jobs
+                        .push_back(FinishedJob::from_running_job(running_job));
+                    if self.finished_jobs.len() > MAX_FINISHED_JOBS {
+                        self.finished_jobs.pop_front();
+                    }
+                }
+                _ => running_jobs.push(running_job),
+            }
+        }
+        self.running_jobs = running_jobs;
+    }
+
+    /// Returns the job if it exists and the client is allowed to see its status.
+    pub fn get(&self, get_job_request: &GetJobRequest) -> GetJobResult {
+        let job_id = &get_job_request.job_id;
+        let mut target = SelectorTarget::from_name(job_id);
+        target.groups = vec![JOBS_GROUP.to_string()];
+        let job = if let Some(running_job) = self
+            .running_jobs
+            .iter()
+            .find(|running_job| &running_job.id == job_id)
+        {
+            target = SelectorTarget::from_program_fsm(&running_job.program_fsm);
+            let program_ctx = running_job.program_fsm.app_context();
+            let program = &program_ctx.program.program;
+            let mut job = chay_proto::Job {
+                id: job_id.clone(),
+                args: job_args(&program_ctx.config.program),
+                start_time: program.start_time.map(|start_time| start_time.into()),
+                exit: program
+                    .last_exit
+                    .as_ref()
+                    .map(crate::proto_converters::proto_from_program_exit),
+                error: program_ctx.last_error.clone().unwrap_or_default(),
+                timed_out: running_job.timed_out,
+                output: running_job.console.captured_output(),
+                ..Default::default()
+            };
+            job.set_state(crate::proto_converters::proto_from_program_state(
+                running_job.program_fsm.current_state_key(),
+            ));
+            job
+        } else if let Some(finished_job) = self
+            .finished_jobs
+            .iter()
+            .find(|finished_job| &finished_job.id == job_id)
+        {
+            let mut job = chay_proto::Job {
+                id: job_id.clone(),
+                args: finished_job.args.clone(),
+                finished: true,
+                start_time: finished_job.start_time.map(|start_time| start_time.into()),
+                end_time: Some(finished_job.end_time.into()),
+                exit: finished_job
+                    .exit
+                    .as_ref()
+                    .map(crate::proto_converters::proto_from_program_exit),
+                error: finished_job.error.clone().unwrap_or_default(),
+                timed_out: finished_job.timed_out,
+                output: finished_job.console.captured_output(),
+                ..Default::default()
+            };
+            job.set_state(crate::proto_converters::proto_from_program_state(
+                finished_job.state.clone(),
+            ));
+            job
+        } else {
+            return Err(RequestError::new(
+                tonic::Code::NotFound,
+                format!("Job not found: {job_id}"),
+            ));
+        };
+        let permissions = &get_job_request.permissions;
+        if !permissions.is_allowed(crate::auth::STATUS_ACTION, &target) {
+            return Err(RequestError::new(
+                tonic::Code::PermissionDenied,
+                permissions.denied_message(crate::auth::STATUS_ACTION),
+            ));
+        }
+        Ok(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_job(jobs: &mut Jobs, args: &[&str]) -> String {
+        let config: crate::config::Config =
+            toml::from_str("[vars]\n[programs]\n[loggers]\n").unwrap();
+        let (results_sender, _results_receiver) = tokio::sync::mpsc::channel(1);
+        let run_job_request = RunJobRequest {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            logger: None,
+            timeout: None,
+            permissions: Permissions::AllowAll {
+                allow_commands: true,
+            },
+            source: "test".to_string(),
+            results_sender,
+        };
+        jobs.run(&config, &[], &run_job_request).unwrap()
+    }
+
+    fn finished_job(jobs: &mut Jobs, job_id: &str) -> chay_proto::Job {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while jobs
+            .running_jobs
+            .iter()
+            .any(|running_job| running_job.id == job_id)
+        {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "{job_id} didn't finish"
+            );
+            jobs.update();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let (results_sender, _results_receiver) = tokio::sync::mpsc::channel(1);
+        jobs.get(&GetJobRequest {
+            job_id: job_id.to_string(),
+            permissions: Permissions::AllowAll {
+                allow_commands: false,
+            },
+            results_sender,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn jobs_that_exit_immediately_finish() {
+        let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
+        let mut jobs = Jobs::new(program_transitions_tx);
+        let job_id = run_job(&mut jobs, &["true"]);
+        let job = finished_job(&mut jobs, &job_id);
+        assert_eq!(job.state(), chay_proto::ProgramState::Exited);
+        assert_eq!(job.error, "");
+        assert_eq!(
+            job.exit.unwrap().status,
+            Some(chay_proto::program_exit::Status::Code(0))
+        );
+
+        let job_id = run_job(&mut jobs, &["sh", "-c", "exit 3"]);
+        let job = finished_job(&mut jobs, &job_id);
+        assert_eq!(job.state(), chay_proto::ProgramState::Exited);
+        assert_eq!(job.error, "");
+        assert_eq!(
+            job.exit.unwrap().status,
+            Some(chay_proto::program_exit::Status::Code(3))
+        );
+    }
+}
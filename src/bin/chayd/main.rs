@@ -1,21 +1,45 @@
 use crate::chayd_service_impl::{
-    broadcast_program_states, ChaydServiceImpl, ProgramStatesChannels,
+    broadcast_program_statuses, ChaydServiceImpl, ChaydServiceSenders, ProgramAction,
+    ProgramEventsRequest, ProgramStatusesChannels,
 };
-use crate::program_fsm::{new_program_fsm, ProgramFsm};
+use crate::jobs::Jobs;
+use crate::metrics::{render_program_metrics, MetricsEndpoint, RpcMetricsLayer};
+use crate::program_changes::{ConfigChanges, ProgramChanges};
+use crate::program_console::{program_console, tailed_program_console};
+use crate::program_exec::exec_command;
+use crate::program_fsm::{new_program_fsm, ProgramFsm, TransitionReason};
+use crate::program_selector::SelectorTarget;
+use crate::program_usage::sample_program_usages;
+use crate::program_waits::ProgramWaits;
+use crate::request_error::RequestError;
+use chay::addr::ChaydAddr;
 use chay_proto::chayd_service_server::ChaydServiceServer;
 use clap::Parser;
 use std::collections::HashMap;
-use wildmatch::WildMatch;
+use std::os::unix::fs::PermissionsExt;
 
 mod chay_proto {
     tonic::include_proto!("chay.proto.v1");
 }
+mod auth;
 mod chayd_service_impl;
 mod config;
+mod jobs;
+mod metrics;
 mod program;
+mod program_cgroup;
+mod program_changes;
+mod program_console;
 mod program_context;
+mod program_exec;
 mod program_fsm;
+mod program_rlimits;
+mod program_selector;
+mod program_usage;
+mod program_waits;
+mod program_watchdog;
 mod proto_converters;
+mod request_error;
 
 /// Daemon to supervise a list of processes
 #[derive(clap::Parser, Debug)]
@@ -29,6 +53,143 @@ pub fn bug_panic(message: &str) {
     panic!("Internal Error! Please create a bug report: {}", message);
 }
 
+fn set_socket_permissions(
+    socket_path: &std::path::Path,
+    chayd_config: &crate::config::ChaydConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let uid = match &chayd_config.socket_owner {
+        Some(owner) => Some(
+            nix::unistd::User::from_name(owner)?
+                .ok_or(format!("User not found: {owner}"))?
+                .uid,
+        ),
+        None => None,
+    };
+    let gid = match &chayd_config.socket_group {
+        Some(group) => Some(
+            nix::unistd::Group::from_name(group)?
+                .ok_or(format!("Group not found: {group}"))?
+                .gid,
+        ),
+        None => None,
+    };
+    if uid.is_some() || gid.is_some() {
+        nix::unistd::chown(socket_path, uid, gid)?;
+    }
+    if let Some(socket_mode) = &chayd_config.socket_mode {
+        let mode = u32::from_str_radix(socket_mode, 8)
+            .map_err(|_| format!("Invalid socket_mode: {socket_mode}"))?;
+        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Removes the socket file left behind by a previous chayd instance, otherwise bind fails. Anything
+/// else at the path, including the socket of a chayd that is still running, is left alone.
+fn remove_stale_socket(socket_path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+    let metadata = match std::fs::symlink_metadata(socket_path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", socket_path.display()).into());
+    }
+    match std::os::unix::net::UnixStream::connect(socket_path) {
+        Ok(_) => Err(format!("{} is in use by another process", socket_path.display()).into()),
+        Err(error) if error.kind() == std::io::ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket {}", socket_path.display());
+            std::fs::remove_file(socket_path)?;
+            Ok(())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn bind_unix_listener(
+    socket_path: &std::path::Path,
+    chayd_config: &crate::config::ChaydConfig,
+) -> Result<tokio::net::UnixListener, Box<dyn std::error::Error>> {
+    remove_stale_socket(socket_path)?;
+    // Only chayd's user may connect until the configured owner, group and mode are set. The umask
+    // is process-wide, but no other thread creates files before the programs are started.
+    let old_umask = nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(0o177));
+    let bind_result = tokio::net::UnixListener::bind(socket_path);
+    nix::sys::stat::umask(old_umask);
+    let listener = bind_result?;
+    set_socket_permissions(socket_path, chayd_config)?;
+    Ok(listener)
+}
+
+/// Runs a server until it fails, which chayd can't recover from.
+async fn exit_on_server_error<E: std::fmt::Display>(
+    server_name: &str,
+    server: impl std::future::Future<Output = Result<(), E>>,
+) {
+    if let Err(error) = server.await {
+        log::error!("{} server failed: {}", server_name, error);
+        std::process::exit(1);
+    }
+}
+
+fn server_tls_config(
+    chayd_config: &crate::config::ChaydConfig,
+) -> Result<Option<tonic::transport::ServerTlsConfig>, Box<dyn std::error::Error>> {
+    let (cert_path, key_path) = match (&chayd_config.tls_cert, &chayd_config.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => {
+            if chayd_config.tls_client_ca.is_some() {
+                return Err("tls_client_ca requires tls_cert and tls_key".into());
+            }
+            return Ok(None);
+        }
+        _ => return Err("tls_cert and tls_key must be set together".into()),
+    };
+    let identity =
+        tonic::transport::Identity::from_pem(std::fs::read(cert_path)?, std::fs::read(key_path)?);
+    let mut tls_config = tonic::transport::ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca_path) = &chayd_config.tls_client_ca {
+        tls_config = tls_config.client_ca_root(tonic::transport::Certificate::from_pem(
+            std::fs::read(client_ca_path)?,
+        ));
+    }
+    Ok(Some(tls_config))
+}
+
+/// Re-reads the config file and applies the changes to the programs and auth. The old config is
+/// kept if the new one is invalid.
+fn reload_config(
+    config_path: &std::path::PathBuf,
+    config: &mut crate::config::Config,
+    program_fsms: &mut Vec<ProgramFsm>,
+    program_changes: &mut ProgramChanges,
+    authenticator: &std::sync::RwLock<crate::auth::Authenticator>,
+) -> Result<ConfigChanges, RequestError> {
+    log::info!("Reloading config from {}", config_path.display());
+    let invalid_config = |message: String| {
+        log::error!("{}, keeping the old config", message);
+        RequestError::new(tonic::Code::InvalidArgument, message)
+    };
+    let (new_config, rendered_config) = crate::config::read_and_render(config_path, &config.chayd)
+        .map_err(|error| {
+            invalid_config(format!(
+                "Invalid config: {}",
+                crate::config::render_error_message(error.as_ref())
+            ))
+        })?;
+    let new_authenticator = crate::auth::Authenticator::new(
+        new_config.auth.clone(),
+        new_config.chayd.allow_unauthenticated_commands,
+    )
+    .map_err(|error| invalid_config(format!("Invalid auth config: {}", error)))?;
+    *authenticator.write().unwrap() = new_authenticator;
+    *config = new_config;
+    let config_changes = program_changes.apply_config(program_fsms, &rendered_config);
+    log::info!("Reloaded config: {:?}", config_changes);
+    Ok(config_changes)
+}
+
 fn update_program_fsms(program_fsms: &mut Vec<ProgramFsm>) {
     for program_fsm in program_fsms {
         program_fsm.update();
@@ -44,61 +205,330 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     simple_log::new(log_config)?;
 
     let args = Args::parse();
-    let config = crate::config::read_from_file(&args.config_path).unwrap_or_else(|error| {
+    let mut config = crate::config::read_from_file(&args.config_path).unwrap_or_else(|error| {
         log::error!("Error parsing toml file: {}", error);
         std::process::exit(1);
     });
+    if let Some(cgroup_parent) = &config.chayd.cgroup_parent {
+        crate::program_cgroup::prepare_cgroup_parent(cgroup_parent).unwrap_or_else(|error| {
+            log::error!("Invalid cgroup_parent: {}", error);
+            std::process::exit(1);
+        });
+    }
     let rendered_config = crate::config::render(&config).unwrap_or_else(|error| {
-        log::error!("Invalid config: {}", error.source().unwrap());
+        log::error!(
+            "Invalid config: {}",
+            crate::config::render_error_message(error.as_ref())
+        );
         std::process::exit(1);
     });
 
+    let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
     let mut program_fsms: Vec<ProgramFsm> = rendered_config
         .iter()
         .map(|(program_name, program_config)| {
-            new_program_fsm(program_name.clone(), &program_config)
+            new_program_fsm(
+                program_name.clone(),
+                program_config,
+                program_transitions_tx.clone(),
+            )
         })
         .collect();
 
-    let program_states_channels =
-        std::sync::Arc::new(tokio::sync::RwLock::new(ProgramStatesChannels::default()));
+    let program_statuses_channels =
+        std::sync::Arc::new(tokio::sync::RwLock::new(ProgramStatusesChannels::default()));
     let (program_events_tx, mut program_events_rx) = tokio::sync::mpsc::channel(20);
+    let (program_changes_tx, mut program_changes_rx) = tokio::sync::mpsc::channel(20);
+    let mut program_changes = ProgramChanges::new(program_transitions_tx.clone());
+    let (config_reloads_tx, mut config_reloads_rx) = tokio::sync::mpsc::channel(1);
+    let (attach_requests_tx, mut attach_requests_rx) = tokio::sync::mpsc::channel(20);
+    let (exec_requests_tx, mut exec_requests_rx) = tokio::sync::mpsc::channel(20);
+    let (run_job_requests_tx, mut run_job_requests_rx) = tokio::sync::mpsc::channel(20);
+    let (get_job_requests_tx, mut get_job_requests_rx) = tokio::sync::mpsc::channel(20);
+    let (tail_requests_tx, mut tail_requests_rx) = tokio::sync::mpsc::channel(20);
+    let mut jobs = Jobs::new(program_transitions_tx.clone());
+    let (program_metrics_requests_tx, mut program_metrics_requests_rx) =
+        tokio::sync::mpsc::channel(20);
+    let rpc_metrics = std::sync::Arc::new(std::sync::Mutex::new(Default::default()));
+    let mut program_waits = ProgramWaits::default();
+    // Seeds the snapshot GetStatus answers from, which is otherwise only filled on the first tick.
+    broadcast_program_statuses(
+        program_fsms.iter().chain(jobs.program_fsms()),
+        &program_statuses_channels,
+    )
+    .await;
 
-    let chayd_addr = "[::1]:50051".parse()?;
-    let chayd_service = ChaydServiceImpl::new(program_states_channels.clone(), program_events_tx);
-
-    tokio::spawn(
-        tonic::transport::Server::builder()
-            .add_service(ChaydServiceServer::new(chayd_service))
-            .serve(chayd_addr),
+    let chayd_addr: ChaydAddr = config.chayd.addr.parse().unwrap_or_else(|error| {
+        log::error!("Invalid chayd config: {}", error);
+        std::process::exit(1);
+    });
+    let authenticator = crate::auth::Authenticator::new(
+        config.auth.clone(),
+        config.chayd.allow_unauthenticated_commands,
+    )
+    .unwrap_or_else(|error| {
+        log::error!("Invalid auth config: {}", error);
+        std::process::exit(1);
+    });
+    let authenticator = std::sync::Arc::new(std::sync::RwLock::new(authenticator));
+    let chayd_service = ChaydServiceImpl::new(
+        program_statuses_channels.clone(),
+        ChaydServiceSenders {
+            program_events: program_events_tx,
+            program_transitions: program_transitions_tx,
+            program_changes: program_changes_tx,
+            config_reloads: config_reloads_tx,
+            attach_requests: attach_requests_tx,
+            exec_requests: exec_requests_tx,
+            run_job_requests: run_job_requests_tx,
+            get_job_requests: get_job_requests_tx,
+            tail_requests: tail_requests_tx,
+        },
+        authenticator.clone(),
     );
 
+    let mut chayd_server_builder = tonic::transport::Server::builder();
+    let tls_config = server_tls_config(&config.chayd).unwrap_or_else(|error| {
+        log::error!("Invalid TLS config: {}", error);
+        std::process::exit(1);
+    });
+    if let Some(tls_config) = tls_config {
+        chayd_server_builder =
+            chayd_server_builder
+                .tls_config(tls_config)
+                .unwrap_or_else(|error| {
+                    log::error!("Invalid TLS config: {}", error);
+                    std::process::exit(1);
+                });
+    }
+    let chayd_server = chayd_server_builder
+        .layer(RpcMetricsLayer::new(rpc_metrics.clone()))
+        .add_service(ChaydServiceServer::new(chayd_service));
+    match &chayd_addr {
+        ChaydAddr::Tcp(socket_addr) => {
+            let listener = tokio::net::TcpListener::bind(socket_addr)
+                .await
+                .unwrap_or_else(|error| {
+                    log::error!("Could not bind {}: {}", chayd_addr, error);
+                    std::process::exit(1);
+                });
+            tokio::spawn(exit_on_server_error(
+                "gRPC",
+                chayd_server
+                    .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+            ));
+        }
+        ChaydAddr::Unix(socket_path) => {
+            let listener = bind_unix_listener(socket_path, &config.chayd).unwrap_or_else(|error| {
+                log::error!("Could not bind {}: {}", chayd_addr, error);
+                std::process::exit(1);
+            });
+            tokio::spawn(exit_on_server_error(
+                "gRPC",
+                chayd_server
+                    .serve_with_incoming(tokio_stream::wrappers::UnixListenerStream::new(listener)),
+            ));
+        }
+    }
+    log::info!("Listening on {}", chayd_addr);
+
+    if let Some(metrics_addr) = &config.chayd.metrics_addr {
+        let metrics_addr: std::net::SocketAddr = metrics_addr.parse().unwrap_or_else(|error| {
+            log::error!("Invalid metrics_addr {}: {}", metrics_addr, error);
+            std::process::exit(1);
+        });
+        let metrics_endpoint = MetricsEndpoint::new(
+            program_metrics_requests_tx.clone(),
+            rpc_metrics,
+            program_statuses_channels.clone(),
+        );
+        let metrics_server = metrics_endpoint
+            .serve(&metrics_addr)
+            .unwrap_or_else(|error| {
+                log::error!("Could not bind {}: {}", metrics_addr, error);
+                std::process::exit(1);
+            });
+        tokio::spawn(exit_on_server_error("Metrics", metrics_server));
+        log::info!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
+
+    let mut sighup_stream = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .unwrap_or_else(|error| {
+            log::error!("Could not handle SIGHUP: {}", error);
+            std::process::exit(1);
+        });
+
     let mut fsm_update_interval = tokio::time::interval(tokio::time::Duration::from_millis(500));
     loop {
         tokio::select! {
             _ = fsm_update_interval.tick() => {
                 update_program_fsms(&mut program_fsms);
-                broadcast_program_states(&program_fsms, &program_states_channels).await;
+                jobs.update();
+                sample_program_usages(program_fsms.iter_mut().chain(jobs.program_fsms_mut()));
+                program_changes.apply_pending_changes(&mut program_fsms).await;
+                program_waits.check(&program_fsms).await;
+                broadcast_program_statuses(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &program_statuses_channels,
+                )
+                .await;
             },
-            Some((program_event, program_expr, program_events_tx)) = program_events_rx.recv() => {
+            Some(_) = sighup_stream.recv() => {
+                let _ = reload_config(
+                    &args.config_path,
+                    &mut config,
+                    &mut program_fsms,
+                    &mut program_changes,
+                    &authenticator,
+                );
+                broadcast_program_statuses(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &program_statuses_channels,
+                )
+                .await;
+            },
+            Some(config_reload_request) = config_reloads_rx.recv() => {
+                let result = reload_config(
+                    &args.config_path,
+                    &mut config,
+                    &mut program_fsms,
+                    &mut program_changes,
+                    &authenticator,
+                );
+                match config_reload_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send config reload result"),
+                }
+                broadcast_program_statuses(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &program_statuses_channels,
+                )
+                .await;
+            },
+            Some(attach_request) = attach_requests_rx.recv() => {
+                let result = program_console(&program_fsms, &attach_request);
+                match attach_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send attach result"),
+                }
+            },
+            Some(tail_request) = tail_requests_rx.recv() => {
+                let result = tailed_program_console(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &tail_request,
+                );
+                match tail_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send tail result"),
+                }
+            },
+            Some(exec_request) = exec_requests_rx.recv() => {
+                let result = exec_command(&config, &program_fsms, &exec_request);
+                match exec_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send exec result"),
+                }
+            },
+            Some(program_metrics_request) = program_metrics_requests_rx.recv() => {
+                let program_metrics = render_program_metrics(&program_fsms);
+                match program_metrics_request.results_sender.send(program_metrics).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send program metrics"),
+                }
+            },
+            Some(run_job_request) = run_job_requests_rx.recv() => {
+                let result = jobs.run(&config, &program_fsms, &run_job_request);
+                match run_job_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send run job result"),
+                }
+            },
+            Some(get_job_request) = get_job_requests_rx.recv() => {
+                let result = jobs.get(&get_job_request);
+                match get_job_request.results_sender.send(result).await {
+                    Ok(_) => {},
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send get job result"),
+                }
+            },
+            Some(program_change_request) = program_changes_rx.recv() => {
+                program_changes
+                    .handle_request(&config, &mut program_fsms, program_change_request)
+                    .await;
+                broadcast_program_statuses(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &program_statuses_channels,
+                )
+                .await;
+            },
+            Some(program_events_request) = program_events_rx.recv() => {
+                let ProgramEventsRequest {
+                    program_action,
+                    program_selector,
+                    permissions,
+                    source,
+                    wait,
+                    results_sender: program_events_tx,
+                } = program_events_request;
                 let mut result = HashMap::<String, chay::fsm::MachineResult>::new();
-                let match_all = program_expr == "all";
+                let action = program_action.name();
                 for fsm in &mut program_fsms {
                     let program_name = fsm.app_context().name();
-                    if match_all || WildMatch::new(&program_expr).matches(&program_name) {
-                        result.insert(program_name, fsm.react(&program_event));
+                    let target = SelectorTarget::from_program_fsm(fsm);
+                    if !program_selector.matches(&target) {
+                        continue;
+                    }
+                    if !permissions.is_allowed(action, &target) {
+                        result.insert(program_name, Err(permissions.denied_message(action)));
+                        continue;
+                    }
+                    match &program_action {
+                        ProgramAction::Event(program_event) => {
+                            fsm.app_context_mut().transition_reason =
+                                Some(TransitionReason::UserRequest {
+                                    action: action.to_string(),
+                                    source: source.clone(),
+                                });
+                            result.insert(program_name, fsm.react(program_event));
+                            // Don't leave the reason around for a later transition if the event
+                            // didn't cause one.
+                            fsm.app_context_mut().transition_reason = None;
+                        }
+                        ProgramAction::Signal { signal, target } => {
+                            result.insert(
+                                program_name,
+                                fsm.app_context_mut().send_signal_to_target(*signal, *target),
+                            );
+                        }
                     }
                 }
                 if result.is_empty() {
                     let status = tonic::Status::not_found(format!(
                         "No programs found matching expression: {}",
-                        program_expr
+                        program_selector
                     ));
                     match program_events_tx.send(Err(status)).await {
                         Ok(_) => {},
                         // The connection was probably closed by the client.
                         Err(_) => log::warn!("Could not send program events results"),
                     }
+                } else if let (Some(wait), ProgramAction::Event(program_event)) =
+                    (wait, &program_action)
+                {
+                    program_waits.add(
+                        &program_fsms,
+                        program_event,
+                        result,
+                        wait.timeout,
+                        program_events_tx,
+                    );
+                    program_waits.check(&program_fsms).await;
                 } else {
                     match program_events_tx.send(Ok(result)).await {
                         Ok(_) => {},
@@ -106,8 +536,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         Err(_) => log::warn!("Could not send program events results"),
                     }
                 }
-                broadcast_program_states(&program_fsms, &program_states_channels).await;
+                broadcast_program_statuses(
+                    program_fsms.iter().chain(jobs.program_fsms()),
+                    &program_statuses_channels,
+                )
+                .await;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("chayd.sock");
+        remove_stale_socket(&socket_path).unwrap();
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let error = remove_stale_socket(&socket_path).unwrap_err();
+        assert!(error.to_string().contains("in use"), "{error}");
+        assert!(socket_path.exists());
+        drop(listener);
+        remove_stale_socket(&socket_path).unwrap();
+        assert!(!socket_path.exists());
+
+        std::fs::write(&socket_path, "").unwrap();
+        let error = remove_stale_socket(&socket_path).unwrap_err();
+        assert!(error.to_string().contains("not a socket"), "{error}");
+        assert!(socket_path.exists());
+    }
+
+    #[test]
+    fn reloads_the_auth_config() {
+        let config_content = |token: &str| {
+            format!(
+                r#"
+                [auth]
+                tokens = {{ deploy = "{token}" }}
+                [vars]
+                [loggers]
+                [programs]
+                "#
+            )
+        };
+        let authenticate = |authenticator: &std::sync::RwLock<crate::auth::Authenticator>,
+                            token: &str| {
+            let mut request = tonic::Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {token}").parse().unwrap());
+            authenticator.read().unwrap().authenticate(&request)
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("chayd.toml");
+        std::fs::write(&config_path, config_content("old-token")).unwrap();
+        let mut config = crate::config::read_from_file(&config_path).unwrap();
+        let authenticator = std::sync::RwLock::new(
+            crate::auth::Authenticator::new(config.auth.clone(), false).unwrap(),
+        );
+        let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
+        let mut program_changes = ProgramChanges::new(program_transitions_tx);
+        let mut program_fsms = vec![];
+        assert!(authenticate(&authenticator, "old-token").is_ok());
+
+        std::fs::write(&config_path, config_content("new-token")).unwrap();
+        reload_config(
+            &config_path,
+            &mut config,
+            &mut program_fsms,
+            &mut program_changes,
+            &authenticator,
+        )
+        .unwrap();
+        assert!(authenticate(&authenticator, "old-token").is_err());
+        assert!(authenticate(&authenticator, "new-token").is_ok());
+
+        // An invalid auth section keeps the old config and authenticator.
+        std::fs::write(
+            &config_path,
+            config_content("new-token") + "[[auth.rules]]\nidentity = \"deploy\"\nactions = [\"launch\"]\nprograms = \"web\"\n",
+        )
+        .unwrap();
+        reload_config(
+            &config_path,
+            &mut config,
+            &mut program_fsms,
+            &mut program_changes,
+            &authenticator,
+        )
+        .unwrap_err();
+        assert!(authenticate(&authenticator, "new-token").is_ok());
+        assert_eq!(config.auth.unwrap().tokens["deploy"], "new-token");
+    }
+}
@@ -0,0 +1,646 @@
+use crate::bug_panic;
+use crate::chayd_service_impl::ProgramStatusesChannels;
+use crate::program_fsm::{ProgramFsm, ProgramState};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use tonic::codegen::http;
+
+const PROGRAM_STATES: [ProgramState; 7] = [
+    ProgramState::Stopped,
+    ProgramState::Exited,
+    ProgramState::Backoff,
+    ProgramState::Starting,
+    ProgramState::Running,
+    ProgramState::Stopping,
+    ProgramState::Exiting,
+];
+/// Upper bounds of the RPC latency histogram buckets, in seconds.
+const RPC_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn state_label(program_state: &ProgramState) -> String {
+    format!("{:?}", program_state).to_lowercase()
+}
+
+/// Counts of a program's state machine. They start over when the program is replaced, e.g. by a
+/// config reload, which Prometheus handles like a restart of the process.
+#[derive(Debug, Default)]
+pub struct ProgramFsmMetrics {
+    transition_counts: HashMap<(ProgramState, ProgramState), u64>,
+    /// Time spent in backoff, not counting the current backoff.
+    backoff_duration: std::time::Duration,
+    backoff_start_time: Option<std::time::Instant>,
+}
+
+impl ProgramFsmMetrics {
+    /// Called by the FSM's transition observer.
+    pub fn observe_transition(&mut self, from_state: &ProgramState, to_state: &ProgramState) {
+        *self
+            .transition_counts
+            .entry((from_state.clone(), to_state.clone()))
+            .or_default() += 1;
+        if *from_state == ProgramState::Backoff {
+            if let Some(backoff_start_time) = self.backoff_start_time.take() {
+                self.backoff_duration += backoff_start_time.elapsed();
+            }
+        }
+        if *to_state == ProgramState::Backoff {
+            self.backoff_start_time = Some(std::time::Instant::now());
+        }
+    }
+
+    fn backoff_duration(&self) -> std::time::Duration {
+        self.backoff_duration
+            + self
+                .backoff_start_time
+                .map_or(std::time::Duration::ZERO, |backoff_start_time| {
+                    backoff_start_time.elapsed()
+                })
+    }
+
+    /// Every entry into backoff is a restart attempt, same as ProgramContext::num_restarts.
+    fn num_restarts(&self) -> u64 {
+        self.transition_counts
+            .iter()
+            .filter(|((_, to_state), _)| *to_state == ProgramState::Backoff)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Writes metrics in the Prometheus text format.
+#[derive(Default)]
+struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {metric_type}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, label_value)| {
+                    let label_value = label_value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{label}=\"{label_value}\"")
+                })
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {value}");
+    }
+}
+
+/// Renders the metrics of every program. Jobs are left out since each has a new name.
+pub fn render_program_metrics(program_fsms: &[ProgramFsm]) -> String {
+    let mut writer = MetricsWriter::default();
+    writer.header(
+        "chay_program_state",
+        "gauge",
+        "Whether the program is in the state.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        let current_state = program_fsm.current_state_key();
+        for state in &PROGRAM_STATES {
+            writer.sample(
+                "chay_program_state",
+                &[
+                    ("program", &program_ctx.name),
+                    ("state", &state_label(state)),
+                ],
+                (*state == current_state) as u8,
+            );
+        }
+    }
+    writer.header(
+        "chay_program_uptime_seconds",
+        "gauge",
+        "Time since the program's process started, 0 if it isn't running.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        let program = &program_ctx.program.program;
+        let uptime = match program.pid() {
+            Some(_) => program
+                .start_time
+                .and_then(|start_time| start_time.elapsed().ok())
+                .unwrap_or_default(),
+            None => std::time::Duration::ZERO,
+        };
+        writer.sample(
+            "chay_program_uptime_seconds",
+            &[("program", &program_ctx.name)],
+            uptime.as_secs_f64(),
+        );
+    }
+    writer.header(
+        "chay_program_restarts_total",
+        "counter",
+        "Number of times the program went into backoff to be restarted.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        writer.sample(
+            "chay_program_restarts_total",
+            &[("program", &program_ctx.name)],
+            program_ctx.metrics.num_restarts(),
+        );
+    }
+    writer.header(
+        "chay_program_consecutive_restarts",
+        "gauge",
+        "Number of consecutive restart attempts since the program was last running.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        writer.sample(
+            "chay_program_consecutive_restarts",
+            &[("program", &program_ctx.name)],
+            program_ctx.num_restarts,
+        );
+    }
+    writer.header(
+        "chay_program_exits_total",
+        "counter",
+        "Number of times the program's process exited, by exit code or by signal.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        let program = &program_ctx.program.program;
+        for (code, count) in &program.exit_code_counts {
+            writer.sample(
+                "chay_program_exits_total",
+                &[("program", &program_ctx.name), ("code", &code.to_string())],
+                count,
+            );
+        }
+        for (signal, count) in &program.exit_signal_counts {
+            writer.sample(
+                "chay_program_exits_total",
+                &[
+                    ("program", &program_ctx.name),
+                    ("signal", &signal.to_string()),
+                ],
+                count,
+            );
+        }
+    }
+    writer.header(
+        "chay_program_backoff_seconds_total",
+        "counter",
+        "Time the program spent in backoff.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        writer.sample(
+            "chay_program_backoff_seconds_total",
+            &[("program", &program_ctx.name)],
+            program_ctx.metrics.backoff_duration().as_secs_f64(),
+        );
+    }
+    writer.header(
+        "chay_program_transitions_total",
+        "counter",
+        "Number of state transitions of the program.",
+    );
+    for program_fsm in program_fsms {
+        let program_ctx = program_fsm.app_context();
+        // Sorted so the output is stable between scrapes.
+        let transition_counts: BTreeMap<(String, String), u64> = program_ctx
+            .metrics
+            .transition_counts
+            .iter()
+            .map(|((from_state, to_state), count)| {
+                ((state_label(from_state), state_label(to_state)), *count)
+            })
+            .collect();
+        for ((from_state, to_state), count) in &transition_counts {
+            writer.sample(
+                "chay_program_transitions_total",
+                &[
+                    ("program", &program_ctx.name),
+                    ("from", from_state),
+                    ("to", to_state),
+                ],
+                count,
+            );
+        }
+    }
+    writer.text
+}
+
+#[derive(Default)]
+struct RpcDurationHistogram {
+    /// Number of observations at most as long as each of RPC_DURATION_BUCKETS.
+    bucket_counts: [u64; RPC_DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counts and latencies of the RPCs by method. The latency of a streaming RPC is the time until
+/// its stream was returned.
+#[derive(Default)]
+pub struct RpcMetrics {
+    request_counts: BTreeMap<(String, String), u64>,
+    durations: BTreeMap<String, RpcDurationHistogram>,
+}
+
+impl RpcMetrics {
+    fn observe(&mut self, method: &str, code: tonic::Code, duration: std::time::Duration) {
+        *self
+            .request_counts
+            .entry((method.to_string(), format!("{:?}", code)))
+            .or_default() += 1;
+        let histogram = self.durations.entry(method.to_string()).or_default();
+        let secs = duration.as_secs_f64();
+        for (bucket_count, upper_bound) in
+            histogram.bucket_counts.iter_mut().zip(RPC_DURATION_BUCKETS)
+        {
+            if secs <= upper_bound {
+                *bucket_count += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    fn render(&self, writer: &mut MetricsWriter) {
+        writer.header(
+            "chay_rpc_requests_total",
+            "counter",
+            "Number of RPCs handled by chayd, by method and status code.",
+        );
+        for ((method, code), count) in &self.request_counts {
+            writer.sample(
+                "chay_rpc_requests_total",
+                &[("method", method), ("code", code)],
+                count,
+            );
+        }
+        writer.header(
+            "chay_rpc_duration_seconds",
+            "histogram",
+            "Time chayd took to respond to RPCs, by method.",
+        );
+        for (method, histogram) in &self.durations {
+            for (bucket_count, upper_bound) in
+                histogram.bucket_counts.iter().zip(RPC_DURATION_BUCKETS)
+            {
+                writer.sample(
+                    "chay_rpc_duration_seconds_bucket",
+                    &[("method", method), ("le", &upper_bound.to_string())],
+                    bucket_count,
+                );
+            }
+            writer.sample(
+                "chay_rpc_duration_seconds_bucket",
+                &[("method", method), ("le", "+Inf")],
+                histogram.count,
+            );
+            writer.sample(
+                "chay_rpc_duration_seconds_sum",
+                &[("method", method)],
+                histogram.sum,
+            );
+            writer.sample(
+                "chay_rpc_duration_seconds_count",
+                &[("method", method)],
+                histogram.count,
+            );
+        }
+    }
+}
+
+/// Records the RPC metrics of every request to the gRPC server.
+#[derive(Clone)]
+pub struct RpcMetricsLayer {
+    rpc_metrics: std::sync::Arc<std::sync::Mutex<RpcMetrics>>,
+}
+
+impl RpcMetricsLayer {
+    pub fn new(rpc_metrics: std::sync::Arc<std::sync::Mutex<RpcMetrics>>) -> Self {
+        Self { rpc_metrics }
+    }
+}
+
+impl<S> tower::Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetricsService {
+            inner,
+            rpc_metrics: self.rpc_metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcMetricsService<S> {
+    inner: S,
+    rpc_metrics: std::sync::Arc<std::sync::Mutex<RpcMetrics>>,
+}
+
+impl<S, ReqBody, ResBody> tower::Service<http::Request<ReqBody>> for RpcMetricsService<S>
+where
+    S: tower::Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // The path is "/<package>.<service>/<method>".
+        let method = request
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let start_time = std::time::Instant::now();
+        let rpc_metrics = self.rpc_metrics.clone();
+        let response_future = self.inner.call(request);
+        Box::pin(async move {
+            let result = response_future.await;
+            // Errors are in the headers when returned before the response body, which is the
+            // case for every error returned by a handler. A streaming RPC that fails later on
+            // has its error in the trailers and is counted as Ok.
+            let code = match &result {
+                Ok(response) => tonic::Status::from_header_map(response.headers())
+                    .map_or(tonic::Code::Ok, |status| status.code()),
+                Err(_) => tonic::Code::Unknown,
+            };
+            rpc_metrics
+                .lock()
+                .unwrap()
+                .observe(&method, code, start_time.elapsed());
+            result
+        })
+    }
+}
+
+pub struct ProgramMetricsRequest {
+    pub results_sender: tokio::sync::mpsc::Sender<String>,
+}
+
+/// Serves the metrics over HTTP. The program metrics are rendered by the main loop, which owns
+/// the programs.
+#[derive(Clone)]
+pub struct MetricsEndpoint {
+    program_metrics_requests_sender: tokio::sync::mpsc::Sender<ProgramMetricsRequest>,
+    rpc_metrics: std::sync::Arc<std::sync::Mutex<RpcMetrics>>,
+    program_statuses_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatusesChannels>>,
+}
+
+impl MetricsEndpoint {
+    pub fn new(
+        program_metrics_requests_sender: tokio::sync::mpsc::Sender<ProgramMetricsRequest>,
+        rpc_metrics: std::sync::Arc<std::sync::Mutex<RpcMetrics>>,
+        program_statuses_channels: std::sync::Arc<tokio::sync::RwLock<ProgramStatusesChannels>>,
+    ) -> Self {
+        Self {
+            program_metrics_requests_sender,
+            rpc_metrics,
+            program_statuses_channels,
+        }
+    }
+
+    async fn render(&self) -> String {
+        let (program_metrics_tx, mut program_metrics_rx) = tokio::sync::mpsc::channel(1);
+        match self
+            .program_metrics_requests_sender
+            .send(ProgramMetricsRequest {
+                results_sender: program_metrics_tx,
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(_) => {
+                bug_panic("Could not send to program metrics requests channel");
+            }
+        }
+        let mut text = match program_metrics_rx.recv().await {
+            Some(program_metrics) => program_metrics,
+            None => {
+                bug_panic("Received None from program metrics requests channel rx");
+                // Unreachable
+                String::new()
+            }
+        };
+        let mut writer = MetricsWriter::default();
+        self.rpc_metrics.lock().unwrap().render(&mut writer);
+        writer.header(
+            "chay_status_streams",
+            "gauge",
+            "Number of clients watching the program statuses.",
+        );
+        writer.sample(
+            "chay_status_streams",
+            &[],
+            self.program_statuses_channels.read().await.senders.len(),
+        );
+        text.push_str(&writer.text);
+        text
+    }
+
+    async fn handle(
+        &self,
+        request: hyper::Request<hyper::Body>,
+    ) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+        if request.method() != hyper::Method::GET || request.uri().path() != "/metrics" {
+            return Ok(hyper::Response::builder()
+                .status(hyper::StatusCode::NOT_FOUND)
+                .body(hyper::Body::from("Not found, metrics are at /metrics\n"))
+                .unwrap());
+        }
+        Ok(hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(hyper::Body::from(self.render().await))
+            .unwrap())
+    }
+
+    /// Binds the address right away, so an address in use is reported at startup.
+    pub fn serve(
+        self,
+        metrics_addr: &std::net::SocketAddr,
+    ) -> hyper::Result<impl std::future::Future<Output = hyper::Result<()>>> {
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let metrics_endpoint = self.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request| {
+                    let metrics_endpoint = metrics_endpoint.clone();
+                    async move { metrics_endpoint.handle(request).await }
+                }))
+            }
+        });
+        Ok(hyper::Server::try_bind(metrics_addr)?.serve(make_service))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, RenderedProgramConfig};
+
+    #[test]
+    fn escapes_label_values() {
+        let mut writer = MetricsWriter::default();
+        writer.sample("chay_test", &[("a", "x\\y"), ("b", "\"z\"\n")], 1);
+        writer.sample("chay_test", &[], 2);
+        assert_eq!(
+            writer.text,
+            "chay_test{a=\"x\\\\y\",b=\"\\\"z\\\"\\n\"} 1\nchay_test 2\n"
+        );
+    }
+
+    #[test]
+    fn renders_rpc_metrics() {
+        let mut rpc_metrics = RpcMetrics::default();
+        // Durations that are exact in binary, so their sum prints exactly.
+        rpc_metrics.observe(
+            "Start",
+            tonic::Code::Ok,
+            std::time::Duration::from_nanos(3_906_250),
+        );
+        // Bucket bounds are inclusive.
+        rpc_metrics.observe(
+            "Start",
+            tonic::Code::Ok,
+            std::time::Duration::from_millis(250),
+        );
+        rpc_metrics.observe(
+            "Start",
+            tonic::Code::NotFound,
+            std::time::Duration::from_secs(20),
+        );
+        let mut writer = MetricsWriter::default();
+        rpc_metrics.render(&mut writer);
+        assert_eq!(
+            writer.text,
+            r#"# HELP chay_rpc_requests_total Number of RPCs handled by chayd, by method and status code.
+# TYPE chay_rpc_requests_total counter
+chay_rpc_requests_total{method="Start",code="NotFound"} 1
+chay_rpc_requests_total{method="Start",code="Ok"} 2
+# HELP chay_rpc_duration_seconds Time chayd took to respond to RPCs, by method.
+# TYPE chay_rpc_duration_seconds histogram
+chay_rpc_duration_seconds_bucket{method="Start",le="0.005"} 1
+chay_rpc_duration_seconds_bucket{method="Start",le="0.01"} 1
+chay_rpc_duration_seconds_bucket{method="Start",le="0.025"} 1
+chay_rpc_duration_seconds_bucket{method="Start",le="0.05"} 1
+chay_rpc_duration_seconds_bucket{method="Start",le="0.1"} 1
+chay_rpc_duration_seconds_bucket{method="Start",le="0.25"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="0.5"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="1"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="2.5"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="5"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="10"} 2
+chay_rpc_duration_seconds_bucket{method="Start",le="+Inf"} 3
+chay_rpc_duration_seconds_sum{method="Start"} 20.25390625
+chay_rpc_duration_seconds_count{method="Start"} 3
+"#
+        );
+    }
+
+    #[test]
+    fn renders_program_metrics() {
+        let config: Config = toml::from_str(
+            r#"
+            [vars]
+            [loggers]
+            [programs.web]
+            command = "true"
+            autostart = false
+            "#,
+        )
+        .unwrap();
+        let rendered_config =
+            RenderedProgramConfig::new(&config, "web", &config.programs["web"]).unwrap();
+        let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
+        let mut program_fsm = crate::program_fsm::new_program_fsm(
+            "web".to_string(),
+            &rendered_config,
+            program_transitions_tx,
+        );
+        let program_ctx = program_fsm.app_context_mut();
+        program_ctx.num_restarts = 1;
+        program_ctx.program.program.exit_code_counts = BTreeMap::from([(1, 2)]);
+        program_ctx.program.program.exit_signal_counts = BTreeMap::from([(9, 1)]);
+        let metrics = &mut program_ctx.metrics;
+        metrics.transition_counts = HashMap::from([
+            ((ProgramState::Starting, ProgramState::Running), 1),
+            ((ProgramState::Running, ProgramState::Backoff), 2),
+            ((ProgramState::Backoff, ProgramState::Starting), 2),
+        ]);
+        metrics.backoff_duration = std::time::Duration::from_millis(1500);
+        assert_eq!(
+            render_program_metrics(&[program_fsm]),
+            r#"# HELP chay_program_state Whether the program is in the state.
+# TYPE chay_program_state gauge
+chay_program_state{program="web",state="stopped"} 1
+chay_program_state{program="web",state="exited"} 0
+chay_program_state{program="web",state="backoff"} 0
+chay_program_state{program="web",state="starting"} 0
+chay_program_state{program="web",state="running"} 0
+chay_program_state{program="web",state="stopping"} 0
+chay_program_state{program="web",state="exiting"} 0
+# HELP chay_program_uptime_seconds Time since the program's process started, 0 if it isn't running.
+# TYPE chay_program_uptime_seconds gauge
+chay_program_uptime_seconds{program="web"} 0
+# HELP chay_program_restarts_total Number of times the program went into backoff to be restarted.
+# TYPE chay_program_restarts_total counter
+chay_program_restarts_total{program="web"} 2
+# HELP chay_program_consecutive_restarts Number of consecutive restart attempts since the program was last running.
+# TYPE chay_program_consecutive_restarts gauge
+chay_program_consecutive_restarts{program="web"} 1
+# HELP chay_program_exits_total Number of times the program's process exited, by exit code or by signal.
+# TYPE chay_program_exits_total counter
+chay_program_exits_total{program="web",code="1"} 2
+chay_program_exits_total{program="web",signal="9"} 1
+# HELP chay_program_backoff_seconds_total Time the program spent in backoff.
+# TYPE chay_program_backoff_seconds_total counter
+chay_program_backoff_seconds_total{program="web"} 1.5
+# HELP chay_program_transitions_total Number of state transitions of the program.
+# TYPE chay_program_transitions_total counter
+chay_program_transitions_total{program="web",from="backoff",to="starting"} 2
+chay_program_transitions_total{program="web",from="running",to="backoff"} 2
+chay_program_transitions_total{program="web",from="starting",to="running"} 1
+"#
+        );
+    }
+
+    #[test]
+    fn counts_the_current_backoff() {
+        let mut metrics = ProgramFsmMetrics::default();
+        metrics.observe_transition(&ProgramState::Running, &ProgramState::Backoff);
+        metrics.backoff_start_time =
+            Some(std::time::Instant::now() - std::time::Duration::from_secs(2));
+        assert!(metrics.backoff_duration() >= std::time::Duration::from_secs(2));
+        metrics.observe_transition(&ProgramState::Backoff, &ProgramState::Starting);
+        assert!(metrics.backoff_start_time.is_none());
+        let backoff_duration = metrics.backoff_duration();
+        assert!(backoff_duration >= std::time::Duration::from_secs(2));
+        // Only the ongoing backoff grows.
+        assert_eq!(metrics.backoff_duration(), backoff_duration);
+        assert_eq!(metrics.num_restarts(), 1);
+    }
+}
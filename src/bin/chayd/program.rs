@@ -1,6 +1,114 @@
+use crate::program_console::{OutputSinks, ProgramConsole};
 use nix::sys::signal::Signal;
 use nix::unistd::Pid;
 use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+/// Parses a signal name with or without the "SIG" prefix (e.g. "HUP" or "SIGHUP"), or a number.
+pub fn parse_signal(signal: &str) -> Result<Signal, String> {
+    if let Ok(signal_number) = signal.parse::<i32>() {
+        return Signal::try_from(signal_number)
+            .map_err(|_| format!("Invalid signal number: {signal}"));
+    }
+    let signal_name = signal.to_uppercase();
+    let signal_name = if signal_name.starts_with("SIG") {
+        signal_name
+    } else {
+        format!("SIG{signal_name}")
+    };
+    signal_name
+        .parse()
+        .map_err(|_| format!("Invalid signal: {signal}"))
+}
+
+#[derive(Clone, Debug)]
+pub struct ProgramExit {
+    pub status: std::process::ExitStatus,
+    /// Time the exit was first observed, which may be up to one FSM update after the actual exit.
+    pub time: std::time::SystemTime,
+    /// Resources used by the process and its reaped descendants, from wait4. Unset for processes
+    /// that weren't waited for by a Program.
+    pub rusage: Option<ExitRusage>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExitRusage {
+    pub user_cpu_time: std::time::Duration,
+    pub system_cpu_time: std::time::Duration,
+    pub max_rss_bytes: u64,
+}
+
+impl ExitRusage {
+    fn from_rusage(rusage: &nix::libc::rusage) -> Self {
+        let timeval_duration = |timeval: &nix::libc::timeval| {
+            std::time::Duration::new(timeval.tv_sec as u64, timeval.tv_usec as u32 * 1000)
+        };
+        Self {
+            user_cpu_time: timeval_duration(&rusage.ru_utime),
+            system_cpu_time: timeval_duration(&rusage.ru_stime),
+            // In kilobytes on Linux.
+            max_rss_bytes: rusage.ru_maxrss as u64 * 1024,
+        }
+    }
+}
+
+/// Switches the child process to the user and the user's groups between fork and exec.
+fn configure_user(command: &mut std::process::Command, user_name: &str) -> std::io::Result<()> {
+    let user = nix::unistd::User::from_name(user_name)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("User not found: {user_name}"),
+        )
+    })?;
+    let (uid, gid) = (user.uid, user.gid);
+    // Looked up before forking, since reading the group database isn't async-signal-safe. Only
+    // async-signal-safe calls may be made between fork and exec, as another thread of chayd may
+    // hold a lock, e.g. of malloc, that the child process would then wait for forever.
+    let groups = nix::unistd::getgrouplist(&std::ffi::CString::new(user_name)?, gid)?;
+    unsafe {
+        command.pre_exec(move || {
+            nix::unistd::setgroups(&groups)?;
+            nix::unistd::setgid(gid)?;
+            nix::unistd::setuid(uid)?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// What a program's process gets from its config besides its command and args. Also given to
+/// commands run in the program's context with Exec.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessContext {
+    pub env: std::collections::BTreeMap<String, String>,
+    pub directory: Option<String>,
+    pub user: Option<String>,
+    /// Set in the child process before it execs.
+    pub rlimits: Vec<crate::program_rlimits::Rlimit>,
+}
+
+impl ProcessContext {
+    /// Sets up the command's environment, working directory, rlimits, cgroup and user.
+    pub fn configure(
+        &self,
+        command: &mut std::process::Command,
+        cgroup: Option<&crate::program_cgroup::ProgramCgroup>,
+    ) -> std::io::Result<()> {
+        command.envs(&self.env);
+        if let Some(directory) = &self.directory {
+            command.current_dir(directory);
+        }
+        crate::program_rlimits::configure_rlimits(command, &self.rlimits);
+        if let Some(cgroup) = cgroup {
+            cgroup.configure(command)?;
+        }
+        // Last, since the child process can't raise its hard rlimits once it switched users.
+        if let Some(user) = &self.user {
+            configure_user(command, user)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct Program {
@@ -8,6 +116,23 @@ pub struct Program {
     pub command: String,
     pub args: Option<Vec<String>>,
     pub child_proc: Option<std::process::Child>,
+    /// Time the current (or most recent) child process was spawned.
+    pub start_time: Option<std::time::SystemTime>,
+    /// Exit of the most recent child process that has exited. Kept across restarts.
+    pub last_exit: Option<ProgramExit>,
+    /// Whether the exit of the current child process has been recorded in last_exit.
+    exit_observed: bool,
+    /// Number of child processes that exited with each exit code, or were killed by each signal.
+    pub exit_code_counts: std::collections::BTreeMap<i32, u64>,
+    pub exit_signal_counts: std::collections::BTreeMap<i32, u64>,
+    /// Set for attachable, tailable or pty programs and for jobs, but not their sidecars. Their
+    /// output goes through chayd so it can be tailed, and their stdin too if they are attachable.
+    pub console: Option<ProgramConsole>,
+    /// Sampled on every FSM update while the child process is running.
+    pub usage: crate::program_usage::ProgramUsage,
+    pub context: ProcessContext,
+    /// Set if chayd.cgroup_parent is configured. Holds the child process and its descendants.
+    pub cgroup: Option<crate::program_cgroup::ProgramCgroup>,
 }
 
 impl Program {
@@ -17,6 +142,23 @@ impl Program {
             command,
             args,
             child_proc: None,
+            start_time: None,
+            last_exit: None,
+            exit_observed: false,
+            exit_code_counts: Default::default(),
+            exit_signal_counts: Default::default(),
+            console: None,
+            usage: Default::default(),
+            context: Default::default(),
+            cgroup: None,
+        }
+    }
+
+    /// Returns the pid of the child process if it is running, as of the last exit status check.
+    pub fn pid(&self) -> Option<u32> {
+        match &self.child_proc {
+            Some(child_proc) if !self.exit_observed => Some(child_proc.id()),
+            _ => None,
         }
     }
 
@@ -33,7 +175,16 @@ impl Program {
         if pipe_stdin {
             command.stdin(std::process::Stdio::piped());
         }
-        if let Some(parent_proc) = parent_proc {
+        self.context.configure(&mut command, self.cgroup.as_ref())?;
+        let mut console_stdio = None;
+        if let Some(console) = &self.console {
+            // NOTE: This will panic if parent_proc's stdin was not piped.
+            let parent_stdin = parent_proc.map(|parent_proc| parent_proc.stdin.take().unwrap());
+            console_stdio = Some((
+                console.configure_stdio(&mut command)?,
+                OutputSinks::new(parent_stdin)?,
+            ));
+        } else if let Some(parent_proc) = parent_proc {
             // NOTE: This will panic if parent_proc's stdin was not piped.
             let parent_stdin = parent_proc.stdin.take().unwrap();
             command.stderr(unsafe {
@@ -43,31 +194,52 @@ impl Program {
             });
             command.stdout(parent_stdin);
         }
-        match command.spawn() {
-            Ok(child_proc) => {
+        let spawn_result = command.spawn();
+        // Close chayd's copies of the child's ends of the pipes or pty, so reading the output
+        // ends when the child exits.
+        drop(command);
+        match spawn_result {
+            Ok(mut child_proc) => {
+                if let (Some(console), Some((console_stdio, output_sinks))) =
+                    (&self.console, console_stdio)
+                {
+                    console.connect(&self.name, &mut child_proc, console_stdio, output_sinks);
+                }
                 self.child_proc.replace(child_proc);
+                self.start_time = Some(std::time::SystemTime::now());
+                self.exit_observed = false;
                 Ok(())
             }
             Err(error) => Err(error),
         }
     }
 
+    /// Fails with ESRCH if the program has not been started or has already exited.
     pub fn send_signal(&self, signal: Signal) -> nix::Result<()> {
-        let child_proc = self.child_proc.as_ref().unwrap_or_else(|| {
-            panic!("Program::send_signal called while not running");
-        });
-        let pid = Pid::from_raw(child_proc.id() as i32);
+        let pid = match self.pid() {
+            Some(pid) => Pid::from_raw(pid as i32),
+            None => return Err(nix::errno::Errno::ESRCH),
+        };
         nix::sys::signal::kill(pid, signal)
     }
 
     pub fn reset_child_proc(&mut self) {
         self.reap();
+        if let Some(cgroup) = &self.cgroup {
+            // Processes left behind by the exited child process, e.g. ones that daemonized.
+            if self.child_proc.is_some() && cgroup.is_populated() {
+                log::warn!("Killing processes left behind by {}", self.name);
+                if let Err(error) = cgroup.kill() {
+                    log::error!("Could not kill the cgroup of {}: {}", self.name, error);
+                }
+            }
+        }
         self.child_proc = None;
     }
 
     pub fn is_running(&mut self) -> bool {
-        if let Some(child_proc) = &mut self.child_proc {
-            return match child_proc.try_wait() {
+        if self.child_proc.is_some() {
+            return match self.try_wait() {
                 // If the ExitStatus is None, that means the exit status is not yet ready. This
                 // should only happen if the process is still running.
                 Ok(None) => true,
@@ -80,17 +252,46 @@ impl Program {
     // Returns the exit status without checking if the process been started.
     // Panics if the process has not yet been started.
     pub fn exit_status_unchecked(&mut self) -> std::io::Result<Option<std::process::ExitStatus>> {
-        self.child_proc.as_mut().unwrap().try_wait()
+        self.try_wait()
     }
 
     pub fn reap(&mut self) {
-        if let Some(child_proc) = &mut self.child_proc {
-            match child_proc.try_wait() {
-                Ok(None) => {
-                    panic!("Reaped running program: {}", self.name);
+        if self.child_proc.is_some() {
+            if let Ok(None) = self.try_wait() {
+                panic!("Reaped running program: {}", self.name);
+            }
+        }
+    }
+
+    /// Same as Child::try_wait, but records the exit the first time it is observed. Waits with
+    /// wait4 to get the rusage, so the exit status is kept here since the Child can't wait for the
+    /// reaped process again.
+    /// Panics if the process has not yet been started.
+    fn try_wait(&mut self) -> std::io::Result<Option<std::process::ExitStatus>> {
+        let pid = self.child_proc.as_ref().unwrap().id() as nix::libc::pid_t;
+        if self.exit_observed {
+            return Ok(self.last_exit.as_ref().map(|last_exit| last_exit.status));
+        }
+        let mut status = 0;
+        let mut rusage = unsafe { std::mem::zeroed::<nix::libc::rusage>() };
+        match unsafe { nix::libc::wait4(pid, &mut status, nix::libc::WNOHANG, &mut rusage) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            0 => Ok(None),
+            _ => {
+                let exit_status = std::process::ExitStatus::from_raw(status);
+                self.exit_observed = true;
+                if let Some(code) = exit_status.code() {
+                    *self.exit_code_counts.entry(code).or_default() += 1;
+                } else if let Some(signal) = exit_status.signal() {
+                    *self.exit_signal_counts.entry(signal).or_default() += 1;
                 }
-                Ok(Some(_)) | Err(_) => (),
-            };
+                self.last_exit = Some(ProgramExit {
+                    status: exit_status,
+                    time: std::time::SystemTime::now(),
+                    rusage: Some(ExitRusage::from_rusage(&rusage)),
+                });
+                Ok(Some(exit_status))
+            }
         }
     }
 }
@@ -100,7 +301,16 @@ impl Drop for Program {
         // This is not ideal, but it will at least ensure tha tno matter what we always kill child
         // processes when we exit in the case of a panic, etc.
         if let Some(child_proc) = &mut self.child_proc {
+            // The pid may have been reused since the process was reaped.
+            if self.exit_observed {
+                return;
+            }
             log::error!("Force-killing child proc on drop: {}", self.name);
+            if let Some(cgroup) = &self.cgroup {
+                if cgroup.kill().is_ok() {
+                    return;
+                }
+            }
             match child_proc.kill() {
                 Ok(_) => (),
                 Err(_) => (),
@@ -108,3 +318,86 @@ impl Drop for Program {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn wait_for_exit(program: &mut Program) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while program.is_running() {
+            assert!(std::time::Instant::now() < deadline, "Never exited");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn records_exits() {
+        let mut program = Program::new(
+            "exits".to_string(),
+            "sh".to_string(),
+            Some(vec!["-c".to_string(), "exit 3".to_string()]),
+        );
+        program.start(false, None).unwrap();
+        wait_for_exit(&mut program);
+        assert_eq!(program.exit_code_counts, BTreeMap::from([(3, 1)]));
+        assert!(program.last_exit.as_ref().unwrap().rusage.is_some());
+        // Each process is counted once, however often its exit is checked.
+        program.start(false, None).unwrap();
+        wait_for_exit(&mut program);
+        assert!(!program.is_running());
+        program.reset_child_proc();
+        assert_eq!(program.exit_code_counts, BTreeMap::from([(3, 2)]));
+        assert!(program.exit_signal_counts.is_empty());
+    }
+
+    #[test]
+    fn records_stopped_and_killed_exits() {
+        let mut program = Program::new(
+            "sleeps".to_string(),
+            "sleep".to_string(),
+            Some(vec!["10".to_string()]),
+        );
+        for signal in [Signal::SIGTERM, Signal::SIGKILL] {
+            program.start(false, None).unwrap();
+            crate::program_context::send_signal_to_program_if_running(&mut program, signal);
+            wait_for_exit(&mut program);
+            let last_exit = program.last_exit.as_ref().unwrap();
+            assert_eq!(last_exit.status.signal(), Some(signal as i32));
+            assert!(last_exit.rusage.is_some());
+        }
+        program.reset_child_proc();
+        assert_eq!(
+            program.exit_signal_counts,
+            BTreeMap::from([(Signal::SIGTERM as i32, 1), (Signal::SIGKILL as i32, 1)])
+        );
+        assert!(program.exit_code_counts.is_empty());
+    }
+
+    #[test]
+    fn switches_to_the_user_and_its_groups() {
+        // Only root can switch users.
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let context = ProcessContext {
+            user: Some("nobody".to_string()),
+            ..Default::default()
+        };
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "id -u; id -G"]);
+        context.configure(&mut command, None).unwrap();
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let nobody = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+        let groups =
+            nix::unistd::getgrouplist(&std::ffi::CString::new("nobody").unwrap(), nobody.gid)
+                .unwrap();
+        let groups: Vec<String> = groups.iter().map(|gid| gid.to_string()).collect();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("{}\n{}\n", nobody.uid, groups.join(" "))
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::CgroupConfig;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+
+/// Controllers the limits of a CgroupConfig need. Enabled for the children of chayd.cgroup_parent
+/// when chayd starts, if the kernel provides them.
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// Checks that chayd.cgroup_parent is a cgroup v2 that programs can be put under, and enables the
+/// controllers for its children. Creates it if it doesn't exist yet.
+pub fn prepare_cgroup_parent(parent: &std::path::Path) -> Result<(), String> {
+    let describe = |error: std::io::Error| format!("{}: {error}", parent.display());
+    std::fs::create_dir_all(parent).map_err(describe)?;
+    let available_controllers = std::fs::read_to_string(parent.join("cgroup.controllers"))
+        .map_err(|_| format!("{} is not a cgroup v2 directory", parent.display()))?;
+    // Controllers can only be enabled for the children of cgroups without processes.
+    let procs = std::fs::read_to_string(parent.join("cgroup.procs")).map_err(describe)?;
+    if !procs.trim().is_empty() {
+        return Err(format!(
+            "{} contains processes, which cgroup v2 doesn't allow in a cgroup whose children have \
+            controllers enabled. Use an empty cgroup, e.g. a child of the current one.",
+            parent.display()
+        ));
+    }
+    let subtree_control = parent.join("cgroup.subtree_control");
+    for controller in CONTROLLERS {
+        if !available_controllers
+            .split_whitespace()
+            .any(|available_controller| available_controller == controller)
+        {
+            log::warn!(
+                "The {} controller is not available in {}, limits that need it will fail",
+                controller,
+                parent.display()
+            );
+            continue;
+        }
+        std::fs::write(&subtree_control, format!("+{controller}")).map_err(|error| {
+            format!(
+                "Could not enable the {controller} controller in {}: {error}",
+                subtree_control.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// A cgroup v2 holding a program's process and all of its descendants, so they can be limited and
+/// killed together.
+#[derive(Debug)]
+pub struct ProgramCgroup {
+    path: std::path::PathBuf,
+    config: CgroupConfig,
+}
+
+impl ProgramCgroup {
+    pub fn new(parent: &std::path::Path, name: &str, config: CgroupConfig) -> Self {
+        Self {
+            path: parent.join(name),
+            config,
+        }
+    }
+
+    /// Each limit that is set, as the controller it needs, its interface file and its value.
+    fn limits(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut limits = vec![];
+        if let Some(memory_max) = &self.config.memory_max {
+            limits.push(("memory", "memory.max", memory_max.clone()));
+        }
+        if let Some(cpu_max) = &self.config.cpu_max {
+            limits.push(("cpu", "cpu.max", cpu_max.clone()));
+        }
+        if let Some(pids_max) = &self.config.pids_max {
+            limits.push(("pids", "pids.max", pids_max.clone()));
+        }
+        if let Some(io_weight) = self.config.io_weight {
+            limits.push(("io", "io.weight", io_weight.to_string()));
+        }
+        limits
+    }
+
+    fn write(&self, file_name: &str, value: &str) -> std::io::Result<()> {
+        let path = self.path.join(file_name);
+        std::fs::write(&path, value).map_err(|error| {
+            std::io::Error::new(
+                error.kind(),
+                format!("Could not write {value:?} to {}: {error}", path.display()),
+            )
+        })
+    }
+
+    /// Creates the cgroup and writes its limits, and moves the child process into it between fork
+    /// and exec, so none of its descendants can escape it. The controllers were enabled by
+    /// prepare_cgroup_parent.
+    pub fn configure(&self, command: &mut std::process::Command) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.path)?;
+        let limits = self.limits();
+        if !limits.is_empty() {
+            let enabled_controllers =
+                std::fs::read_to_string(self.path.join("cgroup.controllers"))?;
+            for (controller, _, _) in &limits {
+                if !enabled_controllers
+                    .split_whitespace()
+                    .any(|enabled_controller| enabled_controller == *controller)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!(
+                            "The {controller} controller is not available in {}",
+                            self.path.display()
+                        ),
+                    ));
+                }
+            }
+        }
+        for (_, file_name, value) in &limits {
+            self.write(file_name, value)?;
+        }
+        // Opened before forking, since opening a path allocates. The file is closed on exec.
+        let cgroup_procs = std::fs::OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))?;
+        unsafe {
+            command.pre_exec(move || {
+                // Writing 0 moves the writing process.
+                nix::unistd::write(cgroup_procs.as_raw_fd(), b"0")?;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether any process is left in the cgroup.
+    pub fn is_populated(&self) -> bool {
+        std::fs::read_to_string(self.path.join("cgroup.events"))
+            .map(|events| events.lines().any(|line| line == "populated 1"))
+            .unwrap_or(false)
+    }
+
+    /// Sends SIGKILL to every process in the cgroup. Unlike signalling them by pid, this doesn't
+    /// race with processes forking, since those are killed too.
+    pub fn kill(&self) -> std::io::Result<()> {
+        self.write("cgroup.kill", "1")
+    }
+}
+
+impl Drop for ProgramCgroup {
+    fn drop(&mut self) {
+        // Fails if processes are still in the cgroup, or if another program with the same name now
+        // uses it, which is fine.
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_cgroup_parents_outside_cgroup2() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroup_parent = dir.path().join("chayd");
+        assert_eq!(
+            prepare_cgroup_parent(&cgroup_parent).unwrap_err(),
+            format!("{} is not a cgroup v2 directory", cgroup_parent.display())
+        );
+    }
+}
@@ -0,0 +1,544 @@
+use crate::auth::Permissions;
+use crate::config::{Config, ProgramConfig, RenderedProgramConfig};
+use crate::program_fsm::{
+    new_program_fsm, ProgramEvent, ProgramFsm, ProgramState, ProgramTransition,
+};
+use crate::program_selector::SelectorTarget;
+use crate::request_error::RequestError;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub type ProgramChangeResult = Result<(), RequestError>;
+
+pub enum ProgramChange {
+    Add(ProgramConfig),
+    /// Stops the program and replaces it with one using the new config.
+    Update(ProgramConfig),
+    /// Stops the program and removes it.
+    Remove,
+}
+
+impl ProgramChange {
+    /// The auth action the client needs for the change.
+    fn action(&self) -> &'static str {
+        match self {
+            ProgramChange::Add(_) => crate::auth::ADD_PROGRAM_ACTION,
+            ProgramChange::Update(_) => crate::auth::UPDATE_PROGRAM_ACTION,
+            ProgramChange::Remove => crate::auth::REMOVE_PROGRAM_ACTION,
+        }
+    }
+}
+
+/// A change to the set of supervised programs, along with the channel to send the result back on.
+pub struct ProgramChangeRequest {
+    pub program_name: String,
+    pub change: ProgramChange,
+    pub permissions: Permissions,
+    pub results_sender: tokio::sync::mpsc::Sender<ProgramChangeResult>,
+}
+
+pub type ConfigReloadResult = Result<ConfigChanges, RequestError>;
+
+/// A request to re-read the config file, along with the channel to send the result back on.
+pub struct ConfigReloadRequest {
+    pub results_sender: tokio::sync::mpsc::Sender<ConfigReloadResult>,
+}
+
+/// Names of the programs changed by a config reload.
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    pub added_programs: Vec<String>,
+    pub removed_programs: Vec<String>,
+    pub changed_programs: Vec<String>,
+}
+
+/// A program that is being stopped before it is removed or replaced.
+struct PendingProgramChange {
+    /// The config to replace the program with once stopped. The program is removed if None.
+    new_config: Option<RenderedProgramConfig>,
+    /// Whether to start the replacement program, because the program was running when the change
+    /// was requested.
+    start_after_replace: bool,
+    results_sender: Option<tokio::sync::mpsc::Sender<ProgramChangeResult>>,
+}
+
+/// Adds, updates and removes programs while chayd is running. Programs are always stopped before
+/// being removed or replaced, which can take several FSM updates.
+pub struct ProgramChanges {
+    program_transitions_tx: tokio::sync::broadcast::Sender<ProgramTransition>,
+    pending_changes: HashMap<String, PendingProgramChange>,
+    /// Programs added, updated or removed over RPC, which config reloads leave alone until chayd
+    /// restarts.
+    rpc_changed_programs: HashSet<String>,
+}
+
+fn render_program_config(
+    config: &Config,
+    program_name: &str,
+    program_config: &ProgramConfig,
+) -> Result<RenderedProgramConfig, RequestError> {
+    RenderedProgramConfig::new(config, program_name, program_config).map_err(|error| {
+        RequestError::new(
+            tonic::Code::InvalidArgument,
+            format!(
+                "Invalid config: {}",
+                crate::config::render_error_message(error.as_ref())
+            ),
+        )
+    })
+}
+
+fn authorize(
+    permissions: &Permissions,
+    action: &str,
+    target: &SelectorTarget,
+) -> Result<(), RequestError> {
+    if !permissions.is_allowed(action, target) {
+        return Err(RequestError::new(
+            tonic::Code::PermissionDenied,
+            permissions.denied_message(action),
+        ));
+    }
+    Ok(())
+}
+
+fn find_program_fsm<'a>(
+    program_fsms: &'a mut [ProgramFsm],
+    program_name: &str,
+) -> Option<&'a mut ProgramFsm> {
+    program_fsms
+        .iter_mut()
+        .find(|program_fsm| program_fsm.app_context().name == program_name)
+}
+
+impl ProgramChanges {
+    pub fn new(program_transitions_tx: tokio::sync::broadcast::Sender<ProgramTransition>) -> Self {
+        Self {
+            program_transitions_tx,
+            pending_changes: HashMap::new(),
+            rpc_changed_programs: HashSet::new(),
+        }
+    }
+
+    pub fn is_pending(&self, program_name: &str) -> bool {
+        self.pending_changes.contains_key(program_name)
+    }
+
+    /// Adds the program right away. Updates and removals are applied by later calls to
+    /// apply_pending_changes once the program has stopped, and the result is only sent then.
+    pub async fn handle_request(
+        &mut self,
+        config: &Config,
+        program_fsms: &mut Vec<ProgramFsm>,
+        request: ProgramChangeRequest,
+    ) {
+        let ProgramChangeRequest {
+            program_name,
+            change,
+            permissions,
+            results_sender,
+        } = request;
+        let result = self.request_change(config, program_fsms, &program_name, change, &permissions);
+        match result {
+            Ok(true) => {
+                self.pending_changes
+                    .get_mut(&program_name)
+                    .unwrap()
+                    .results_sender = Some(results_sender)
+            }
+            Ok(false) | Err(_) => match results_sender.send(result.map(|_| ())).await {
+                Ok(_) => {}
+                // The connection was probably closed by the client.
+                Err(_) => log::warn!("Could not send program change result"),
+            },
+        }
+    }
+
+    /// Returns whether the change is pending until the program has stopped.
+    fn request_change(
+        &mut self,
+        config: &Config,
+        program_fsms: &mut Vec<ProgramFsm>,
+        program_name: &str,
+        change: ProgramChange,
+        permissions: &Permissions,
+    ) -> Result<bool, RequestError> {
+        let action = change.action();
+        // The client must be allowed to change the program both as it is and as it will be, so
+        // that it can't take over a program by giving it labels or groups it may change.
+        if let ProgramChange::Add(program_config) | ProgramChange::Update(program_config) = &change
+        {
+            authorize(
+                permissions,
+                action,
+                &SelectorTarget::from_program_config(program_name, program_config),
+            )?;
+        }
+        if let ProgramChange::Update(_) | ProgramChange::Remove = &change {
+            // A missing program is only known by name, so that a client can't find out about
+            // programs it may not change.
+            let target = match find_program_fsm(program_fsms, program_name) {
+                Some(program_fsm) => SelectorTarget::from_program_fsm(program_fsm),
+                None => SelectorTarget::from_name(program_name),
+            };
+            authorize(permissions, action, &target)?;
+        }
+        if self.is_pending(program_name) {
+            return Err(RequestError::new(
+                tonic::Code::FailedPrecondition,
+                format!("Program is already being changed: {program_name}"),
+            ));
+        }
+        let program_exists = find_program_fsm(program_fsms, program_name).is_some();
+        let new_config = match change {
+            ProgramChange::Add(program_config) => {
+                if program_exists {
+                    return Err(RequestError::new(
+                        tonic::Code::AlreadyExists,
+                        format!("Program already exists: {program_name}"),
+                    ));
+                }
+                let rendered_config = render_program_config(config, program_name, &program_config)?;
+                self.add(program_fsms, program_name, &rendered_config);
+                self.rpc_changed_programs.insert(program_name.to_string());
+                return Ok(false);
+            }
+            ProgramChange::Update(program_config) => Some(render_program_config(
+                config,
+                program_name,
+                &program_config,
+            )?),
+            ProgramChange::Remove => None,
+        };
+        if !program_exists {
+            return Err(RequestError::new(
+                tonic::Code::NotFound,
+                format!("Program not found: {program_name}"),
+            ));
+        }
+        self.stop_and_replace(program_fsms, program_name, new_config, None);
+        self.rpc_changed_programs.insert(program_name.to_string());
+        Ok(true)
+    }
+
+    pub fn add(
+        &mut self,
+        program_fsms: &mut Vec<ProgramFsm>,
+        program_name: &str,
+        rendered_config: &RenderedProgramConfig,
+    ) {
+        log::info!("Adding program {}", program_name);
+        program_fsms.push(new_program_fsm(
+            program_name.to_string(),
+            rendered_config,
+            self.program_transitions_tx.clone(),
+        ));
+        // Keep the programs sorted by name like they are when read from the config file.
+        program_fsms.sort_by_key(|program_fsm| program_fsm.app_context().name());
+    }
+
+    /// Stops the program, then replaces it with one using the new config, or removes it if there
+    /// is no new config. The replacement is started again if the program is running now. The
+    /// results_sender, if any, is notified once done.
+    pub fn stop_and_replace(
+        &mut self,
+        program_fsms: &mut [ProgramFsm],
+        program_name: &str,
+        new_config: Option<RenderedProgramConfig>,
+        results_sender: Option<tokio::sync::mpsc::Sender<ProgramChangeResult>>,
+    ) {
+        // A change that is already pending is superseded, but its client still gets notified.
+        let (mut start_after_replace, results_sender) =
+            match self.pending_changes.remove(program_name) {
+                Some(pending_change) => (
+                    pending_change.start_after_replace,
+                    results_sender.or(pending_change.results_sender),
+                ),
+                None => (false, results_sender),
+            };
+        if let Some(program_fsm) = find_program_fsm(program_fsms, program_name) {
+            start_after_replace |= !matches!(
+                program_fsm.current_state_key(),
+                ProgramState::Stopped | ProgramState::Exited
+            );
+            log::info!("Stopping program {} to change it", program_name);
+            let _ = program_fsm.react(&ProgramEvent::Stop);
+        }
+        self.pending_changes.insert(
+            program_name.to_string(),
+            PendingProgramChange {
+                new_config,
+                start_after_replace,
+                results_sender,
+            },
+        );
+    }
+
+    /// Adds, removes and replaces programs to match the newly rendered config. Programs whose
+    /// config didn't change are left alone, and so are programs changed over RPC, so that a reload
+    /// doesn't undo them.
+    pub fn apply_config(
+        &mut self,
+        program_fsms: &mut Vec<ProgramFsm>,
+        rendered_config: &BTreeMap<String, RenderedProgramConfig>,
+    ) -> ConfigChanges {
+        let mut config_changes = ConfigChanges::default();
+        let running_configs: BTreeMap<String, RenderedProgramConfig> = program_fsms
+            .iter()
+            .map(|program_fsm| {
+                let program_ctx = program_fsm.app_context();
+                (program_ctx.name(), program_ctx.config.clone())
+            })
+            .collect();
+        for (program_name, running_config) in &running_configs {
+            if self.rpc_changed_programs.contains(program_name) {
+                log::info!(
+                    "Leaving program {} alone, it was changed over RPC",
+                    program_name
+                );
+                continue;
+            }
+            match rendered_config.get(program_name) {
+                Some(new_config) if new_config == running_config => {}
+                Some(new_config) => {
+                    self.stop_and_replace(
+                        program_fsms,
+                        program_name,
+                        Some(new_config.clone()),
+                        None,
+                    );
+                    config_changes.changed_programs.push(program_name.clone());
+                }
+                None => {
+                    self.stop_and_replace(program_fsms, program_name, None, None);
+                    config_changes.removed_programs.push(program_name.clone());
+                }
+            }
+        }
+        for (program_name, new_config) in rendered_config {
+            if !running_configs.contains_key(program_name)
+                && !self.rpc_changed_programs.contains(program_name)
+            {
+                self.add(program_fsms, program_name, new_config);
+                config_changes.added_programs.push(program_name.clone());
+            }
+        }
+        config_changes
+    }
+
+    /// Removes or replaces every program with a pending change that has finished stopping.
+    pub async fn apply_pending_changes(&mut self, program_fsms: &mut Vec<ProgramFsm>) {
+        let mut applied_changes = vec![];
+        for (program_name, pending_change) in &self.pending_changes {
+            let program_fsm = match find_program_fsm(program_fsms, program_name) {
+                Some(program_fsm) => program_fsm,
+                None => {
+                    applied_changes.push(program_name.clone());
+                    continue;
+                }
+            };
+            match program_fsm.current_state_key() {
+                ProgramState::Stopped | ProgramState::Exited => (),
+                ProgramState::Stopping | ProgramState::Exiting => continue,
+                // A client started the program again before it stopped.
+                _ => {
+                    let _ = program_fsm.react(&ProgramEvent::Stop);
+                    continue;
+                }
+            }
+            program_fsms.retain(|program_fsm| &program_fsm.app_context().name != program_name);
+            match &pending_change.new_config {
+                Some(new_config) => {
+                    log::info!("Replacing program {}", program_name);
+                    let mut replacement_fsm = new_program_fsm(
+                        program_name.clone(),
+                        new_config,
+                        self.program_transitions_tx.clone(),
+                    );
+                    if pending_change.start_after_replace
+                        && replacement_fsm.current_state_key() == ProgramState::Stopped
+                    {
+                        let _ = replacement_fsm.react(&ProgramEvent::Start);
+                    }
+                    program_fsms.push(replacement_fsm);
+                    program_fsms.sort_by_key(|program_fsm| program_fsm.app_context().name());
+                }
+                None => log::info!("Removed program {}", program_name),
+            }
+            applied_changes.push(program_name.clone());
+        }
+        for program_name in applied_changes {
+            let pending_change = self.pending_changes.remove(&program_name).unwrap();
+            if let Some(results_sender) = pending_change.results_sender {
+                match results_sender.send(Ok(())).await {
+                    Ok(_) => {}
+                    // The connection was probably closed by the client.
+                    Err(_) => log::warn!("Could not send program change result"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{
+        Authenticator, ADD_PROGRAM_ACTION, REMOVE_PROGRAM_ACTION, UPDATE_PROGRAM_ACTION,
+    };
+    use crate::config::{AuthConfig, AuthRuleConfig};
+
+    const CONFIG: &str = r#"
+        [vars]
+        [loggers]
+        [programs.db]
+        command = "true"
+        autostart = false
+        labels = { tier = "db" }
+        [programs.web]
+        command = "true"
+        autostart = false
+        labels = { tier = "web" }
+    "#;
+
+    /// Permissions of a client that may do the actions to the programs matching the expression.
+    fn rule_permissions(actions: &[&str], programs: &str) -> Permissions {
+        let auth_config = AuthConfig {
+            tokens: BTreeMap::from([("deploy".to_string(), "deploy-token".to_string())]),
+            unix_peer_credentials: false,
+            rules: vec![AuthRuleConfig {
+                identity: "deploy".to_string(),
+                actions: actions.iter().map(|action| action.to_string()).collect(),
+                programs: programs.to_string(),
+            }],
+        };
+        let mut request = tonic::Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer deploy-token".parse().unwrap());
+        Authenticator::new(Some(auth_config), false)
+            .unwrap()
+            .authenticate(&request)
+            .unwrap()
+    }
+
+    fn program_config(config: &Config, program_name: &str, tier: &str) -> ProgramConfig {
+        let mut program_config = config.programs[program_name].clone();
+        program_config.labels = BTreeMap::from([("tier".to_string(), tier.to_string())]);
+        program_config
+    }
+
+    fn program_changes_and_fsms(config: &Config) -> (ProgramChanges, Vec<ProgramFsm>) {
+        let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
+        let mut program_changes = ProgramChanges::new(program_transitions_tx);
+        let mut program_fsms = vec![];
+        for (program_name, program_config) in &config.programs {
+            let rendered_config = render_program_config(config, program_name, program_config);
+            program_changes.add(&mut program_fsms, program_name, &rendered_config.unwrap());
+        }
+        (program_changes, program_fsms)
+    }
+
+    fn error_code(result: Result<bool, RequestError>) -> tonic::Code {
+        tonic::Status::from(result.unwrap_err()).code()
+    }
+
+    #[test]
+    fn update_is_checked_against_the_current_and_new_config() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut program_changes, mut program_fsms) = program_changes_and_fsms(&config);
+        let permissions = rule_permissions(&[UPDATE_PROGRAM_ACTION], "label:tier=web");
+
+        // Relabelling db to tier=web must not let a client that may only update web programs
+        // take it over.
+        let change = ProgramChange::Update(program_config(&config, "db", "web"));
+        let result =
+            program_changes.request_change(&config, &mut program_fsms, "db", change, &permissions);
+        assert_eq!(error_code(result), tonic::Code::PermissionDenied);
+        // Nor may it move a web program out of its reach.
+        let change = ProgramChange::Update(program_config(&config, "web", "db"));
+        let result =
+            program_changes.request_change(&config, &mut program_fsms, "web", change, &permissions);
+        assert_eq!(error_code(result), tonic::Code::PermissionDenied);
+        assert!(!program_changes.is_pending("db"));
+        assert!(!program_changes.is_pending("web"));
+
+        let change = ProgramChange::Update(program_config(&config, "web", "web"));
+        program_changes
+            .request_change(&config, &mut program_fsms, "web", change, &permissions)
+            .unwrap();
+        assert!(program_changes.is_pending("web"));
+    }
+
+    #[test]
+    fn remove_is_checked_against_the_current_config() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut program_changes, mut program_fsms) = program_changes_and_fsms(&config);
+
+        let permissions = rule_permissions(&[REMOVE_PROGRAM_ACTION], "label:tier=web");
+        let result = program_changes.request_change(
+            &config,
+            &mut program_fsms,
+            "db",
+            ProgramChange::Remove,
+            &permissions,
+        );
+        assert_eq!(error_code(result), tonic::Code::PermissionDenied);
+        program_changes
+            .request_change(
+                &config,
+                &mut program_fsms,
+                "web",
+                ProgramChange::Remove,
+                &permissions,
+            )
+            .unwrap();
+        assert!(program_changes.is_pending("web"));
+
+        let permissions = rule_permissions(&[REMOVE_PROGRAM_ACTION], "!label:tier=db");
+        let result = program_changes.request_change(
+            &config,
+            &mut program_fsms,
+            "db",
+            ProgramChange::Remove,
+            &permissions,
+        );
+        assert_eq!(error_code(result), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn reload_leaves_programs_changed_over_rpc_alone() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (mut program_changes, mut program_fsms) = program_changes_and_fsms(&config);
+        let permissions = rule_permissions(&[ADD_PROGRAM_ACTION, UPDATE_PROGRAM_ACTION], "*");
+        let change = ProgramChange::Add(program_config(&config, "web", "cache"));
+        program_changes
+            .request_change(&config, &mut program_fsms, "cache", change, &permissions)
+            .unwrap();
+        let change = ProgramChange::Update(program_config(&config, "web", "web"));
+        program_changes
+            .request_change(&config, &mut program_fsms, "web", change, &permissions)
+            .unwrap();
+
+        // The config file doesn't have the added program and has another config for the updated
+        // one.
+        let mut file_config = config.clone();
+        file_config.programs.get_mut("web").unwrap().labels =
+            BTreeMap::from([("tier".to_string(), "front".to_string())]);
+        let rendered_config = crate::config::render(&file_config).unwrap();
+        let config_changes = program_changes.apply_config(&mut program_fsms, &rendered_config);
+        assert!(config_changes.added_programs.is_empty());
+        assert!(config_changes.removed_programs.is_empty());
+        assert!(config_changes.changed_programs.is_empty());
+        assert!(find_program_fsm(&mut program_fsms, "cache").is_some());
+        assert!(!program_changes.is_pending("cache"));
+        assert_eq!(
+            program_changes.pending_changes["web"]
+                .new_config
+                .as_ref()
+                .unwrap()
+                .program
+                .labels["tier"],
+            "web"
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use crate::auth::Permissions;
+use crate::program_fsm::ProgramFsm;
+use crate::program_selector::SelectorTarget;
+use crate::request_error::RequestError;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+
+/// Writes of at most PIPE_BUF (4096 bytes on Linux) to a pipe are atomic, so reading the output
+/// in chunks of this size keeps stdout and stderr from being interleaved mid-chunk in the
+/// logger's input.
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+/// Number of output chunks an attached or tailing client may fall behind before it misses some
+/// output.
+const OUTPUT_CHANNEL_CAPACITY: usize = 256;
+/// Only the end of a program's output is kept for clients that start tailing it.
+const MAX_TAIL_BYTES: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ConsoleStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsoleOutput {
+    pub stream: ConsoleStream,
+    pub data: Vec<u8>,
+}
+
+/// Where a program's output goes besides the attached clients.
+pub struct OutputSinks {
+    stdout: Box<dyn Write + Send>,
+    stderr: Box<dyn Write + Send>,
+}
+
+impl OutputSinks {
+    /// The logger's stdin if the program has a logger, otherwise chayd's own stdout and stderr,
+    /// same as for the program's pre_command and logger.
+    pub fn new(logger_stdin: Option<std::process::ChildStdin>) -> std::io::Result<Self> {
+        match logger_stdin {
+            Some(logger_stdin) => {
+                let logger_stdin = std::fs::File::from(OwnedFd::from(logger_stdin));
+                Ok(Self {
+                    stderr: Box::new(logger_stdin.try_clone()?),
+                    stdout: Box::new(logger_stdin),
+                })
+            }
+            None => Ok(Self {
+                stdout: Box::new(std::io::stdout()),
+                stderr: Box::new(std::io::stderr()),
+            }),
+        }
+    }
+}
+
+/// The end of a program's output, e.g. to show why a job failed.
+#[derive(Debug)]
+struct CapturedOutput {
+    data: Vec<u8>,
+    max_bytes: usize,
+}
+
+impl CapturedOutput {
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+        if self.data.len() > self.max_bytes {
+            let num_dropped = self.data.len() - self.max_bytes;
+            self.data.drain(..num_dropped);
+        }
+    }
+
+    /// The last num_lines lines, or everything if num_lines is 0. A last line that doesn't end
+    /// with a newline yet counts as a line.
+    fn last_lines(&self, num_lines: usize) -> &[u8] {
+        if num_lines == 0 {
+            return &self.data;
+        }
+        let end = self.data.len().saturating_sub(1);
+        let start = self.data[..end]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, byte)| **byte == b'\n')
+            .nth(num_lines - 1)
+            .map_or(0, |(index, _)| index + 1);
+        &self.data[start..]
+    }
+}
+
+/// What a child process's stdin is when it doesn't run in a pty.
+#[derive(Clone, Copy, Debug)]
+enum ConsoleInput {
+    /// Written to by attached clients.
+    Piped,
+    /// chayd's own stdin, same as for programs that don't have a console.
+    Inherited,
+    /// Nothing writes to the stdin of a job, so it gets EOF rather than waiting forever.
+    Null,
+}
+
+/// The parent's ends of a child process's stdio, set up before the child is spawned.
+pub enum ConsoleStdio {
+    Pipes,
+    /// The child's stdin, stdout and stderr are all the slave side of the pty.
+    Pty {
+        master_reader: std::fs::File,
+        master_writer: std::fs::File,
+    },
+}
+
+/// Keeps a pty fd from leaking into other child processes. The slave side is still passed to the
+/// child since Command dup2s it onto the child's stdio, which clears the flag.
+fn set_cloexec(fd: RawFd) -> nix::Result<()> {
+    nix::fcntl::fcntl(
+        fd,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )?;
+    Ok(())
+}
+
+fn set_window_size(
+    pty_master: &std::fs::File,
+    window_size: &nix::pty::Winsize,
+) -> std::io::Result<()> {
+    if unsafe { nix::libc::ioctl(pty_master.as_raw_fd(), nix::libc::TIOCSWINSZ, window_size) } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The stdio of an attachable, tailable or pty program, or of a job. chayd owns the program's
+/// stdout and stderr so clients can tail them, and its stdin too if the program is attachable.
+/// The output is forwarded to the program's usual destination as well as to every attached or
+/// tailing client. Kept across restarts of the program, so clients stay attached.
+#[derive(Clone, Debug)]
+pub struct ProgramConsole {
+    output_tx: tokio::sync::broadcast::Sender<ConsoleOutput>,
+    /// Stdin of the current child process. Shared so that a write blocked on a program that
+    /// doesn't read its stdin doesn't hold up starting the next child process.
+    stdin: std::sync::Arc<std::sync::Mutex<Option<std::sync::Arc<std::fs::File>>>>,
+    /// Set for programs run in a pty. Updated when an attached client resizes its terminal, so
+    /// the program keeps the size across restarts.
+    window_size: Option<std::sync::Arc<std::sync::Mutex<nix::pty::Winsize>>>,
+    input: ConsoleInput,
+    /// The end of the stdout and stderr, interleaved.
+    captured_output: std::sync::Arc<std::sync::Mutex<CapturedOutput>>,
+}
+
+impl ProgramConsole {
+    /// For attachable programs, which get a pty of the given size if set, pipes otherwise.
+    pub fn new(pty_size: Option<(u16, u16)>) -> Self {
+        Self::with_input(pty_size, ConsoleInput::Piped, MAX_TAIL_BYTES)
+    }
+
+    /// For programs that are tailable or run in a pty but aren't attachable, which keep chayd's
+    /// stdin unless they run in a pty.
+    pub fn output_only(pty_size: Option<(u16, u16)>) -> Self {
+        Self::with_input(pty_size, ConsoleInput::Inherited, MAX_TAIL_BYTES)
+    }
+
+    /// For jobs, which run with pipes and an empty stdin. The last max_bytes of their output are
+    /// kept after they exit.
+    pub fn capturing(max_bytes: usize) -> Self {
+        Self::with_input(None, ConsoleInput::Null, max_bytes)
+    }
+
+    fn with_input(pty_size: Option<(u16, u16)>, input: ConsoleInput, max_bytes: usize) -> Self {
+        let (output_tx, _) = tokio::sync::broadcast::channel(OUTPUT_CHANNEL_CAPACITY);
+        Self {
+            output_tx,
+            stdin: Default::default(),
+            window_size: pty_size.map(|(rows, cols)| {
+                std::sync::Arc::new(std::sync::Mutex::new(nix::pty::Winsize {
+                    ws_row: rows,
+                    ws_col: cols,
+                    ws_xpixel: 0,
+                    ws_ypixel: 0,
+                }))
+            }),
+            input,
+            captured_output: std::sync::Arc::new(std::sync::Mutex::new(CapturedOutput {
+                data: vec![],
+                max_bytes,
+            })),
+        }
+    }
+
+    /// Sets up the command's stdio to be owned by chayd, either as pipes or as a new pty. A
+    /// program in a pty gets its own session with the pty as its controlling terminal.
+    pub fn configure_stdio(
+        &self,
+        command: &mut std::process::Command,
+    ) -> std::io::Result<ConsoleStdio> {
+        let window_size = match &self.window_size {
+            Some(window_size) => *window_size.lock().unwrap(),
+            None => {
+                let stdin = match self.input {
+                    ConsoleInput::Piped => std::process::Stdio::piped(),
+                    ConsoleInput::Inherited => std::process::Stdio::inherit(),
+                    ConsoleInput::Null => std::process::Stdio::null(),
+                };
+                command
+                    .stdin(stdin)
+                    .stdout(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::piped());
+                return Ok(ConsoleStdio::Pipes);
+            }
+        };
+        let pty = nix::pty::openpty(Some(&window_size), None)?;
+        let master = unsafe { std::fs::File::from_raw_fd(pty.master) };
+        let slave = unsafe { std::fs::File::from_raw_fd(pty.slave) };
+        set_cloexec(master.as_raw_fd())?;
+        set_cloexec(slave.as_raw_fd())?;
+        command
+            .stdin(slave.try_clone()?)
+            .stdout(slave.try_clone()?)
+            .stderr(slave);
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                if nix::libc::ioctl(0, nix::libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(ConsoleStdio::Pty {
+            master_writer: master.try_clone()?,
+            master_reader: master,
+        })
+    }
+
+    /// Takes over the stdio of a child process spawned after configure_stdio. The output is read
+    /// on threads until the child process closes it.
+    pub fn connect(
+        &self,
+        program_name: &str,
+        child_proc: &mut std::process::Child,
+        console_stdio: ConsoleStdio,
+        output_sinks: OutputSinks,
+    ) {
+        if let ConsoleStdio::Pty {
+            master_reader,
+            master_writer,
+        } = console_stdio
+        {
+            *self.stdin.lock().unwrap() = Some(std::sync::Arc::new(master_writer));
+            // The pty merges stdout and stderr.
+            self.spawn_output_forwarder(
+                program_name,
+                master_reader,
+                output_sinks.stdout,
+                ConsoleStream::Stdout,
+            );
+            return;
+        }
+        *self.stdin.lock().unwrap() = child_proc
+            .stdin
+            .take()
+            .map(|stdin| std::sync::Arc::new(std::fs::File::from(OwnedFd::from(stdin))));
+        if let Some(stdout) = child_proc.stdout.take() {
+            self.spawn_output_forwarder(
+                program_name,
+                stdout,
+                output_sinks.stdout,
+                ConsoleStream::Stdout,
+            );
+        }
+        if let Some(stderr) = child_proc.stderr.take() {
+            self.spawn_output_forwarder(
+                program_name,
+                stderr,
+                output_sinks.stderr,
+                ConsoleStream::Stderr,
+            );
+        }
+    }
+
+    fn spawn_output_forwarder(
+        &self,
+        program_name: &str,
+        mut output: impl Read + Send + 'static,
+        mut sink: Box<dyn Write + Send>,
+        stream: ConsoleStream,
+    ) {
+        let program_name = program_name.to_string();
+        let output_tx = self.output_tx.clone();
+        let captured_output = self.captured_output.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; OUTPUT_CHUNK_SIZE];
+            let mut sink_is_open = true;
+            loop {
+                let num_read = match output.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(num_read) => num_read,
+                    Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                    // Reading a pty master fails with EIO rather than returning EOF once the
+                    // slave side is closed.
+                    Err(error) if error.raw_os_error() == Some(nix::libc::EIO) => break,
+                    Err(error) => {
+                        log::error!("Could not read {:?} of {}: {}", stream, program_name, error);
+                        break;
+                    }
+                };
+                let data = &buf[..num_read];
+                // Keep reading after the sink is closed, e.g. when the logger exited, so the
+                // program doesn't block on a full pipe until it is restarted.
+                if sink_is_open {
+                    if let Err(error) = sink.write_all(data).and_then(|_| sink.flush()) {
+                        log::warn!(
+                            "Could not forward {:?} of {}: {}",
+                            stream,
+                            program_name,
+                            error
+                        );
+                        sink_is_open = false;
+                    }
+                }
+                // Sent while holding the lock, so a client that starts tailing gets each chunk
+                // either in the captured output or from its receiver, but not both.
+                let mut captured_output = captured_output.lock().unwrap();
+                captured_output.append(data);
+                // Only fails if no client is attached or tailing.
+                let _ = output_tx.send(ConsoleOutput {
+                    stream,
+                    data: data.to_vec(),
+                });
+            }
+        });
+    }
+
+    pub fn captured_output(&self) -> Vec<u8> {
+        self.captured_output.lock().unwrap().data.clone()
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ConsoleOutput> {
+        self.output_tx.subscribe()
+    }
+
+    /// The last num_lines lines of the captured output (all of it if num_lines is 0), and a
+    /// receiver for the output that follows it.
+    pub fn tail(
+        &self,
+        num_lines: usize,
+    ) -> (Vec<u8>, tokio::sync::broadcast::Receiver<ConsoleOutput>) {
+        let captured_output = self.captured_output.lock().unwrap();
+        (
+            captured_output.last_lines(num_lines).to_vec(),
+            self.output_tx.subscribe(),
+        )
+    }
+
+    /// Resizes the program's pty, e.g. when an attached client's terminal is resized. Fails if
+    /// the program doesn't run in a pty.
+    pub fn resize(&self, rows: u16, cols: u16) -> std::io::Result<()> {
+        let window_size = match &self.window_size {
+            Some(window_size) => window_size,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Program doesn't run in a pty",
+                ))
+            }
+        };
+        let mut window_size = window_size.lock().unwrap();
+        window_size.ws_row = rows;
+        window_size.ws_col = cols;
+        match self.stdin.lock().unwrap().as_ref() {
+            Some(pty_master) => set_window_size(pty_master, &window_size),
+            // Applied when the program is started.
+            None => Ok(()),
+        }
+    }
+
+    /// Blocks until the data is written, or fails if the program isn't running.
+    pub fn write_stdin(&self, data: &[u8]) -> std::io::Result<()> {
+        let stdin = self.stdin.lock().unwrap().clone();
+        match stdin {
+            Some(stdin) => (&*stdin).write_all(data),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Program has not been started",
+            )),
+        }
+    }
+}
+
+pub type AttachResult = Result<ProgramConsole, RequestError>;
+
+pub struct AttachRequest {
+    pub program_name: String,
+    pub permissions: Permissions,
+    pub results_sender: tokio::sync::mpsc::Sender<AttachResult>,
+}
+
+pub type TailResult = Result<ProgramConsole, RequestError>;
+
+pub struct TailRequest {
+    pub program_name: String,
+    pub permissions: Permissions,
+    pub results_sender: tokio::sync::mpsc::Sender<TailResult>,
+}
+
+/// Finds the program, if the client is allowed to do the action to it.
+fn find_allowed_program_fsm<'a>(
+    mut program_fsms: impl Iterator<Item = &'a ProgramFsm>,
+    program_name: &str,
+    permissions: &Permissions,
+    action: &str,
+) -> Result<&'a ProgramFsm, RequestError> {
+    let program_fsm = program_fsms
+        .find(|program_fsm| program_fsm.app_context().name == program_name)
+        .ok_or_else(|| {
+            RequestError::new(
+                tonic::Code::NotFound,
+                format!("Program not found: {program_name}"),
+            )
+        })?;
+    if !permissions.is_allowed(action, &SelectorTarget::from_program_fsm(program_fsm)) {
+        return Err(RequestError::new(
+            tonic::Code::PermissionDenied,
+            permissions.denied_message(action),
+        ));
+    }
+    Ok(program_fsm)
+}
+
+/// Returns the console of the program if it exists, is attachable, and the client is allowed to
+/// attach to it.
+pub fn program_console(
+    program_fsms: &[ProgramFsm],
+    attach_request: &AttachRequest,
+) -> AttachResult {
+    let program_name = &attach_request.program_name;
+    let program_fsm = find_allowed_program_fsm(
+        program_fsms.iter(),
+        program_name,
+        &attach_request.permissions,
+        crate::auth::ATTACH_ACTION,
+    )?;
+    // Tailable and pty programs have a console too, but only attachable ones take input.
+    match &program_fsm.app_context().program.program.console {
+        Some(console) if program_fsm.app_context().config.program.attachable => Ok(console.clone()),
+        _ => Err(RequestError::new(
+            tonic::Code::FailedPrecondition,
+            format!(
+                "Program {program_name} is not attachable, set attachable = true in its config"
+            ),
+        )),
+    }
+}
+
+/// Returns the console of the program or running job if it exists and the client is allowed to
+/// tail its output.
+pub fn tailed_program_console<'a>(
+    program_fsms: impl Iterator<Item = &'a ProgramFsm>,
+    tail_request: &TailRequest,
+) -> TailResult {
+    let program_name = &tail_request.program_name;
+    let program_fsm = find_allowed_program_fsm(
+        program_fsms,
+        program_name,
+        &tail_request.permissions,
+        crate::auth::TAIL_ACTION,
+    )?;
+    match &program_fsm.app_context().program.program.console {
+        Some(console) => Ok(console.clone()),
+        None => Err(RequestError::new(
+            tonic::Code::FailedPrecondition,
+            format!("Program {program_name} is not tailable, set tailable = true in its config"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured_output(data: &[u8]) -> CapturedOutput {
+        CapturedOutput {
+            data: data.to_vec(),
+            max_bytes: MAX_TAIL_BYTES,
+        }
+    }
+
+    #[test]
+    fn last_lines() {
+        let output = captured_output(b"one\ntwo\nthree\n");
+        assert_eq!(output.last_lines(1), b"three\n");
+        assert_eq!(output.last_lines(2), b"two\nthree\n");
+        assert_eq!(output.last_lines(3), b"one\ntwo\nthree\n");
+        assert_eq!(output.last_lines(10), b"one\ntwo\nthree\n");
+        assert_eq!(output.last_lines(0), b"one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn last_lines_with_unfinished_line() {
+        let output = captured_output(b"one\ntwo\nthr");
+        assert_eq!(output.last_lines(1), b"thr");
+        assert_eq!(output.last_lines(2), b"two\nthr");
+        assert_eq!(captured_output(b"").last_lines(1), b"");
+        assert_eq!(captured_output(b"\n").last_lines(1), b"\n");
+    }
+
+    #[test]
+    fn append_keeps_the_end() {
+        let mut output = CapturedOutput {
+            data: vec![],
+            max_bytes: 4,
+        };
+        output.append(b"abc");
+        output.append(b"def");
+        assert_eq!(output.data, b"cdef");
+    }
+}
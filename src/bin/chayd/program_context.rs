@@ -4,7 +4,8 @@ use nix::sys::signal::Signal;
 pub enum PrecommandStatus {
     RUNNING,
     SUCCESS,
-    ERROR,
+    /// Contains a message describing the error.
+    ERROR(String),
 }
 
 #[derive(Default)]
@@ -16,16 +17,30 @@ pub struct PrecommandContext {
 }
 
 impl PrecommandContext {
-    fn reset(&mut self) {
+    pub fn reset(&mut self) {
         self.program.reset_child_proc();
         self.start_time = None;
     }
 }
 
+/// Which of a program's processes to send a signal to.
+#[derive(Clone, Copy, Debug)]
+pub enum SignalTarget {
+    Program,
+    Logger,
+    PreCommand,
+    /// The program and all of its sidecar programs that are running.
+    Group,
+}
+
 pub enum SubprogramStatus {
-    STARTING,
-    SUCCESS,
-    ERROR,
+    Starting,
+    Success,
+    /// The subprogram exited before being checked, but without a start_wait it already counted as
+    /// started, so this is a normal exit rather than a start failure.
+    Exited,
+    /// Contains a message describing the error.
+    Error(String),
 }
 
 #[derive(Default)]
@@ -52,10 +67,27 @@ pub struct ProgramContext {
     pub pre_command: Option<PrecommandContext>,
     pub logger: Option<SubprogramContext>,
     pub logger_pre_command: Option<PrecommandContext>,
+    pub reload_command: Option<PrecommandContext>,
+    /// Set when a reload was requested and the reload_command hasn't finished yet.
+    pub reload_requested: bool,
 
     pub num_restarts: u32,
     pub should_restart: bool,
     pub sigterm_time: Option<std::time::Instant>,
+
+    /// Most recent error that prevented the program from starting, e.g. a spawn error.
+    pub last_error: Option<String>,
+    /// Time the program will be started again while in backoff.
+    pub backoff_end_time: Option<std::time::SystemTime>,
+    /// Why the next state transition happens. Set right before transitioning and consumed by the
+    /// FSM's transition observer.
+    pub transition_reason: Option<crate::program_fsm::TransitionReason>,
+    /// Updated by the FSM's transition observer.
+    pub metrics: crate::metrics::ProgramFsmMetrics,
+    /// Checks the program's resource usage against its watchdog config while running.
+    pub watchdog: crate::program_watchdog::ProgramWatchdog,
+    /// Where the program's transitions are sent for WatchEvents clients.
+    program_transitions_tx: tokio::sync::broadcast::Sender<crate::program_fsm::ProgramTransition>,
 }
 
 fn logger_pre_command_name(program_name: &str) -> String {
@@ -70,22 +102,25 @@ fn pre_command_name(program_name: &str) -> String {
     format!("{program_name}-pre-command")
 }
 
-fn signal_to_str(signal: Signal) -> String {
-    match signal {
-        Signal::SIGKILL => "SIGKILL".to_string(),
-        Signal::SIGTERM => "SIGTERM".to_string(),
-        _ => panic!("Unknown signal"),
-    }
+fn reload_command_name(program_name: &str) -> String {
+    format!("{program_name}-reload-command")
 }
 
-fn send_signal_to_program_if_running(program: &mut Program, signal: Signal) {
+pub fn send_signal_to_program_if_running(program: &mut Program, signal: Signal) {
     if program.is_running() {
-        match program.send_signal(signal) {
+        let result = match &program.cgroup {
+            // Also kills the program's descendants, without racing with them forking. Other
+            // signals, e.g. the stop and reload signals, only go to the program's process, which
+            // may handle them for its children.
+            Some(cgroup) if signal == Signal::SIGKILL => cgroup.kill(),
+            _ => program.send_signal(signal).map_err(std::io::Error::from),
+        };
+        match result {
             Ok(_) => {}
             Err(error) => {
                 log::error!(
                     "Could not send {} to program {}: {:?}",
-                    signal_to_str(signal),
+                    signal.as_str(),
                     program.name,
                     error
                 );
@@ -94,23 +129,65 @@ fn send_signal_to_program_if_running(program: &mut Program, signal: Signal) {
     }
 }
 
+/// Panics if the rlimits are invalid, which is checked when the config is rendered.
+fn new_program(
+    name: String,
+    command: String,
+    args: Option<Vec<String>>,
+    rlimits_config: &crate::config::RlimitsConfig,
+) -> Program {
+    let mut program = Program::new(name, command, args);
+    program.context.rlimits = crate::program_rlimits::parse_rlimits(rlimits_config).unwrap();
+    program
+}
+
 impl ProgramContext {
-    pub fn new(name: &str, config: crate::config::RenderedProgramConfig) -> Self {
-        let program = SubprogramContext {
-            program: Program::new(
+    pub fn new(
+        name: &str,
+        config: crate::config::RenderedProgramConfig,
+        program_transitions_tx: tokio::sync::broadcast::Sender<
+            crate::program_fsm::ProgramTransition,
+        >,
+    ) -> Self {
+        let mut program = SubprogramContext {
+            program: new_program(
                 name.to_string(),
                 config.program.command.clone(),
                 config.program.args.clone(),
+                &config.program.rlimits,
             ),
             start_wait: std::time::Duration::from_secs(config.program.start_wait_secs as u64),
             start_time: None,
         };
+        program.program.context.env = config.program.env.clone();
+        program.program.context.directory = config.program.directory.clone();
+        program.program.context.user = config.program.user.clone();
+        let pty_size = if config.program.pty {
+            Some((config.program.pty_rows, config.program.pty_cols))
+        } else {
+            None
+        };
+        if config.program.attachable {
+            program.program.console = Some(crate::program_console::ProgramConsole::new(pty_size));
+        } else if config.program.tailable || pty_size.is_some() {
+            program.program.console = Some(crate::program_console::ProgramConsole::output_only(
+                pty_size,
+            ));
+        }
+        if let Some(cgroup_parent) = &config.cgroup_parent {
+            program.program.cgroup = Some(crate::program_cgroup::ProgramCgroup::new(
+                cgroup_parent,
+                name,
+                config.program.cgroup.clone().unwrap_or_default(),
+            ));
+        }
         let pre_command = if let Some(pre_command_config) = &config.program.pre_command {
             Some(PrecommandContext {
-                program: Program::new(
+                program: new_program(
                     pre_command_name(name),
                     pre_command_config.command.clone(),
                     pre_command_config.args.clone(),
+                    &pre_command_config.rlimits,
                 ),
                 timeout: std::time::Duration::from_secs(pre_command_config.timeout_secs as u64),
                 start_time: None,
@@ -119,25 +196,35 @@ impl ProgramContext {
             None
         };
         let logger = if let Some(logger_config) = &config.logger {
-            Some(SubprogramContext {
-                program: Program::new(
+            let mut logger = SubprogramContext {
+                program: new_program(
                     logger_name(name),
                     logger_config.command.clone(),
                     logger_config.args.clone(),
+                    &logger_config.rlimits,
                 ),
                 start_wait: std::time::Duration::from_secs(logger_config.start_wait_secs as u64),
                 start_time: None,
-            })
+            };
+            if let Some(cgroup_parent) = &config.cgroup_parent {
+                logger.program.cgroup = Some(crate::program_cgroup::ProgramCgroup::new(
+                    cgroup_parent,
+                    &logger_name(name),
+                    Default::default(),
+                ));
+            }
+            Some(logger)
         } else {
             None
         };
         let logger_pre_command = if let Some(logger_config) = &config.logger {
             if let Some(logger_pre_command_config) = &logger_config.pre_command {
                 Some(PrecommandContext {
-                    program: Program::new(
+                    program: new_program(
                         logger_pre_command_name(name),
                         logger_pre_command_config.command.clone(),
                         logger_pre_command_config.args.clone(),
+                        &logger_pre_command_config.rlimits,
                     ),
                     timeout: std::time::Duration::from_secs(
                         logger_pre_command_config.timeout_secs as u64,
@@ -150,6 +237,20 @@ impl ProgramContext {
         } else {
             None
         };
+        let reload_command = config
+            .program
+            .reload_command
+            .as_ref()
+            .map(|reload_command_config| PrecommandContext {
+                program: new_program(
+                    reload_command_name(name),
+                    reload_command_config.command.clone(),
+                    reload_command_config.args.clone(),
+                    &reload_command_config.rlimits,
+                ),
+                timeout: std::time::Duration::from_secs(reload_command_config.timeout_secs as u64),
+                start_time: None,
+            });
         Self {
             name: name.to_string(),
             config,
@@ -157,9 +258,17 @@ impl ProgramContext {
             pre_command,
             logger,
             logger_pre_command,
+            reload_command,
+            reload_requested: false,
             num_restarts: 0u32,
             should_restart: false,
             sigterm_time: None,
+            last_error: None,
+            backoff_end_time: None,
+            transition_reason: None,
+            metrics: Default::default(),
+            watchdog: Default::default(),
+            program_transitions_tx,
         }
     }
 
@@ -167,6 +276,27 @@ impl ProgramContext {
         self.name.clone()
     }
 
+    /// Sends a transition of the program to the WatchEvents clients. Called by the FSM's
+    /// transition observer, and by the watchdog for events that don't change the state.
+    pub fn send_transition_event(
+        &self,
+        from_state: &crate::program_fsm::ProgramState,
+        to_state: &crate::program_fsm::ProgramState,
+        reason: Option<crate::program_fsm::TransitionReason>,
+    ) {
+        let program_transition = crate::program_fsm::ProgramTransition {
+            program_name: self.name(),
+            labels: self.config.program.labels.clone(),
+            groups: self.config.program.groups.clone(),
+            from_state: from_state.clone(),
+            to_state: to_state.clone(),
+            time: std::time::SystemTime::now(),
+            reason,
+        };
+        // Sending only fails if there are no subscribers, i.e. nobody is watching.
+        let _ = self.program_transitions_tx.send(program_transition);
+    }
+
     pub fn all_programs_are_running(&mut self) -> bool {
         if let Some(logger) = &mut self.logger {
             if !logger.program.is_running() {
@@ -176,6 +306,19 @@ impl ProgramContext {
         self.program.program.is_running()
     }
 
+    /// Describes which program exited, assuming at least one of the running programs did.
+    pub fn exited_reason(&mut self) -> crate::program_fsm::TransitionReason {
+        let program_is_running = self.program.program.is_running();
+        let exited_program = match &self.logger {
+            Some(logger) if program_is_running => &logger.program,
+            _ => &self.program.program,
+        };
+        crate::program_fsm::TransitionReason::ProgramExited {
+            program_name: exited_program.name.clone(),
+            exit: exited_program.last_exit.clone(),
+        }
+    }
+
     pub fn all_programs_are_stopped(&mut self) -> bool {
         if self.program.program.is_running() {
             return false;
@@ -195,6 +338,11 @@ impl ProgramContext {
                 return false;
             }
         }
+        if let Some(reload_command) = &mut self.reload_command {
+            if reload_command.program.is_running() {
+                return false;
+            }
+        }
         true
     }
 
@@ -209,6 +357,10 @@ impl ProgramContext {
         if let Some(logger_pre_command) = &mut self.logger_pre_command {
             logger_pre_command.reset();
         }
+        if let Some(reload_command) = &mut self.reload_command {
+            reload_command.reset();
+        }
+        self.reload_requested = false;
         self.sigterm_time = None;
         // NOTE: Intentionally do not reset num_restarts or should_restart here. Those are reset
         // seperately during in the appropriate state transitions.
@@ -221,12 +373,74 @@ impl ProgramContext {
         if let Some(logger_pre_command) = &mut self.logger_pre_command {
             send_signal_to_program_if_running(&mut logger_pre_command.program, signal);
         }
+        if let Some(reload_command) = &mut self.reload_command {
+            send_signal_to_program_if_running(&mut reload_command.program, signal);
+        }
         if let Some(logger) = &mut self.logger {
             send_signal_to_program_if_running(&mut logger.program, signal);
         }
         send_signal_to_program_if_running(&mut self.program.program, signal);
     }
 
+    /// Sends a signal on behalf of a client, so unlike the other signalling methods this reports
+    /// programs that aren't running as an error.
+    pub fn send_signal_to_target(
+        &mut self,
+        signal: Signal,
+        target: SignalTarget,
+    ) -> chay::fsm::MachineResult {
+        let mut programs: Vec<&mut Program> = vec![];
+        match target {
+            SignalTarget::Program => programs.push(&mut self.program.program),
+            SignalTarget::Logger => match &mut self.logger {
+                Some(logger) => programs.push(&mut logger.program),
+                None => return Err("No logger configured".to_string()),
+            },
+            SignalTarget::PreCommand => match &mut self.pre_command {
+                Some(pre_command) => programs.push(&mut pre_command.program),
+                None => return Err("No pre_command configured".to_string()),
+            },
+            SignalTarget::Group => {
+                programs.push(&mut self.program.program);
+                if let Some(pre_command) = &mut self.pre_command {
+                    programs.push(&mut pre_command.program);
+                }
+                if let Some(logger) = &mut self.logger {
+                    programs.push(&mut logger.program);
+                }
+                if let Some(logger_pre_command) = &mut self.logger_pre_command {
+                    programs.push(&mut logger_pre_command.program);
+                }
+                if let Some(reload_command) = &mut self.reload_command {
+                    programs.push(&mut reload_command.program);
+                }
+            }
+        }
+        let mut signalled_programs = vec![];
+        for program in programs {
+            if !program.is_running() {
+                continue;
+            }
+            if let Err(error) = program.send_signal(signal) {
+                return Err(format!(
+                    "Could not send {} to {}: {}",
+                    signal.as_str(),
+                    program.name,
+                    error
+                ));
+            }
+            signalled_programs.push(program.name.clone());
+        }
+        if signalled_programs.is_empty() {
+            return Err("Not running".to_string());
+        }
+        Ok(Some(format!(
+            "Sent {} to {}",
+            signal.as_str(),
+            signalled_programs.join(", ")
+        )))
+    }
+
     pub fn send_sigterm_or_sigkill_signal_to_all_running_programs(&mut self) {
         if let Some(sigterm_time) = self.sigterm_time {
             // We already sent SIGTERM in a previous update. Send SIGKILL if it
@@ -0,0 +1,218 @@
+use crate::auth::Permissions;
+use crate::chay_proto;
+use crate::program::ProcessContext;
+use crate::program_cgroup::ProgramCgroup;
+use crate::program_fsm::ProgramFsm;
+use crate::program_selector::SelectorTarget;
+use crate::request_error::RequestError;
+use chay_proto::chayd_service_exec_response::Output;
+use chay_proto::ChaydServiceExecResponse;
+use tokio::io::AsyncReadExt;
+
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+/// Numbers the cgroups of commands, so commands run at the same time get their own.
+static NEXT_EXEC_NUMBER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+pub type ExecResponseResult = Result<ChaydServiceExecResponse, tonic::Status>;
+
+/// A command to run in a program's context.
+pub struct ExecCommand {
+    /// The rendered command followed by its arguments.
+    pub args: Vec<String>,
+    /// The program's env, directory, user and rlimits.
+    pub context: ProcessContext,
+    /// Set if chayd.cgroup_parent is configured. Has the same limits as the program's cgroup, but
+    /// is separate from it so restarting the program doesn't kill the command.
+    pub cgroup: Option<ProgramCgroup>,
+}
+
+pub type ExecResult = Result<ExecCommand, RequestError>;
+
+pub struct ExecRequest {
+    pub program_name: String,
+    pub args: Vec<String>,
+    pub permissions: Permissions,
+    pub results_sender: tokio::sync::mpsc::Sender<ExecResult>,
+}
+
+/// Renders the command's args with the program's vars and copies the program's context, if the
+/// program exists and the client is allowed to run commands in its context.
+pub fn exec_command(
+    config: &crate::config::Config,
+    program_fsms: &[ProgramFsm],
+    exec_request: &ExecRequest,
+) -> ExecResult {
+    let program_name = &exec_request.program_name;
+    let program_fsm = program_fsms
+        .iter()
+        .find(|program_fsm| &program_fsm.app_context().name == program_name)
+        .ok_or_else(|| {
+            RequestError::new(
+                tonic::Code::NotFound,
+                format!("Program not found: {program_name}"),
+            )
+        })?;
+    let permissions = &exec_request.permissions;
+    if !permissions.is_allowed(
+        crate::auth::EXEC_ACTION,
+        &SelectorTarget::from_program_fsm(program_fsm),
+    ) {
+        return Err(RequestError::new(
+            tonic::Code::PermissionDenied,
+            permissions.denied_message(crate::auth::EXEC_ACTION),
+        ));
+    }
+    if exec_request.args.is_empty() {
+        return Err(RequestError::new(
+            tonic::Code::InvalidArgument,
+            "Missing command",
+        ));
+    }
+    let args = crate::config::render_exec_args(config, program_name, &exec_request.args).map_err(
+        |error| {
+            RequestError::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "Invalid args: {}",
+                    crate::config::render_error_message(&error)
+                ),
+            )
+        },
+    )?;
+    let program_ctx = program_fsm.app_context();
+    let cgroup_config = program_ctx
+        .config
+        .program
+        .cgroup
+        .clone()
+        .unwrap_or_default();
+    let cgroup = program_ctx
+        .config
+        .cgroup_parent
+        .as_ref()
+        .map(|cgroup_parent| {
+            let exec_number = NEXT_EXEC_NUMBER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            ProgramCgroup::new(
+                cgroup_parent,
+                &format!("{program_name}-exec-{exec_number}"),
+                cgroup_config,
+            )
+        });
+    Ok(ExecCommand {
+        args,
+        context: program_ctx.program.program.context.clone(),
+        cgroup,
+    })
+}
+
+async fn forward_output(
+    mut output: impl tokio::io::AsyncRead + Unpin,
+    stream_tx: tokio::sync::mpsc::Sender<ExecResponseResult>,
+    to_output: fn(Vec<u8>) -> Output,
+) {
+    let mut buf = [0u8; OUTPUT_CHUNK_SIZE];
+    loop {
+        let num_read = match output.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(num_read) => num_read,
+        };
+        let response = ChaydServiceExecResponse {
+            output: Some(to_output(buf[..num_read].to_vec())),
+        };
+        if stream_tx.send(Ok(response)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs the command and streams its output, followed by its exit. The command is killed if the
+/// client goes away before it exits, along with its descendants if it has a cgroup.
+pub fn spawn_exec(
+    program_name: &str,
+    exec_command: ExecCommand,
+) -> Result<tokio::sync::mpsc::Receiver<ExecResponseResult>, RequestError> {
+    let ExecCommand {
+        args,
+        context,
+        cgroup,
+    } = exec_command;
+    let spawn_error = |error: std::io::Error| {
+        RequestError::new(
+            tonic::Code::InvalidArgument,
+            format!("Could not run {}: {}", args[0], error),
+        )
+    };
+    let mut command = std::process::Command::new(&args[0]);
+    command.args(&args[1..]);
+    context
+        .configure(&mut command, cgroup.as_ref())
+        .map_err(spawn_error)?;
+    let mut child_proc = tokio::process::Command::from(command)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(spawn_error)?;
+    log::info!("Running {:?} for {}", args, program_name);
+    let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(20);
+    let stdout_forwarder = tokio::spawn(forward_output(
+        child_proc.stdout.take().unwrap(),
+        stream_tx.clone(),
+        Output::Stdout,
+    ));
+    let stderr_forwarder = tokio::spawn(forward_output(
+        child_proc.stderr.take().unwrap(),
+        stream_tx.clone(),
+        Output::Stderr,
+    ));
+    let program_name = program_name.to_string();
+    tokio::spawn(async move {
+        let exit_status = tokio::select! {
+            exit_status = child_proc.wait() => exit_status,
+            _ = stream_tx.closed() => {
+                log::info!("Killing {:?} for {} since the client went away", args, program_name);
+                if let Some(cgroup) = &cgroup {
+                    let _ = cgroup.kill();
+                }
+                let _ = child_proc.kill().await;
+                return;
+            }
+        };
+        if let Some(cgroup) = &cgroup {
+            // Processes left behind by the command, e.g. ones that daemonized.
+            if cgroup.is_populated() {
+                log::warn!(
+                    "Killing processes left behind by {:?} for {}",
+                    args,
+                    program_name
+                );
+                let _ = cgroup.kill();
+            }
+        }
+        // Send all the output before the exit.
+        let _ = stdout_forwarder.await;
+        let _ = stderr_forwarder.await;
+        let response = match exit_status {
+            Ok(exit_status) => Ok(ChaydServiceExecResponse {
+                output: Some(Output::Exit(
+                    crate::proto_converters::proto_from_program_exit(
+                        &crate::program::ProgramExit {
+                            status: exit_status,
+                            time: std::time::SystemTime::now(),
+                            rusage: None,
+                        },
+                    ),
+                )),
+            }),
+            Err(error) => Err(tonic::Status::internal(format!(
+                "Could not wait for {}: {}",
+                args[0], error
+            ))),
+        };
+        // The connection was probably closed by the client if this fails.
+        let _ = stream_tx.send(response).await;
+    });
+    Ok(stream_rx)
+}
@@ -6,8 +6,9 @@ pub type ProgramFsm = chay::fsm::Machine<ProgramState, ProgramContext, ProgramEv
 pub fn new_program_fsm(
     program_name: String,
     config: &crate::config::RenderedProgramConfig,
+    program_transitions_tx: tokio::sync::broadcast::Sender<ProgramTransition>,
 ) -> ProgramFsm {
-    let program_ctx = ProgramContext::new(&program_name, config.clone());
+    let program_ctx = ProgramContext::new(&program_name, config.clone(), program_transitions_tx);
     let init_state = if config.autostart() {
         ProgramState::Starting
     } else {
@@ -27,7 +28,7 @@ pub fn new_program_fsm(
         Box::new(Stopping::default());
     let exiting: Box<dyn chay::fsm::State<ProgramState, ProgramContext, ProgramEvent>> =
         Box::new(Exiting::default());
-    return ProgramFsm::new(
+    let mut program_fsm = ProgramFsm::new(
         program_ctx,
         init_state,
         HashMap::from([
@@ -40,15 +41,65 @@ pub fn new_program_fsm(
             (ProgramState::Exiting, exiting),
         ]),
     );
+    program_fsm.add_observer(Box::new(
+        |from_state: &ProgramState, to_state: &ProgramState, program_ctx: &mut ProgramContext| {
+            program_ctx.metrics.observe_transition(from_state, to_state);
+        },
+    ));
+    program_fsm.add_observer(Box::new(
+        |from_state: &ProgramState, to_state: &ProgramState, program_ctx: &mut ProgramContext| {
+            let reason = program_ctx.transition_reason.take();
+            program_ctx.send_transition_event(from_state, to_state, reason);
+        },
+    ));
+    program_fsm
 }
 
 pub enum ProgramEvent {
     Start,
     Stop,
     Restart,
+    Reload,
 }
 
-#[derive(Clone, Eq, Hash, PartialEq)]
+/// Why a program changed state.
+#[derive(Clone, Debug)]
+pub enum TransitionReason {
+    /// A client sent a program event, e.g. "restart".
+    UserRequest {
+        action: String,
+        source: String,
+    },
+    Started,
+    /// Contains a message describing the error, e.g. a spawn error.
+    StartFailed(String),
+    /// The program or one of its sidecar programs (e.g. its logger) exited while running.
+    ProgramExited {
+        program_name: String,
+        exit: Option<crate::program::ProgramExit>,
+    },
+    BackoffElapsed,
+    /// A client asked to start the program while it was in backoff.
+    BackoffSkipped,
+    AllProcessesStopped,
+    /// The program exceeded a limit of its watchdog config. Contains a message describing the
+    /// exceeded limits.
+    WatchdogTriggered(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ProgramTransition {
+    pub program_name: String,
+    /// Labels and groups of the program, to match program expressions against.
+    pub labels: std::collections::BTreeMap<String, String>,
+    pub groups: Vec<String>,
+    pub from_state: ProgramState,
+    pub to_state: ProgramState,
+    pub time: std::time::SystemTime,
+    pub reason: Option<TransitionReason>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum ProgramState {
     Stopped,
     Exited,
@@ -100,6 +151,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Stopped {
                 context.transition(ProgramState::Starting);
                 chay::fsm::MachineResult::Ok(Some("Wasn't running (was stopped)".to_string()))
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Ok(Some("Not running (was stopped)".to_string()))
+            }
         }
     }
 
@@ -129,6 +183,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Exited {
                 context.transition(ProgramState::Starting);
                 chay::fsm::MachineResult::Ok(Some("Wasn't running (was exited)".to_string()))
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Ok(Some("Not running (was exited)".to_string()))
+            }
         }
     }
 
@@ -153,6 +210,7 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Backoff {
             }
         }
         if self.skip_backoff_delay {
+            program_ctx.transition_reason = Some(TransitionReason::BackoffSkipped);
             context.transition(ProgramState::Starting);
             return;
         }
@@ -160,6 +218,7 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Backoff {
         if (now - self.enter_time.unwrap())
             >= std::time::Duration::from_secs(program_ctx.config.backoff_delay_secs() as u64)
         {
+            program_ctx.transition_reason = Some(TransitionReason::BackoffElapsed);
             context.transition(ProgramState::Starting);
         }
     }
@@ -199,6 +258,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Backoff {
                 self.skip_backoff_delay = true;
                 chay::fsm::MachineResult::Ok(Some("Will restart after backoff cleanup".to_string()))
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Ok(Some("Not running (was backoff)".to_string()))
+            }
         }
     }
 
@@ -212,9 +274,14 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Backoff {
         program_ctx.sigterm_time = None;
         program_ctx.num_restarts += 1u32;
         self.enter_time.replace(std::time::Instant::now());
+        program_ctx.backoff_end_time = Some(
+            std::time::SystemTime::now()
+                + std::time::Duration::from_secs(program_ctx.config.backoff_delay_secs() as u64),
+        );
     }
 
     fn exit(&mut self, program_ctx: &mut ProgramContext) {
+        program_ctx.backoff_end_time = None;
         program_ctx.reset()
     }
 }
@@ -232,47 +299,50 @@ impl Starting {
                     // The program is still running. Check if it has timed out.
                     if now - *start_time > pre_command.timeout {
                         // The program failed to run within the given timeout.
-                        log::info!("{} timed out", pre_command.program.name);
-                        return crate::program_context::PrecommandStatus::ERROR;
+                        let message = format!("{} timed out", pre_command.program.name);
+                        log::info!("{message}");
+                        return crate::program_context::PrecommandStatus::ERROR(message);
                     }
                     // We are still waiting for pre_command to finish running.
                     return crate::program_context::PrecommandStatus::RUNNING;
                 }
                 Ok(Some(exit_status)) => {
                     if !exit_status.success() {
-                        if let Some(code) = exit_status.code() {
-                            log::info!(
+                        let message = if let Some(code) = exit_status.code() {
+                            format!(
                                 "{} exited with non-zero code {}",
-                                pre_command.program.name,
-                                code
-                            );
+                                pre_command.program.name, code
+                            )
                         } else {
                             // I don't know if this can ever happen. The documentation says
                             // that on unix this can be None if the program was terminated by
                             // a signal, which shouldn't ever happen here to my knowledge.
-                            log::info!(
+                            format!(
                                 "{} exited with non-zero code [unknown]",
                                 pre_command.program.name
-                            );
-                        }
-                        return crate::program_context::PrecommandStatus::ERROR;
+                            )
+                        };
+                        log::info!("{message}");
+                        return crate::program_context::PrecommandStatus::ERROR(message);
                     }
                     // The program finished running successfully!
                     return crate::program_context::PrecommandStatus::SUCCESS;
                 }
                 Err(error) => {
-                    log::error!(
+                    let message = format!(
                         "{} error retrieving exit status: {error}",
                         pre_command.program.name
                     );
-                    return crate::program_context::PrecommandStatus::ERROR;
+                    log::error!("{message}");
+                    return crate::program_context::PrecommandStatus::ERROR(message);
                 }
             }
         }
         // Command has not been started yet.
         if let Err(error) = pre_command.program.start(false, None) {
-            log::info!("{} spawn error: {error}", pre_command.program.name);
-            return crate::program_context::PrecommandStatus::ERROR;
+            let message = format!("{} spawn error: {error}", pre_command.program.name);
+            log::info!("{message}");
+            return crate::program_context::PrecommandStatus::ERROR(message);
         }
         pre_command.start_time = Some(now);
         return crate::program_context::PrecommandStatus::RUNNING;
@@ -288,27 +358,36 @@ impl Starting {
             Ok(None) => {
                 // The program is still running. Check if it has reached the start_wait period.
                 if now - *start_time >= subprogram.start_wait {
-                    return crate::program_context::SubprogramStatus::SUCCESS;
+                    return crate::program_context::SubprogramStatus::Success;
                 }
-                return crate::program_context::SubprogramStatus::STARTING;
+                return crate::program_context::SubprogramStatus::Starting;
+            }
+            Ok(Some(_)) if subprogram.start_wait.is_zero() => {
+                log::info!("{} exited", subprogram.program.name);
+                crate::program_context::SubprogramStatus::Exited
             }
             Ok(Some(exit_status)) => {
-                if let Some(code) = exit_status.code() {
-                    log::info!("{} exited with code {}", subprogram.program.name, code);
+                let message = if let Some(code) = exit_status.code() {
+                    let message = format!("{} exited with code {}", subprogram.program.name, code);
+                    log::info!("{message}");
+                    message
                 } else {
                     // I don't know if this can ever happen. The documentation says
                     // that on unix this can be None if the program was terminated by
                     // a signal, which shouldn't ever happen here to my knowledge.
-                    log::error!("{} exited with code [unknown]", subprogram.program.name);
-                }
-                return crate::program_context::SubprogramStatus::ERROR;
+                    let message = format!("{} exited with code [unknown]", subprogram.program.name);
+                    log::error!("{message}");
+                    message
+                };
+                crate::program_context::SubprogramStatus::Error(message)
             }
             Err(error) => {
-                log::error!(
+                let message = format!(
                     "{} error retrieving exit status: {error}",
                     subprogram.program.name
                 );
-                return crate::program_context::SubprogramStatus::ERROR;
+                log::error!("{message}");
+                crate::program_context::SubprogramStatus::Error(message)
             }
         }
     }
@@ -326,8 +405,8 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
             match Starting::check_pre_command(logger_pre_command, now.clone()) {
                 crate::program_context::PrecommandStatus::RUNNING => return,
                 crate::program_context::PrecommandStatus::SUCCESS => (),
-                crate::program_context::PrecommandStatus::ERROR => {
-                    transition_to_backoff_or_exiting(context, program_ctx);
+                crate::program_context::PrecommandStatus::ERROR(message) => {
+                    fail_start(context, program_ctx, message);
                     return;
                 }
             }
@@ -337,8 +416,8 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
             match Starting::check_pre_command(pre_command, now.clone()) {
                 crate::program_context::PrecommandStatus::RUNNING => return,
                 crate::program_context::PrecommandStatus::SUCCESS => (),
-                crate::program_context::PrecommandStatus::ERROR => {
-                    transition_to_backoff_or_exiting(context, program_ctx);
+                crate::program_context::PrecommandStatus::ERROR(message) => {
+                    fail_start(context, program_ctx, message);
                     return;
                 }
             }
@@ -348,8 +427,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
             // Start the logger if it isn't already started.
             if logger.start_time.is_none() {
                 if let Err(error) = logger.program.start(true, None) {
-                    log::info!("{} spawn error: {error}", logger.program.name);
-                    transition_to_backoff_or_exiting(context, program_ctx);
+                    let message = format!("{} spawn error: {error}", logger.program.name);
+                    log::info!("{message}");
+                    fail_start(context, program_ctx, message);
                     return;
                 }
                 logger.start_time = Some(now);
@@ -362,8 +442,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
                     false,
                     Some(&mut logger.program.child_proc.as_mut().unwrap()),
                 ) {
-                    log::info!("{} spawn error: {error}", program_ctx.name);
-                    transition_to_backoff_or_exiting(context, program_ctx);
+                    let message = format!("{} spawn error: {error}", program_ctx.name);
+                    log::info!("{message}");
+                    fail_start(context, program_ctx, message);
                     return;
                 }
                 program_ctx.program.start_time = Some(now);
@@ -372,8 +453,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
             // Start the program if it isn't already started.
             if program_ctx.program.start_time.is_none() {
                 if let Err(error) = program_ctx.program.program.start(false, None) {
-                    log::info!("{} spawn error: {error}", program_ctx.name);
-                    transition_to_backoff_or_exiting(context, program_ctx);
+                    let message = format!("{} spawn error: {error}", program_ctx.name);
+                    log::info!("{message}");
+                    fail_start(context, program_ctx, message);
                     return;
                 }
                 program_ctx.program.start_time = Some(now);
@@ -382,12 +464,17 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
 
         // Wait for the program to start successfully.
         match Starting::check_subprogram(&mut program_ctx.program, now.clone()) {
-            crate::program_context::SubprogramStatus::STARTING => return,
-            crate::program_context::SubprogramStatus::SUCCESS => (),
-            crate::program_context::SubprogramStatus::ERROR => {
+            crate::program_context::SubprogramStatus::Starting => return,
+            crate::program_context::SubprogramStatus::Success => (),
+            crate::program_context::SubprogramStatus::Exited => {
+                program_ctx.transition_reason = Some(program_ctx.exited_reason());
                 transition_to_backoff_or_exiting(context, program_ctx);
                 return;
             }
+            crate::program_context::SubprogramStatus::Error(message) => {
+                fail_start(context, program_ctx, message);
+                return;
+            }
         }
 
         if let Some(logger) = &mut program_ctx.logger {
@@ -396,17 +483,23 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
             // fails to start. When that happens we wouldn't get a very helpful error message. It'd
             // say the logger stopped running, rather than showing that the program exited.
             match Starting::check_subprogram(logger, now.clone()) {
-                crate::program_context::SubprogramStatus::STARTING => return,
-                crate::program_context::SubprogramStatus::SUCCESS => (),
-                crate::program_context::SubprogramStatus::ERROR => {
+                crate::program_context::SubprogramStatus::Starting => return,
+                crate::program_context::SubprogramStatus::Success => (),
+                crate::program_context::SubprogramStatus::Exited => {
+                    program_ctx.transition_reason = Some(program_ctx.exited_reason());
                     transition_to_backoff_or_exiting(context, program_ctx);
                     return;
                 }
+                crate::program_context::SubprogramStatus::Error(message) => {
+                    fail_start(context, program_ctx, message);
+                    return;
+                }
             }
         }
 
         // If we get here, all the pre_commands succeeded and both the logger and program are
         // running successfully!
+        program_ctx.transition_reason = Some(TransitionReason::Started);
         context.transition(ProgramState::Running);
     }
 
@@ -433,6 +526,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Starting {
                 context.transition(ProgramState::Stopping);
                 chay::fsm::MachineResult::Ok(None)
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Ok(Some("Not running yet (was starting)".to_string()))
+            }
         }
     }
 
@@ -448,8 +544,12 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Running {
         program_ctx: &mut ProgramContext,
     ) {
         if !program_ctx.all_programs_are_running() {
+            program_ctx.transition_reason = Some(program_ctx.exited_reason());
             transition_to_backoff_or_exiting(context, program_ctx);
+            return;
         }
+        Running::check_reload_command(program_ctx);
+        Running::check_watchdog(context, program_ctx);
     }
 
     fn react(
@@ -472,15 +572,120 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Running {
                 context.transition(ProgramState::Stopping);
                 chay::fsm::MachineResult::Ok(None)
             }
+            ProgramEvent::Reload => {
+                if let Some(reload_signal) = program_ctx.config.reload_signal() {
+                    return match program_ctx.program.program.send_signal(reload_signal) {
+                        Ok(_) => chay::fsm::MachineResult::Ok(Some(format!(
+                            "Sent {}",
+                            reload_signal.as_str()
+                        ))),
+                        Err(error) => chay::fsm::MachineResult::Err(format!(
+                            "Could not send {}: {}",
+                            reload_signal.as_str(),
+                            error
+                        )),
+                    };
+                }
+                if let Some(reload_command) = &mut program_ctx.reload_command {
+                    // The reload_command may still be running after being killed for timing out.
+                    if program_ctx.reload_requested || reload_command.program.is_running() {
+                        return chay::fsm::MachineResult::Ok(Some("Already reloading".to_string()));
+                    }
+                    // The reload_command is started by the next update.
+                    program_ctx.reload_requested = true;
+                    return chay::fsm::MachineResult::Ok(None);
+                }
+                program_ctx.should_restart = true;
+                context.transition(ProgramState::Stopping);
+                chay::fsm::MachineResult::Ok(Some(
+                    "No reload method configured, restarting".to_string(),
+                ))
+            }
         }
     }
 
     fn enter(&mut self, program_ctx: &mut ProgramContext) {
         program_ctx.num_restarts = 0u32;
+        // Errors of earlier attempts no longer apply once the program started.
+        program_ctx.last_error = None;
+        program_ctx.watchdog.reset();
         log::info!("{} running", program_ctx.name);
     }
 }
 
+impl Running {
+    /// Restarts the program, or sends an event, if it exceeded a limit of its watchdog config.
+    fn check_watchdog(
+        context: &mut dyn chay::fsm::Context<ProgramState>,
+        program_ctx: &mut ProgramContext,
+    ) {
+        let watchdog_config = match &program_ctx.config.program.watchdog {
+            Some(watchdog_config) => watchdog_config,
+            None => return,
+        };
+        let message = match program_ctx
+            .watchdog
+            .check(watchdog_config, &program_ctx.program.program.usage)
+        {
+            Some(message) => message,
+            None => return,
+        };
+        match watchdog_config.action {
+            crate::config::WatchdogAction::Restart => {
+                log::warn!(
+                    "{} watchdog triggered, restarting: {}",
+                    program_ctx.name,
+                    message
+                );
+                program_ctx.transition_reason = Some(TransitionReason::WatchdogTriggered(message));
+                program_ctx.should_restart = true;
+                context.transition(ProgramState::Stopping);
+            }
+            crate::config::WatchdogAction::Event => {
+                log::warn!("{} watchdog triggered: {}", program_ctx.name, message);
+                // The program stays running, so the event is a transition to the same state.
+                program_ctx.send_transition_event(
+                    &ProgramState::Running,
+                    &ProgramState::Running,
+                    Some(TransitionReason::WatchdogTriggered(message)),
+                );
+            }
+        }
+    }
+
+    /// Runs the reload_command if a reload was requested, without affecting the program's state.
+    fn check_reload_command(program_ctx: &mut ProgramContext) {
+        let reload_command = match &mut program_ctx.reload_command {
+            Some(reload_command) => reload_command,
+            None => return,
+        };
+        if !program_ctx.reload_requested {
+            // Clean up after a reload_command that was killed for timing out.
+            if reload_command.start_time.is_some() && !reload_command.program.is_running() {
+                reload_command.reset();
+            }
+            return;
+        }
+        match Starting::check_pre_command(reload_command, std::time::Instant::now()) {
+            crate::program_context::PrecommandStatus::RUNNING => (),
+            crate::program_context::PrecommandStatus::SUCCESS => {
+                log::info!("{} reloaded", program_ctx.name);
+                reload_command.reset();
+                program_ctx.reload_requested = false;
+            }
+            crate::program_context::PrecommandStatus::ERROR(message) => {
+                // The reload_command is still running if it timed out.
+                crate::program_context::send_signal_to_program_if_running(
+                    &mut reload_command.program,
+                    nix::sys::signal::Signal::SIGKILL,
+                );
+                program_ctx.last_error = Some(message);
+                program_ctx.reload_requested = false;
+            }
+        }
+    }
+}
+
 impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Stopping {
     fn update(
         &mut self,
@@ -488,12 +693,14 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Stopping {
         program_ctx: &mut ProgramContext,
     ) {
         if program_ctx.all_programs_are_stopped() {
+            program_ctx.transition_reason = Some(TransitionReason::AllProcessesStopped);
             transition_to_stopped_or_restart(program_ctx.should_restart, context);
             return;
         }
         program_ctx.send_sigterm_or_sigkill_signal_to_all_running_programs();
         // Check again if everything is stopped in case we just killed everything above.
         if program_ctx.all_programs_are_stopped() {
+            program_ctx.transition_reason = Some(TransitionReason::AllProcessesStopped);
             transition_to_stopped_or_restart(program_ctx.should_restart, context);
         }
     }
@@ -516,6 +723,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Stopping {
                 program_ctx.should_restart = true;
                 chay::fsm::MachineResult::Ok(Some("Will restart after stopping".to_string()))
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Err("Cannot reload while stopping".to_string())
+            }
         }
     }
 
@@ -538,12 +748,14 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Exiting {
         program_ctx: &mut ProgramContext,
     ) {
         if program_ctx.all_programs_are_stopped() {
+            program_ctx.transition_reason = Some(TransitionReason::AllProcessesStopped);
             transition_to_exited_or_restart(program_ctx.should_restart, context);
             return;
         }
         program_ctx.send_sigterm_or_sigkill_signal_to_all_running_programs();
         // Check again if everything is stopped in case we just killed everything above.
         if program_ctx.all_programs_are_stopped() {
+            program_ctx.transition_reason = Some(TransitionReason::AllProcessesStopped);
             transition_to_exited_or_restart(program_ctx.should_restart, context);
         }
     }
@@ -566,6 +778,9 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Exiting {
                 program_ctx.should_restart = true;
                 chay::fsm::MachineResult::Ok(Some("Will restart after exiting".to_string()))
             }
+            ProgramEvent::Reload => {
+                chay::fsm::MachineResult::Err("Cannot reload while exiting".to_string())
+            }
         }
     }
 
@@ -581,6 +796,17 @@ impl chay::fsm::State<ProgramState, ProgramContext, ProgramEvent> for Exiting {
     }
 }
 
+/// Records why the program failed to start and transitions to backoff or exiting.
+fn fail_start(
+    context: &mut dyn chay::fsm::Context<ProgramState>,
+    program_ctx: &mut ProgramContext,
+    message: String,
+) {
+    program_ctx.last_error = Some(message.clone());
+    program_ctx.transition_reason = Some(TransitionReason::StartFailed(message));
+    transition_to_backoff_or_exiting(context, program_ctx);
+}
+
 fn transition_to_exited_or_exiting(
     context: &mut dyn chay::fsm::Context<ProgramState>,
     program_ctx: &mut ProgramContext,
@@ -0,0 +1,255 @@
+use crate::config::{RlimitConfig, RlimitValue, RlimitsConfig};
+use nix::sys::resource::{getrlimit, rlim_t, setrlimit, Resource, RLIM_INFINITY};
+use std::os::unix::process::CommandExt;
+
+/// Names of the limits in the config, e.g. `nofile` for RLIMIT_NOFILE, as used by ulimit and
+/// systemd.
+const RESOURCES: [(&str, Resource); 16] = [
+    ("as", Resource::RLIMIT_AS),
+    ("core", Resource::RLIMIT_CORE),
+    ("cpu", Resource::RLIMIT_CPU),
+    ("data", Resource::RLIMIT_DATA),
+    ("fsize", Resource::RLIMIT_FSIZE),
+    ("locks", Resource::RLIMIT_LOCKS),
+    ("memlock", Resource::RLIMIT_MEMLOCK),
+    ("msgqueue", Resource::RLIMIT_MSGQUEUE),
+    ("nice", Resource::RLIMIT_NICE),
+    ("nofile", Resource::RLIMIT_NOFILE),
+    ("nproc", Resource::RLIMIT_NPROC),
+    ("rss", Resource::RLIMIT_RSS),
+    ("rtprio", Resource::RLIMIT_RTPRIO),
+    ("rttime", Resource::RLIMIT_RTTIME),
+    ("sigpending", Resource::RLIMIT_SIGPENDING),
+    ("stack", Resource::RLIMIT_STACK),
+];
+
+/// A resource limit to set in a child process before it execs.
+#[derive(Clone, Copy, Debug)]
+pub struct Rlimit {
+    resource: Resource,
+    soft_limit: rlim_t,
+    hard_limit: rlim_t,
+}
+
+fn parse_rlimit_value(name: &str, rlimit_value: &RlimitValue) -> Result<rlim_t, String> {
+    match rlimit_value {
+        RlimitValue::Value(value) => Ok(*value as rlim_t),
+        RlimitValue::Keyword(keyword) if keyword == "unlimited" || keyword == "infinity" => {
+            Ok(RLIM_INFINITY)
+        }
+        RlimitValue::Keyword(keyword) => Err(format!(
+            "Invalid value of rlimit {name}: {keyword} (expected a number or \"unlimited\")"
+        )),
+    }
+}
+
+fn format_rlimit_value(value: rlim_t) -> String {
+    if value == RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parses and validates an rlimits table of the config. Raising a hard limit above chayd's own
+/// is only allowed for root, so other users get the error when the config is loaded rather than
+/// when the program is started.
+pub fn parse_rlimits(rlimits_config: &RlimitsConfig) -> Result<Vec<Rlimit>, String> {
+    let mut rlimits = vec![];
+    for (name, rlimit_config) in rlimits_config {
+        let resource = RESOURCES
+            .iter()
+            .find(|(resource_name, _)| resource_name == name)
+            .map(|(_, resource)| *resource)
+            .ok_or_else(|| format!("Unknown rlimit: {name}"))?;
+        let (soft_limit, hard_limit) = match rlimit_config {
+            RlimitConfig::Limit(rlimit_value) => {
+                let value = parse_rlimit_value(name, rlimit_value)?;
+                (value, value)
+            }
+            RlimitConfig::SoftHard { soft, hard } => (
+                parse_rlimit_value(name, soft)?,
+                parse_rlimit_value(name, hard)?,
+            ),
+        };
+        // RLIM_INFINITY is the largest rlim_t, so it compares as expected.
+        if soft_limit > hard_limit {
+            return Err(format!(
+                "Soft limit of rlimit {name} is above its hard limit: {} > {}",
+                format_rlimit_value(soft_limit),
+                format_rlimit_value(hard_limit)
+            ));
+        }
+        let (_, current_hard_limit) =
+            getrlimit(resource).map_err(|error| format!("Could not get rlimit {name}: {error}"))?;
+        if hard_limit > current_hard_limit && !nix::unistd::geteuid().is_root() {
+            return Err(format!(
+                "Rlimit {name} is above chayd's hard limit: {} > {}",
+                format_rlimit_value(hard_limit),
+                format_rlimit_value(current_hard_limit)
+            ));
+        }
+        rlimits.push(Rlimit {
+            resource,
+            soft_limit,
+            hard_limit,
+        });
+    }
+    Ok(rlimits)
+}
+
+/// Sets the rlimits in the child process, between fork and exec.
+pub fn configure_rlimits(command: &mut std::process::Command, rlimits: &[Rlimit]) {
+    if rlimits.is_empty() {
+        return;
+    }
+    // Copied before forking, since allocating isn't safe in the child.
+    let rlimits = rlimits.to_vec();
+    unsafe {
+        command.pre_exec(move || {
+            for rlimit in &rlimits {
+                setrlimit(rlimit.resource, rlimit.soft_limit, rlimit.hard_limit)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rlimits_config(name: &str, rlimit_config: RlimitConfig) -> RlimitsConfig {
+        RlimitsConfig::from([(name.to_string(), rlimit_config)])
+    }
+
+    fn keyword(keyword: &str) -> RlimitValue {
+        RlimitValue::Keyword(keyword.to_string())
+    }
+
+    fn current_hard_limit(resource: Resource) -> rlim_t {
+        getrlimit(resource).unwrap().1
+    }
+
+    #[test]
+    fn parses_limits() {
+        let hard_limit = current_hard_limit(Resource::RLIMIT_NOFILE);
+        let rlimits = parse_rlimits(&rlimits_config(
+            "nofile",
+            RlimitConfig::Limit(RlimitValue::Value(hard_limit as u64)),
+        ))
+        .unwrap();
+        assert_eq!(rlimits.len(), 1);
+        assert_eq!(rlimits[0].resource, Resource::RLIMIT_NOFILE);
+        assert_eq!(rlimits[0].soft_limit, hard_limit);
+        assert_eq!(rlimits[0].hard_limit, hard_limit);
+
+        let rlimits = parse_rlimits(&rlimits_config(
+            "nofile",
+            RlimitConfig::SoftHard {
+                soft: RlimitValue::Value(16),
+                hard: RlimitValue::Value(hard_limit as u64),
+            },
+        ))
+        .unwrap();
+        assert_eq!(rlimits[0].soft_limit, 16);
+        assert_eq!(rlimits[0].hard_limit, hard_limit);
+    }
+
+    #[test]
+    fn parses_unlimited_and_infinity() {
+        assert_eq!(
+            parse_rlimit_value("core", &keyword("unlimited")),
+            Ok(RLIM_INFINITY)
+        );
+        assert_eq!(
+            parse_rlimit_value("core", &keyword("infinity")),
+            Ok(RLIM_INFINITY)
+        );
+        let error = parse_rlimit_value("core", &keyword("lots")).unwrap_err();
+        assert!(
+            error.contains("Invalid value of rlimit core: lots"),
+            "{error}"
+        );
+
+        // Unlimited is only below chayd's hard limit if that is unlimited too.
+        let result = parse_rlimits(&rlimits_config(
+            "core",
+            RlimitConfig::SoftHard {
+                soft: RlimitValue::Value(0),
+                hard: keyword("unlimited"),
+            },
+        ));
+        if current_hard_limit(Resource::RLIMIT_CORE) == RLIM_INFINITY
+            || nix::unistd::geteuid().is_root()
+        {
+            let rlimits = result.unwrap();
+            assert_eq!(rlimits[0].soft_limit, 0);
+            assert_eq!(rlimits[0].hard_limit, RLIM_INFINITY);
+        } else {
+            assert!(result.unwrap_err().contains("above chayd's hard limit"));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_rlimits() {
+        let error = parse_rlimits(&rlimits_config(
+            "nofiles",
+            RlimitConfig::Limit(RlimitValue::Value(1024)),
+        ))
+        .unwrap_err();
+        assert_eq!(error, "Unknown rlimit: nofiles");
+    }
+
+    #[test]
+    fn rejects_soft_limit_above_hard_limit() {
+        let error = parse_rlimits(&rlimits_config(
+            "nofile",
+            RlimitConfig::SoftHard {
+                soft: RlimitValue::Value(64),
+                hard: RlimitValue::Value(32),
+            },
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Soft limit of rlimit nofile is above its hard limit: 64 > 32"
+        );
+        let error = parse_rlimits(&rlimits_config(
+            "nofile",
+            RlimitConfig::SoftHard {
+                soft: keyword("infinity"),
+                hard: RlimitValue::Value(32),
+            },
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Soft limit of rlimit nofile is above its hard limit: unlimited > 32"
+        );
+    }
+
+    #[test]
+    fn rejects_limit_above_chayd_hard_limit_unless_root() {
+        let hard_limit = current_hard_limit(Resource::RLIMIT_NOFILE);
+        if hard_limit == RLIM_INFINITY {
+            // Nothing is above it.
+            return;
+        }
+        let result = parse_rlimits(&rlimits_config(
+            "nofile",
+            RlimitConfig::Limit(RlimitValue::Value(hard_limit as u64 + 1)),
+        ));
+        if nix::unistd::geteuid().is_root() {
+            assert_eq!(result.unwrap()[0].hard_limit, hard_limit + 1);
+        } else {
+            assert_eq!(
+                result.unwrap_err(),
+                format!(
+                    "Rlimit nofile is above chayd's hard limit: {} > {hard_limit}",
+                    hard_limit + 1
+                )
+            );
+        }
+    }
+}
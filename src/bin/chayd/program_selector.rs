@@ -0,0 +1,361 @@
+use crate::program_fsm::{ProgramFsm, ProgramState, ProgramTransition};
+use std::collections::BTreeMap;
+use wildmatch::WildMatch;
+
+const REGEX_PREFIX: &str = "re:";
+const STATE_PREFIX: &str = "state=";
+const LABEL_PREFIX: &str = "label:";
+const GROUP_PREFIX: &str = "group:";
+
+/// What a program selector is matched against. The state is unknown for programs that don't
+/// exist yet, e.g. when checking permissions to add a program, so state terms never match them.
+#[derive(Clone, Debug)]
+pub struct SelectorTarget {
+    pub name: String,
+    pub state: Option<ProgramState>,
+    pub labels: BTreeMap<String, String>,
+    pub groups: Vec<String>,
+}
+
+impl SelectorTarget {
+    /// For programs that are only known by name.
+    pub fn from_name(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            state: None,
+            labels: BTreeMap::new(),
+            groups: vec![],
+        }
+    }
+
+    pub fn from_program_config(name: &str, program_config: &crate::config::ProgramConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            state: None,
+            labels: program_config.labels.clone(),
+            groups: program_config.groups.clone(),
+        }
+    }
+
+    pub fn from_program_fsm(program_fsm: &ProgramFsm) -> Self {
+        let program_ctx = program_fsm.app_context();
+        Self {
+            state: Some(program_fsm.current_state_key()),
+            ..Self::from_program_config(&program_ctx.name, &program_ctx.config.program)
+        }
+    }
+
+    /// The state is the one the program transitioned to.
+    pub fn from_program_transition(program_transition: &ProgramTransition) -> Self {
+        Self {
+            name: program_transition.program_name.clone(),
+            state: Some(program_transition.to_state.clone()),
+            labels: program_transition.labels.clone(),
+            groups: program_transition.groups.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    All,
+    Glob(WildMatch),
+    Regex(regex::Regex),
+    State(ProgramState),
+    /// Matches any value if None.
+    Label {
+        key: String,
+        value: Option<String>,
+    },
+    Group(String),
+}
+
+impl Matcher {
+    fn parse(term: &str) -> Result<Self, String> {
+        if term == "all" {
+            return Ok(Matcher::All);
+        }
+        if let Some(pattern) = term.strip_prefix(REGEX_PREFIX) {
+            return match regex::Regex::new(pattern) {
+                Ok(regex) => Ok(Matcher::Regex(regex)),
+                Err(error) => Err(format!("invalid regex \"{pattern}\": {error}")),
+            };
+        }
+        if let Some(state) = term.strip_prefix(STATE_PREFIX) {
+            return Ok(Matcher::State(parse_state(state)?));
+        }
+        if let Some(label) = term.strip_prefix(LABEL_PREFIX) {
+            let (key, value) = match label.split_once('=') {
+                Some((key, value)) => (key, Some(value.to_string())),
+                None => (label, None),
+            };
+            if key.is_empty() {
+                return Err(format!("missing label key in \"{term}\""));
+            }
+            return Ok(Matcher::Label {
+                key: key.to_string(),
+                value,
+            });
+        }
+        if let Some(group) = term.strip_prefix(GROUP_PREFIX) {
+            if group.is_empty() {
+                return Err(format!("missing group name in \"{term}\""));
+            }
+            return Ok(Matcher::Group(group.to_string()));
+        }
+        if term.contains(char::is_whitespace) {
+            return Err(format!("unexpected whitespace in \"{term}\""));
+        }
+        Ok(Matcher::Glob(WildMatch::new(term)))
+    }
+
+    fn matches(&self, target: &SelectorTarget) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Glob(glob) => glob.matches(&target.name),
+            Matcher::Regex(regex) => regex.is_match(&target.name),
+            Matcher::State(state) => target.state.as_ref() == Some(state),
+            Matcher::Label { key, value } => match (target.labels.get(key), value) {
+                (Some(label_value), Some(value)) => label_value == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            Matcher::Group(group) => target.groups.contains(group),
+        }
+    }
+}
+
+fn parse_state(state: &str) -> Result<ProgramState, String> {
+    match state.to_lowercase().as_str() {
+        "stopped" => Ok(ProgramState::Stopped),
+        "exited" => Ok(ProgramState::Exited),
+        "backoff" => Ok(ProgramState::Backoff),
+        "starting" => Ok(ProgramState::Starting),
+        "running" => Ok(ProgramState::Running),
+        "stopping" => Ok(ProgramState::Stopping),
+        "exiting" => Ok(ProgramState::Exiting),
+        _ => Err(format!(
+            "unknown state \"{state}\", expected one of stopped, exited, backoff, starting, \
+            running, stopping or exiting"
+        )),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    negated: bool,
+    matcher: Matcher,
+}
+
+/// A parsed program expression. The expression is a comma-separated list of terms, each of which
+/// may be negated with a leading "!":
+///
+/// - `all`: every program.
+/// - `web-*`: program names matching a wildcard pattern.
+/// - `re:^web-\d+$`: program names matching a regex. The regex can't contain commas.
+/// - `state=backoff`: programs in the given state.
+/// - `label:tier=frontend`: programs with the given label value, or `label:tier` for any value.
+/// - `group:frontend`: programs listing the group in their config.
+///
+/// A program is selected if it matches any term that isn't negated and none of the negated terms.
+/// An expression with only negated terms starts from all programs, e.g. `!db` selects every
+/// program except db.
+#[derive(Clone, Debug)]
+pub struct ProgramSelector {
+    expr: String,
+    terms: Vec<Term>,
+}
+
+impl std::str::FromStr for ProgramSelector {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("Invalid program expression \"{expr}\": {reason}");
+        if expr.trim().is_empty() {
+            return Err(invalid("empty expression".to_string()));
+        }
+        let mut terms = vec![];
+        for (index, term) in expr.split(',').enumerate() {
+            let term = term.trim();
+            let (negated, term) = match term.strip_prefix('!') {
+                Some(term) => (true, term.trim_start()),
+                None => (false, term),
+            };
+            if term.is_empty() {
+                return Err(invalid(format!("empty term at position {}", index + 1)));
+            }
+            terms.push(Term {
+                negated,
+                matcher: Matcher::parse(term).map_err(invalid)?,
+            });
+        }
+        Ok(Self {
+            expr: expr.to_string(),
+            terms,
+        })
+    }
+}
+
+impl std::fmt::Display for ProgramSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl ProgramSelector {
+    pub fn matches(&self, target: &SelectorTarget) -> bool {
+        let mut has_positive_terms = false;
+        let mut positive_match = false;
+        for term in &self.terms {
+            let term_matches = term.matcher.matches(target);
+            if term.negated {
+                if term_matches {
+                    return false;
+                }
+            } else {
+                has_positive_terms = true;
+                positive_match |= term_matches;
+            }
+        }
+        positive_match || !has_positive_terms
+    }
+
+    /// Returns true if the selector is "all" or "*", i.e. it unconditionally selects every program.
+    pub fn is_all(&self) -> bool {
+        match self.terms.as_slice() {
+            [Term {
+                negated: false,
+                matcher: Matcher::All,
+            }] => true,
+            [Term {
+                negated: false,
+                matcher: Matcher::Glob(_),
+            }] => self.expr.trim() == "*",
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str) -> SelectorTarget {
+        SelectorTarget::from_name(name)
+    }
+
+    fn selector(expr: &str) -> ProgramSelector {
+        expr.parse().unwrap()
+    }
+
+    fn parse_error(expr: &str) -> String {
+        expr.parse::<ProgramSelector>().unwrap_err()
+    }
+
+    #[test]
+    fn matches_globs() {
+        let web = selector("web-*");
+        assert!(web.matches(&target("web-1")));
+        assert!(!web.matches(&target("db")));
+        assert!(selector("db").matches(&target("db")));
+        assert!(!selector("db").matches(&target("db-replica")));
+    }
+
+    #[test]
+    fn matches_regexes() {
+        let web = selector(r"re:^web-\d+$");
+        assert!(web.matches(&target("web-12")));
+        assert!(!web.matches(&target("web-a")));
+        assert!(!web.matches(&target("old-web-1")));
+    }
+
+    #[test]
+    fn matches_states() {
+        let mut running = target("web");
+        running.state = Some(ProgramState::Running);
+        let mut stopped = target("web");
+        stopped.state = Some(ProgramState::Stopped);
+        assert!(selector("state=running").matches(&running));
+        assert!(selector("state=RUNNING").matches(&running));
+        assert!(!selector("state=running").matches(&stopped));
+        // Programs that don't exist yet have no state.
+        assert!(!selector("state=stopped").matches(&target("web")));
+    }
+
+    #[test]
+    fn matches_labels() {
+        let mut web = target("web");
+        web.labels
+            .insert("tier".to_string(), "frontend".to_string());
+        assert!(selector("label:tier=frontend").matches(&web));
+        assert!(!selector("label:tier=backend").matches(&web));
+        assert!(selector("label:tier").matches(&web));
+        assert!(!selector("label:team").matches(&web));
+        assert!(!selector("label:tier").matches(&target("db")));
+    }
+
+    #[test]
+    fn matches_groups() {
+        let mut web = target("web");
+        web.groups = vec!["frontend".to_string()];
+        assert!(selector("group:frontend").matches(&web));
+        assert!(!selector("group:backend").matches(&web));
+        assert!(!selector("group:frontend").matches(&target("db")));
+    }
+
+    #[test]
+    fn matches_any_term_of_comma_lists() {
+        let web_or_db = selector("web-*, db");
+        assert!(web_or_db.matches(&target("web-1")));
+        assert!(web_or_db.matches(&target("db")));
+        assert!(!web_or_db.matches(&target("cache")));
+    }
+
+    #[test]
+    fn excludes_negated_terms() {
+        let web_except_2 = selector("web-*,!web-2");
+        assert!(web_except_2.matches(&target("web-1")));
+        assert!(!web_except_2.matches(&target("web-2")));
+        assert!(!web_except_2.matches(&target("db")));
+    }
+
+    #[test]
+    fn negation_only_expressions_start_from_all_programs() {
+        let not_db = selector("!db");
+        assert!(not_db.matches(&target("web")));
+        assert!(!not_db.matches(&target("db")));
+        let neither = selector("!db, ! cache");
+        assert!(neither.matches(&target("web")));
+        assert!(!neither.matches(&target("cache")));
+    }
+
+    #[test]
+    fn is_all_only_for_unconditional_expressions() {
+        assert!(selector("all").is_all());
+        assert!(selector("*").is_all());
+        assert!(selector(" * ").is_all());
+        assert!(!selector("all,!db").is_all());
+        assert!(!selector("!db").is_all());
+        assert!(!selector("web-*").is_all());
+        assert!(!selector("*,db").is_all());
+    }
+
+    #[test]
+    fn rejects_empty_expressions_and_terms() {
+        assert!(parse_error("").contains("empty expression"));
+        assert!(parse_error("  ").contains("empty expression"));
+        assert!(parse_error("web,,db").contains("empty term at position 2"));
+        assert!(parse_error("web,").contains("empty term at position 2"));
+        assert!(parse_error("!").contains("empty term at position 1"));
+    }
+
+    #[test]
+    fn rejects_invalid_terms() {
+        assert!(parse_error("re:(").contains("invalid regex"));
+        assert!(parse_error("state=sleeping").contains("unknown state \"sleeping\""));
+        assert!(parse_error("label:").contains("missing label key"));
+        assert!(parse_error("label:=frontend").contains("missing label key"));
+        assert!(parse_error("group:").contains("missing group name"));
+        assert!(parse_error("web 1").contains("unexpected whitespace"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::program_fsm::ProgramFsm;
+use std::collections::HashMap;
+
+/// Resource usage of a program's process and all of its descendants.
+#[derive(Clone, Debug, Default)]
+pub struct ResourceUsage {
+    /// Percent of one CPU used since the previous sample, e.g. 200 for two busy CPUs.
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub num_threads: u32,
+    pub num_fds: u32,
+    /// Bytes the processes caused to be read from and written to storage.
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub num_processes: u32,
+}
+
+/// The most recent resource usage of a program, kept in its Program.
+#[derive(Debug, Default)]
+pub struct ProgramUsage {
+    /// Only set while the program's process is running.
+    pub current: Option<ResourceUsage>,
+    /// Pid and time of the previous sample and the CPU ticks used by then, to compute the CPU %.
+    cpu_sample: Option<(i32, std::time::Instant, u64)>,
+}
+
+impl ProgramUsage {
+    /// Time of the most recent sample, to tell samples apart.
+    pub fn sample_time(&self) -> Option<std::time::Instant> {
+        self.cpu_sample.map(|(_, sample_time, _)| sample_time)
+    }
+}
+
+/// Fields of /proc/<pid>/stat.
+struct ProcessStat {
+    ppid: i32,
+    /// CPU time of the process and its reaped children, in clock ticks.
+    cpu_ticks: u64,
+}
+
+fn read_process_stat(pid: i32) -> Option<ProcessStat> {
+    parse_process_stat(&std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?)
+}
+
+fn parse_process_stat(stat: &str) -> Option<ProcessStat> {
+    // The command name is in parentheses and may contain spaces, so split after it. The first
+    // field after it is the state, which is field 3 in proc(5).
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |number: usize| -> Option<u64> { fields.get(number - 3)?.parse().ok() };
+    Some(ProcessStat {
+        ppid: fields.get(1)?.parse().ok()?,
+        // utime, stime, cutime and cstime.
+        cpu_ticks: field(14)? + field(15)? + field(16)? + field(17)?,
+    })
+}
+
+/// Reads a "Key: value" line of /proc/<pid>/status or /proc/<pid>/io.
+fn read_proc_value(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix(':')?;
+        value.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// Adds the usage of a single process, skipping what can't be read, e.g. the io of a process of
+/// another user when chayd isn't root.
+fn add_process_usage(pid: i32, resource_usage: &mut ResourceUsage) {
+    if let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        resource_usage.rss_bytes += read_proc_value(&status, "VmRSS").unwrap_or(0) * 1024;
+        resource_usage.num_threads += read_proc_value(&status, "Threads").unwrap_or(0) as u32;
+    }
+    if let Ok(io) = std::fs::read_to_string(format!("/proc/{pid}/io")) {
+        resource_usage.read_bytes += read_proc_value(&io, "read_bytes").unwrap_or(0);
+        resource_usage.write_bytes += read_proc_value(&io, "write_bytes").unwrap_or(0);
+    }
+    if let Ok(fds) = std::fs::read_dir(format!("/proc/{pid}/fd")) {
+        resource_usage.num_fds += fds.count() as u32;
+    }
+    resource_usage.num_processes += 1;
+}
+
+/// Reads the stat of every process, since finding the descendants of a process needs the parent
+/// of every process.
+fn read_process_stats() -> HashMap<i32, ProcessStat> {
+    let mut process_stats = HashMap::new();
+    let proc_entries = match std::fs::read_dir("/proc") {
+        Ok(proc_entries) => proc_entries,
+        Err(error) => {
+            log::error!("Could not read /proc: {}", error);
+            return process_stats;
+        }
+    };
+    for proc_entry in proc_entries.flatten() {
+        let pid = match proc_entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // The process may have exited since /proc was listed.
+        if let Some(process_stat) = read_process_stat(pid) {
+            process_stats.insert(pid, process_stat);
+        }
+    }
+    process_stats
+}
+
+fn clock_ticks_per_sec() -> u64 {
+    match nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK) {
+        Ok(Some(clock_ticks_per_sec)) if clock_ticks_per_sec > 0 => clock_ticks_per_sec as u64,
+        // The value on every Linux platform.
+        _ => 100,
+    }
+}
+
+/// Samples the resource usage of every running program, called on every FSM update.
+///
+/// This reads the stat of every process in /proc on each call while any program is running,
+/// whether or not a client or watchdog looks at the usage. That cost is intended: a status may
+/// be requested at any time, and the CPU % needs the previous sample to be recent.
+pub fn sample_program_usages<'a>(program_fsms: impl Iterator<Item = &'a mut ProgramFsm>) {
+    let mut programs: Vec<&mut crate::program::Program> = program_fsms
+        .map(|program_fsm| &mut program_fsm.app_context_mut().program.program)
+        .collect();
+    for program in &mut programs {
+        if program.pid().is_none() {
+            program.usage = ProgramUsage::default();
+        }
+    }
+    if programs.iter().all(|program| program.pid().is_none()) {
+        return;
+    }
+    let process_stats = read_process_stats();
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (pid, process_stat) in &process_stats {
+        children.entry(process_stat.ppid).or_default().push(*pid);
+    }
+    let now = std::time::Instant::now();
+    for program in programs {
+        let pid = match program.pid() {
+            Some(pid) => pid as i32,
+            None => continue,
+        };
+        let mut resource_usage = ResourceUsage::default();
+        let mut cpu_ticks = 0u64;
+        let mut pids = vec![pid];
+        while let Some(descendant_pid) = pids.pop() {
+            let process_stat = match process_stats.get(&descendant_pid) {
+                Some(process_stat) => process_stat,
+                None => continue,
+            };
+            cpu_ticks += process_stat.cpu_ticks;
+            add_process_usage(descendant_pid, &mut resource_usage);
+            pids.extend(children.get(&descendant_pid).into_iter().flatten());
+        }
+        // The previous sample is of another process if the program was restarted in between. The
+        // CPU time of descendants that exited is lost until they are reaped, hence the
+        // saturating_sub.
+        if let Some((_, sample_time, sample_cpu_ticks)) = program
+            .usage
+            .cpu_sample
+            .filter(|(sample_pid, _, _)| *sample_pid == pid)
+        {
+            let elapsed_secs = (now - sample_time).as_secs_f64();
+            if elapsed_secs > 0.0 {
+                let cpu_secs = cpu_ticks.saturating_sub(sample_cpu_ticks) as f64
+                    / clock_ticks_per_sec() as f64;
+                resource_usage.cpu_percent = cpu_secs / elapsed_secs * 100.0;
+            }
+        }
+        program.usage = ProgramUsage {
+            current: Some(resource_usage),
+            cpu_sample: Some((pid, now, cpu_ticks)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fields after the command name, from the state (field 3) to the cstime (field 17).
+    const STAT_FIELDS: &str = "S 1 42 42 0 -1 4194560 1000 0 0 0 150 25 7 3";
+
+    #[test]
+    fn parses_process_stats() {
+        let process_stat =
+            parse_process_stat(&format!("42 (sleep) {STAT_FIELDS} 20 0 1 0")).unwrap();
+        assert_eq!(process_stat.ppid, 1);
+        assert_eq!(process_stat.cpu_ticks, 150 + 25 + 7 + 3);
+    }
+
+    #[test]
+    fn parses_process_stats_with_spaces_and_parentheses_in_the_command_name() {
+        let process_stat =
+            parse_process_stat(&format!("42 (my (odd) cmd) {STAT_FIELDS} 20 0 1 0")).unwrap();
+        assert_eq!(process_stat.ppid, 1);
+        assert_eq!(process_stat.cpu_ticks, 185);
+    }
+
+    #[test]
+    fn rejects_truncated_process_stats() {
+        assert!(parse_process_stat("42 (sleep) S 1 42").is_none());
+        assert!(parse_process_stat("42 sleep").is_none());
+    }
+
+    #[test]
+    fn reads_the_stat_of_this_process() {
+        let process_stat = read_process_stat(std::process::id() as i32).unwrap();
+        assert_eq!(process_stat.ppid, nix::unistd::getppid().as_raw());
+    }
+
+    #[test]
+    fn reads_proc_values() {
+        let status = "Name:\tsleep\nVmRSS:\t    2048 kB\nThreads:\t3\n";
+        assert_eq!(read_proc_value(status, "VmRSS"), Some(2048));
+        assert_eq!(read_proc_value(status, "Threads"), Some(3));
+        assert_eq!(read_proc_value(status, "VmSwap"), None);
+        let io = "rchar: 100\nread_bytes: 4096\nwrite_bytes: 0\ncancelled_write_bytes: 512\n";
+        assert_eq!(read_proc_value(io, "read_bytes"), Some(4096));
+        // Keys are matched in full, not as a suffix or prefix of another key.
+        assert_eq!(read_proc_value(io, "write_bytes"), Some(0));
+        assert_eq!(read_proc_value(io, "char"), None);
+    }
+}
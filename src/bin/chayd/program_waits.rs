@@ -0,0 +1,234 @@
+use crate::chayd_service_impl::ProgramEventsResult;
+use crate::program_fsm::{ProgramEvent, ProgramFsm, ProgramState};
+use std::collections::HashMap;
+
+/// State a program event is expected to lead to.
+#[derive(Clone, Copy, Debug)]
+enum WaitTarget {
+    Running,
+    Stopped,
+}
+
+impl WaitTarget {
+    fn from_program_event(program_event: &ProgramEvent) -> Option<Self> {
+        match program_event {
+            ProgramEvent::Start | ProgramEvent::Restart => Some(WaitTarget::Running),
+            ProgramEvent::Stop => Some(WaitTarget::Stopped),
+            ProgramEvent::Reload => None,
+        }
+    }
+}
+
+struct ProgramWait {
+    target: WaitTarget,
+    /// The program's backoff_end_time when the wait started. A program that is still in backoff
+    /// from before the event, e.g. while cleaning up before a restart, hasn't failed yet.
+    backoff_end_time: Option<std::time::SystemTime>,
+}
+
+/// Results of a program events request that are held back until every program reached the state
+/// its event leads to, failed to, or the timeout elapsed.
+struct PendingWait {
+    program_waits: HashMap<String, ProgramWait>,
+    results: HashMap<String, chay::fsm::MachineResult>,
+    deadline: Option<std::time::Instant>,
+    results_sender: tokio::sync::mpsc::Sender<ProgramEventsResult>,
+}
+
+#[derive(Default)]
+pub struct ProgramWaits {
+    pending_waits: Vec<PendingWait>,
+}
+
+/// Returns the final result of the wait if the program reached the target state or failed to.
+fn program_wait_result(
+    program_fsm: &ProgramFsm,
+    program_wait: &ProgramWait,
+) -> Option<chay::fsm::MachineResult> {
+    let program_ctx = program_fsm.app_context();
+    let state = program_fsm.current_state_key();
+    match (program_wait.target, &state) {
+        (WaitTarget::Running, ProgramState::Running)
+        | (WaitTarget::Stopped, ProgramState::Stopped | ProgramState::Exited) => {
+            Some(Ok(Some(format!("{:?}", state))))
+        }
+        (WaitTarget::Running, ProgramState::Backoff)
+            if program_ctx.backoff_end_time == program_wait.backoff_end_time =>
+        {
+            None
+        }
+        (
+            WaitTarget::Running,
+            ProgramState::Backoff
+            | ProgramState::Exiting
+            | ProgramState::Exited
+            | ProgramState::Stopped,
+        ) => match &program_ctx.last_error {
+            Some(last_error) => Some(Err(format!(
+                "Failed to start ({:?}): {}",
+                state, last_error
+            ))),
+            None => Some(Err(format!("Failed to start ({:?})", state))),
+        },
+        _ => None,
+    }
+}
+
+impl ProgramWaits {
+    /// Holds back the results until the programs whose event succeeded reach the state the event
+    /// leads to. The results are sent by a later call to check.
+    pub fn add(
+        &mut self,
+        program_fsms: &[ProgramFsm],
+        program_event: &ProgramEvent,
+        results: HashMap<String, chay::fsm::MachineResult>,
+        timeout: Option<std::time::Duration>,
+        results_sender: tokio::sync::mpsc::Sender<ProgramEventsResult>,
+    ) {
+        let mut program_waits = HashMap::new();
+        if let Some(target) = WaitTarget::from_program_event(program_event) {
+            for program_fsm in program_fsms {
+                let program_ctx = program_fsm.app_context();
+                if let Some(Ok(_)) = results.get(&program_ctx.name) {
+                    program_waits.insert(
+                        program_ctx.name(),
+                        ProgramWait {
+                            target,
+                            backoff_end_time: program_ctx.backoff_end_time,
+                        },
+                    );
+                }
+            }
+        }
+        self.pending_waits.push(PendingWait {
+            program_waits,
+            results,
+            deadline: timeout.map(|timeout| std::time::Instant::now() + timeout),
+            results_sender,
+        });
+    }
+
+    /// Sends the results of every wait that is done.
+    pub async fn check(&mut self, program_fsms: &[ProgramFsm]) {
+        let now = std::time::Instant::now();
+        let mut done_waits = vec![];
+        let mut pending_waits = vec![];
+        for mut pending_wait in self.pending_waits.drain(..) {
+            let timed_out = pending_wait
+                .deadline
+                .is_some_and(|deadline| now >= deadline);
+            let PendingWait {
+                program_waits,
+                results,
+                ..
+            } = &mut pending_wait;
+            program_waits.retain(|program_name, program_wait| {
+                let program_fsm = program_fsms
+                    .iter()
+                    .find(|program_fsm| &program_fsm.app_context().name == program_name);
+                let result = match program_fsm {
+                    Some(program_fsm) => match program_wait_result(program_fsm, program_wait) {
+                        Some(result) => result,
+                        None if timed_out => Err(format!(
+                            "Timed out waiting for {:?} (was {:?})",
+                            program_wait.target,
+                            program_fsm.current_state_key()
+                        )),
+                        None => return true,
+                    },
+                    None => Err("Program was removed".to_string()),
+                };
+                results.insert(program_name.clone(), result);
+                false
+            });
+            if pending_wait.program_waits.is_empty() {
+                done_waits.push(pending_wait);
+            } else {
+                pending_waits.push(pending_wait);
+            }
+        }
+        self.pending_waits = pending_waits;
+        for done_wait in done_waits {
+            match done_wait.results_sender.send(Ok(done_wait.results)).await {
+                Ok(_) => {}
+                // The connection was probably closed by the client.
+                Err(_) => log::warn!("Could not send program events results"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PreCommandConfig, ProgramConfig, RenderedProgramConfig};
+
+    fn update_until(program_fsm: &mut ProgramFsm, state: ProgramState) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while program_fsm.current_state_key() != state {
+            assert!(std::time::Instant::now() < deadline, "Never {:?}", state);
+            program_fsm.update();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_restarts_after_failed_starts() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        let config = RenderedProgramConfig {
+            program: ProgramConfig {
+                command: "sleep".to_string(),
+                args: Some(vec!["10".to_string()]),
+                // Fails the first start only.
+                pre_command: Some(PreCommandConfig {
+                    command: "sh".to_string(),
+                    args: Some(vec![
+                        "-c".to_string(),
+                        format!("test -e {0} || {{ touch {0}; exit 1; }}", marker.display()),
+                    ]),
+                    timeout_secs: 10,
+                    ..Default::default()
+                }),
+                autostart: true,
+                sigkill_delay_secs: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (program_transitions_tx, _) = tokio::sync::broadcast::channel(100);
+        let mut program_fsms = vec![crate::program_fsm::new_program_fsm(
+            "sleeper".to_string(),
+            &config,
+            program_transitions_tx,
+        )];
+        update_until(&mut program_fsms[0], ProgramState::Exited);
+        assert!(program_fsms[0].app_context().last_error.is_some());
+
+        let mut program_waits = ProgramWaits::default();
+        let (results_sender, mut results_receiver) = tokio::sync::mpsc::channel(1);
+        let result = program_fsms[0].react(&ProgramEvent::Restart);
+        program_waits.add(
+            &program_fsms,
+            &ProgramEvent::Restart,
+            HashMap::from([("sleeper".to_string(), result)]),
+            None,
+            results_sender,
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let results = loop {
+            assert!(std::time::Instant::now() < deadline, "Wait never finished");
+            program_fsms[0].update();
+            program_waits.check(&program_fsms).await;
+            if let Ok(results) = results_receiver.try_recv() {
+                break results.unwrap();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(results["sleeper"], Ok(Some("Running".to_string())));
+        assert_eq!(program_fsms[0].app_context().last_error, None);
+
+        program_fsms[0].react(&ProgramEvent::Stop).unwrap();
+        update_until(&mut program_fsms[0], ProgramState::Stopped);
+    }
+}
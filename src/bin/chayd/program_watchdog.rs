@@ -0,0 +1,178 @@
+use crate::config::WatchdogConfig;
+use crate::program_usage::{ProgramUsage, ResourceUsage};
+
+/// Counts the consecutive resource usage samples of a running program that exceeded a limit of
+/// its watchdog config.
+#[derive(Debug, Default)]
+pub struct ProgramWatchdog {
+    /// Time of the last sample that was checked, since the FSM may be updated more than once per
+    /// sample.
+    last_sample_time: Option<std::time::Instant>,
+    num_exceeded_samples: u32,
+    /// Set once the watchdog triggered, so it only triggers again after a sample within limits.
+    triggered: bool,
+}
+
+/// Describes the limits the sample exceeded, e.g. "open fds 1100 > 1024".
+fn exceeded_limits(
+    watchdog_config: &WatchdogConfig,
+    resource_usage: &ResourceUsage,
+) -> Vec<String> {
+    let mut exceeded_limits = vec![];
+    if let Some(max_rss_bytes) = watchdog_config.max_rss_bytes {
+        if resource_usage.rss_bytes > max_rss_bytes {
+            exceeded_limits.push(format!(
+                "rss {} > {} bytes",
+                resource_usage.rss_bytes, max_rss_bytes
+            ));
+        }
+    }
+    if let Some(max_cpu_percent) = watchdog_config.max_cpu_percent {
+        if resource_usage.cpu_percent > max_cpu_percent {
+            exceeded_limits.push(format!(
+                "cpu {:.1}% > {:.1}%",
+                resource_usage.cpu_percent, max_cpu_percent
+            ));
+        }
+    }
+    if let Some(max_open_fds) = watchdog_config.max_open_fds {
+        if resource_usage.num_fds > max_open_fds {
+            exceeded_limits.push(format!(
+                "open fds {} > {}",
+                resource_usage.num_fds, max_open_fds
+            ));
+        }
+    }
+    exceeded_limits
+}
+
+impl ProgramWatchdog {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Checks the program's latest sample, if it wasn't checked yet. Returns a message describing
+    /// the exceeded limits once a limit was exceeded for num_samples consecutive samples.
+    pub fn check(
+        &mut self,
+        watchdog_config: &WatchdogConfig,
+        program_usage: &ProgramUsage,
+    ) -> Option<String> {
+        let resource_usage = program_usage.current.as_ref()?;
+        self.check_sample(watchdog_config, resource_usage, program_usage.sample_time())
+    }
+
+    fn check_sample(
+        &mut self,
+        watchdog_config: &WatchdogConfig,
+        resource_usage: &ResourceUsage,
+        sample_time: Option<std::time::Instant>,
+    ) -> Option<String> {
+        if sample_time == self.last_sample_time {
+            return None;
+        }
+        self.last_sample_time = sample_time;
+        let mut exceeded_limits = exceeded_limits(watchdog_config, resource_usage);
+        if exceeded_limits.is_empty() {
+            self.num_exceeded_samples = 0;
+            self.triggered = false;
+            return None;
+        }
+        self.num_exceeded_samples += 1;
+        if self.triggered || self.num_exceeded_samples < watchdog_config.num_samples {
+            return None;
+        }
+        self.triggered = true;
+        exceeded_limits.push(format!("for {} samples", self.num_exceeded_samples));
+        Some(exceeded_limits.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog_config() -> WatchdogConfig {
+        WatchdogConfig {
+            max_open_fds: Some(10),
+            num_samples: 3,
+            ..Default::default()
+        }
+    }
+
+    /// Checks the next sample, taken an FSM update after the previous one, with the given number
+    /// of open fds.
+    fn check(watchdog: &mut ProgramWatchdog, num_fds: u32) -> Option<String> {
+        let resource_usage = ResourceUsage {
+            num_fds,
+            ..Default::default()
+        };
+        let sample_time = watchdog
+            .last_sample_time
+            .map_or_else(std::time::Instant::now, |last_sample_time| {
+                last_sample_time + std::time::Duration::from_millis(500)
+            });
+        watchdog.check_sample(&watchdog_config(), &resource_usage, Some(sample_time))
+    }
+
+    #[test]
+    fn triggers_after_consecutive_exceeded_samples() {
+        let mut watchdog = ProgramWatchdog::default();
+        assert_eq!(check(&mut watchdog, 11), None);
+        assert_eq!(check(&mut watchdog, 11), None);
+        // A sample within limits starts the count over.
+        assert_eq!(check(&mut watchdog, 10), None);
+        assert_eq!(check(&mut watchdog, 11), None);
+        assert_eq!(check(&mut watchdog, 11), None);
+        assert_eq!(
+            check(&mut watchdog, 12),
+            Some("open fds 12 > 10, for 3 samples".to_string())
+        );
+    }
+
+    #[test]
+    fn triggers_again_only_after_a_sample_within_limits() {
+        let mut watchdog = ProgramWatchdog::default();
+        for _ in 0..2 {
+            check(&mut watchdog, 11);
+        }
+        assert!(check(&mut watchdog, 11).is_some());
+        assert_eq!(check(&mut watchdog, 11), None);
+        assert_eq!(check(&mut watchdog, 11), None);
+        assert_eq!(check(&mut watchdog, 11), None);
+
+        assert_eq!(check(&mut watchdog, 0), None);
+        for _ in 0..2 {
+            assert_eq!(check(&mut watchdog, 11), None);
+        }
+        assert!(check(&mut watchdog, 11).is_some());
+    }
+
+    #[test]
+    fn checks_each_sample_once() {
+        let mut watchdog = ProgramWatchdog::default();
+        let resource_usage = ResourceUsage {
+            num_fds: 11,
+            ..Default::default()
+        };
+        let sample_time = Some(std::time::Instant::now());
+        for _ in 0..3 {
+            assert_eq!(
+                watchdog.check_sample(&watchdog_config(), &resource_usage, sample_time),
+                None
+            );
+        }
+        assert_eq!(watchdog.num_exceeded_samples, 1);
+    }
+
+    #[test]
+    fn ignores_programs_without_a_sample() {
+        let mut watchdog = ProgramWatchdog::default();
+        let config = WatchdogConfig {
+            num_samples: 1,
+            ..watchdog_config()
+        };
+        assert_eq!(watchdog.check(&config, &ProgramUsage::default()), None);
+        assert_eq!(watchdog.num_exceeded_samples, 0);
+    }
+}
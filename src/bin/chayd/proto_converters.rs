@@ -1,8 +1,10 @@
-use crate::{chay_proto, program_fsm};
+use crate::{chay_proto, program, program_changes, program_context, program_fsm, program_usage};
 use chay_proto::{
-    ChaydServiceRestartResponse, ChaydServiceStartResponse, ChaydServiceStopResponse,
+    ChaydServiceReloadConfigResponse, ChaydServiceReloadResponse, ChaydServiceRestartResponse,
+    ChaydServiceSignalResponse, ChaydServiceStartResponse, ChaydServiceStopResponse,
 };
 use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
 
 pub fn proto_from_program_state(
     program_state: program_fsm::ProgramState,
@@ -18,6 +20,142 @@ pub fn proto_from_program_state(
     }
 }
 
+pub fn signal_target_from_proto(
+    signal_target: chay_proto::SignalTarget,
+) -> program_context::SignalTarget {
+    match signal_target {
+        chay_proto::SignalTarget::Unspecified | chay_proto::SignalTarget::Program => {
+            program_context::SignalTarget::Program
+        }
+        chay_proto::SignalTarget::Logger => program_context::SignalTarget::Logger,
+        chay_proto::SignalTarget::PreCommand => program_context::SignalTarget::PreCommand,
+        chay_proto::SignalTarget::Group => program_context::SignalTarget::Group,
+    }
+}
+
+pub fn proto_from_program_exit(program_exit: &program::ProgramExit) -> chay_proto::ProgramExit {
+    let status = match program_exit.status.code() {
+        Some(code) => Some(chay_proto::program_exit::Status::Code(code)),
+        None => program_exit
+            .status
+            .signal()
+            .map(chay_proto::program_exit::Status::Signal),
+    };
+    let rusage = program_exit.rusage.as_ref();
+    chay_proto::ProgramExit {
+        status,
+        time: Some(program_exit.time.into()),
+        user_cpu_time: rusage.and_then(|rusage| rusage.user_cpu_time.try_into().ok()),
+        system_cpu_time: rusage.and_then(|rusage| rusage.system_cpu_time.try_into().ok()),
+        max_rss_bytes: rusage.map(|rusage| rusage.max_rss_bytes),
+    }
+}
+
+pub fn proto_from_resource_usage(
+    resource_usage: &program_usage::ResourceUsage,
+) -> chay_proto::ResourceUsage {
+    chay_proto::ResourceUsage {
+        cpu_percent: resource_usage.cpu_percent,
+        rss_bytes: resource_usage.rss_bytes,
+        num_threads: resource_usage.num_threads,
+        num_fds: resource_usage.num_fds,
+        read_bytes: resource_usage.read_bytes,
+        write_bytes: resource_usage.write_bytes,
+        num_processes: resource_usage.num_processes,
+    }
+}
+
+pub fn proto_from_program_transition(
+    program_transition: &program_fsm::ProgramTransition,
+) -> chay_proto::ProgramTransition {
+    let mut proto_transition = chay_proto::ProgramTransition {
+        name: program_transition.program_name.clone(),
+        ..Default::default()
+    };
+    proto_transition.set_from_state(proto_from_program_state(
+        program_transition.from_state.clone(),
+    ));
+    proto_transition.set_to_state(proto_from_program_state(
+        program_transition.to_state.clone(),
+    ));
+    proto_transition.time = Some(program_transition.time.into());
+    let reason = match &program_transition.reason {
+        Some(program_fsm::TransitionReason::UserRequest { action, source }) => {
+            proto_transition.message = action.clone();
+            proto_transition.source = source.clone();
+            chay_proto::program_transition::Reason::UserRequest
+        }
+        Some(program_fsm::TransitionReason::Started) => {
+            chay_proto::program_transition::Reason::Started
+        }
+        Some(program_fsm::TransitionReason::StartFailed(message)) => {
+            proto_transition.message = message.clone();
+            chay_proto::program_transition::Reason::StartFailed
+        }
+        Some(program_fsm::TransitionReason::ProgramExited { program_name, exit }) => {
+            proto_transition.message = program_name.clone();
+            proto_transition.exit = exit.as_ref().map(proto_from_program_exit);
+            chay_proto::program_transition::Reason::ProgramExited
+        }
+        Some(program_fsm::TransitionReason::BackoffElapsed) => {
+            chay_proto::program_transition::Reason::BackoffElapsed
+        }
+        Some(program_fsm::TransitionReason::BackoffSkipped) => {
+            chay_proto::program_transition::Reason::BackoffSkipped
+        }
+        Some(program_fsm::TransitionReason::AllProcessesStopped) => {
+            chay_proto::program_transition::Reason::AllProcessesStopped
+        }
+        Some(program_fsm::TransitionReason::WatchdogTriggered(message)) => {
+            proto_transition.message = message.clone();
+            chay_proto::program_transition::Reason::WatchdogTriggered
+        }
+        None => chay_proto::program_transition::Reason::Unspecified,
+    };
+    proto_transition.set_reason(reason);
+    proto_transition
+}
+
+pub fn proto_program_status_from_program_fsm(
+    program_fsm: &program_fsm::ProgramFsm,
+) -> chay_proto::ProgramStatus {
+    let program_ctx = program_fsm.app_context();
+    let program = &program_ctx.program.program;
+    let mut program_status = chay_proto::ProgramStatus {
+        name: program_ctx.name(),
+        ..Default::default()
+    };
+    program_status.set_state(proto_from_program_state(program_fsm.current_state_key()));
+    program_status.start_time = program.start_time.map(|start_time| start_time.into());
+    program_status.pid = program.pid();
+    if program_status.pid.is_some() {
+        program_status.uptime = program
+            .start_time
+            .and_then(|start_time| start_time.elapsed().ok())
+            .and_then(|uptime| uptime.try_into().ok());
+    }
+    program_status.logger_pid = program_ctx
+        .logger
+        .as_ref()
+        .and_then(|logger| logger.program.pid());
+    program_status.num_restarts = program_ctx.num_restarts;
+    program_status.last_exit = program.last_exit.as_ref().map(proto_from_program_exit);
+    program_status.last_error = program_ctx.last_error.clone().unwrap_or_default();
+    program_status.next_backoff_time = program_ctx
+        .backoff_end_time
+        .map(|backoff_end_time| backoff_end_time.into());
+    let program_config = &program_ctx.config.program;
+    program_status.description = program_config.description.clone().unwrap_or_default();
+    program_status.labels = program_config.labels.clone().into_iter().collect();
+    program_status.groups = program_config.groups.clone();
+    program_status.resource_usage = program
+        .usage
+        .current
+        .as_ref()
+        .map(proto_from_resource_usage);
+    program_status
+}
+
 pub fn proto_program_event_result_from_machine_result(
     machine_result: &chay::fsm::MachineResult,
 ) -> chay_proto::ProgramEventResult {
@@ -88,3 +226,43 @@ pub fn proto_restart_response_from_program_events_results(
         });
     response
 }
+
+pub fn proto_reload_response_from_program_events_results(
+    program_events_results: &HashMap<String, chay::fsm::MachineResult>,
+) -> ChaydServiceReloadResponse {
+    let mut response = ChaydServiceReloadResponse::default();
+    program_events_results
+        .iter()
+        .for_each(|(program_name, machine_result)| {
+            response.program_event_results.insert(
+                program_name.clone(),
+                proto_program_event_result_from_machine_result(machine_result),
+            );
+        });
+    response
+}
+
+pub fn proto_signal_response_from_program_events_results(
+    program_events_results: &HashMap<String, chay::fsm::MachineResult>,
+) -> ChaydServiceSignalResponse {
+    let mut response = ChaydServiceSignalResponse::default();
+    program_events_results
+        .iter()
+        .for_each(|(program_name, machine_result)| {
+            response.program_event_results.insert(
+                program_name.clone(),
+                proto_program_event_result_from_machine_result(machine_result),
+            );
+        });
+    response
+}
+
+pub fn proto_reload_config_response_from_config_changes(
+    config_changes: &program_changes::ConfigChanges,
+) -> ChaydServiceReloadConfigResponse {
+    ChaydServiceReloadConfigResponse {
+        added_programs: config_changes.added_programs.clone(),
+        removed_programs: config_changes.removed_programs.clone(),
+        changed_programs: config_changes.changed_programs.clone(),
+    }
+}
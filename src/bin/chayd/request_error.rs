@@ -0,0 +1,22 @@
+/// Error of a request that is handled outside of its RPC handler, e.g. by the main loop. It is
+/// turned into a tonic::Status by the handler, which is too large to pass around as an error.
+#[derive(Debug)]
+pub struct RequestError {
+    code: tonic::Code,
+    message: String,
+}
+
+impl RequestError {
+    pub fn new(code: tonic::Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<RequestError> for tonic::Status {
+    fn from(error: RequestError) -> Self {
+        tonic::Status::new(error.code, error.message)
+    }
+}
@@ -40,11 +40,17 @@ impl<StateKey> Context<StateKey> for ContextImpl<StateKey> {
 
 pub type MachineResult = std::result::Result<Option<String>, String>;
 
+/// Called after every state change with the old and new state keys, once the new state has been
+/// entered.
+pub type TransitionObserver<StateKey, AppContext> =
+    Box<dyn FnMut(&StateKey, &StateKey, &mut AppContext)>;
+
 pub struct Machine<StateKey, AppContext, Event> {
     app_context: AppContext,
     states: HashMap<StateKey, Box<dyn State<StateKey, AppContext, Event>>>,
     context: ContextImpl<StateKey>,
     first_update: bool,
+    observers: Vec<TransitionObserver<StateKey, AppContext>>,
 }
 
 impl<StateKey, AppContext, Event> Machine<StateKey, AppContext, Event>
@@ -61,9 +67,14 @@ where
             states,
             context: ContextImpl::<StateKey>::new(init_state),
             first_update: true,
+            observers: vec![],
         };
     }
 
+    pub fn add_observer(&mut self, observer: TransitionObserver<StateKey, AppContext>) {
+        self.observers.push(observer);
+    }
+
     pub fn current_state_key(&self) -> StateKey {
         return self.context.current_state_key.clone();
     }
@@ -72,6 +83,10 @@ where
         &self.app_context
     }
 
+    pub fn app_context_mut(&mut self) -> &mut AppContext {
+        &mut self.app_context
+    }
+
     pub fn update(&mut self) {
         self.maybe_enter_on_first_update();
         let state_key = self.current_state_key();
@@ -110,6 +125,9 @@ where
             }
             let new_state = self.states.get_mut(&new_state_key).unwrap();
             new_state.enter(&mut self.app_context);
+            for observer in &mut self.observers {
+                observer(&old_state_key, &new_state_key, &mut self.app_context);
+            }
         }
     }
 }
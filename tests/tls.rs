@@ -0,0 +1,196 @@
+//! Runs chayd with TLS and mutual TLS, using certificates generated with the openssl CLI, and
+//! checks which clients can connect to it with chay.
+
+use std::path::Path;
+use std::process::{Child, Command};
+
+/// Kills chayd when the test ends, even if it fails.
+struct Chayd {
+    child_proc: Child,
+    addr: String,
+}
+
+impl Drop for Chayd {
+    fn drop(&mut self) {
+        let _ = self.child_proc.kill();
+        let _ = self.child_proc.wait();
+    }
+}
+
+fn openssl(dir: &Path, args: &[&str]) {
+    let output = Command::new("openssl")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("Could not run openssl");
+    assert!(
+        output.status.success(),
+        "openssl {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Generates a key and a certificate signed by the CA in dir, e.g. server.key and server.pem.
+fn generate_signed_cert(dir: &Path, name: &str, extensions: &str) {
+    let key = format!("{name}.key");
+    let csr = format!("{name}.csr");
+    let cert = format!("{name}.pem");
+    let extensions_file = format!("{name}.ext");
+    std::fs::write(dir.join(&extensions_file), extensions).unwrap();
+    openssl(
+        dir,
+        &[
+            "req",
+            "-newkey",
+            "ec",
+            "-pkeyopt",
+            "ec_paramgen_curve:prime256v1",
+            "-nodes",
+            "-keyout",
+            &key,
+            "-out",
+            &csr,
+            "-subj",
+            &format!("/CN={name}"),
+        ],
+    );
+    openssl(
+        dir,
+        &[
+            "x509",
+            "-req",
+            "-in",
+            &csr,
+            "-CA",
+            "ca.pem",
+            "-CAkey",
+            "ca.key",
+            "-CAcreateserial",
+            "-days",
+            "1",
+            "-extfile",
+            &extensions_file,
+            "-out",
+            &cert,
+        ],
+    );
+}
+
+/// Generates ca.pem, server.pem and client.pem in dir, with their keys.
+fn generate_certs(dir: &Path) {
+    openssl(
+        dir,
+        &[
+            "req",
+            "-x509",
+            "-newkey",
+            "ec",
+            "-pkeyopt",
+            "ec_paramgen_curve:prime256v1",
+            "-nodes",
+            "-keyout",
+            "ca.key",
+            "-out",
+            "ca.pem",
+            "-days",
+            "1",
+            "-subj",
+            "/CN=chay-test-ca",
+        ],
+    );
+    generate_signed_cert(
+        dir,
+        "server",
+        "subjectAltName = DNS:localhost\nbasicConstraints = CA:FALSE\nextendedKeyUsage = serverAuth\n",
+    );
+    generate_signed_cert(
+        dir,
+        "client",
+        "basicConstraints = CA:FALSE\nextendedKeyUsage = clientAuth\n",
+    );
+}
+
+fn unused_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Starts chayd without programs, requiring client certificates signed by the CA if
+/// mutual_tls is set, and waits until it accepts connections.
+fn start_chayd(dir: &Path, mutual_tls: bool) -> Chayd {
+    let addr = unused_addr();
+    let mut config = format!(
+        "[chayd]\naddr = \"{addr}\"\ntls_cert = \"{}\"\ntls_key = \"{}\"\n",
+        dir.join("server.pem").display(),
+        dir.join("server.key").display()
+    );
+    if mutual_tls {
+        config.push_str(&format!(
+            "tls_client_ca = \"{}\"\n",
+            dir.join("ca.pem").display()
+        ));
+    }
+    config.push_str("\n[vars]\n\n[programs]\n\n[loggers]\n");
+    let config_path = dir.join("chayd.toml");
+    std::fs::write(&config_path, config).unwrap();
+    let child_proc = Command::new(env!("CARGO_BIN_EXE_chayd"))
+        .arg(&config_path)
+        .spawn()
+        .unwrap();
+    let chayd = Chayd { child_proc, addr };
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::net::TcpStream::connect(&chayd.addr).is_err() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "chayd didn't start listening"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    chayd
+}
+
+/// Runs `chay health` over TLS if tls is set, with the client certificate if client_cert is
+/// set, and returns whether it succeeded.
+fn chay_health(chayd: &Chayd, dir: &Path, tls: bool, client_cert: bool) -> bool {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_chay"));
+    command.args(["--addr", &chayd.addr]);
+    if tls {
+        command
+            .arg("--tls-ca")
+            .arg(dir.join("ca.pem"))
+            .args(["--tls-domain", "localhost"]);
+    }
+    if client_cert {
+        command
+            .arg("--tls-cert")
+            .arg(dir.join("client.pem"))
+            .arg("--tls-key")
+            .arg(dir.join("client.key"));
+    }
+    command.arg("health").output().unwrap().status.success()
+}
+
+#[test]
+fn tls() {
+    let dir = tempfile::tempdir().unwrap();
+    generate_certs(dir.path());
+    let chayd = start_chayd(dir.path(), false);
+    assert!(chay_health(&chayd, dir.path(), true, false));
+    assert!(
+        !chay_health(&chayd, dir.path(), false, false),
+        "Plaintext client connected to a TLS server"
+    );
+}
+
+#[test]
+fn mutual_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    generate_certs(dir.path());
+    let chayd = start_chayd(dir.path(), true);
+    assert!(chay_health(&chayd, dir.path(), true, true));
+    assert!(
+        !chay_health(&chayd, dir.path(), true, false),
+        "Client without a certificate connected with mutual TLS"
+    );
+}